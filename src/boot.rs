@@ -0,0 +1,83 @@
+use esp32c3_hal::macros::ram;
+use log::info;
+
+// The reset reason code after power on, which clears the boot count
+const CHIP_POWER_ON: u8 = 0x01;
+// Marks the retained boot count as valid, since RTC memory holds garbage after
+// power on
+const RETAINED_BOOT_COUNT_MAGIC: u32 = 0x4243_0001;
+
+/// The number of times the relay has booted since it was powered on.
+#[derive(Clone, Copy)]
+struct RetainedBootCount {
+    check: u32,
+    count: u32,
+}
+
+impl RetainedBootCount {
+    fn checksum(count: u32) -> u32 {
+        RETAINED_BOOT_COUNT_MAGIC.rotate_left(5) ^ count
+    }
+}
+
+// Only accessed from `record_boot`, before the executor starts
+#[ram(rtc_fast, uninitialized)]
+static mut RETAINED_BOOT_COUNT: RetainedBootCount = RetainedBootCount {
+    check: 0,
+    count: 0,
+};
+
+/// Counts this boot in RTC memory and logs it with the reason for the last
+/// reset, so a relay that keeps resetting can be spotted. Both are recorded
+/// for the status page and metrics. This must be called once at startup.
+pub fn record_boot() {
+    let reset_reason = esp32c3_hal::reset::get_reset_reason().map(|reason| reason as u8);
+
+    // Safe since this is only called once, before anything else is running
+    let retained = unsafe { RETAINED_BOOT_COUNT };
+    let previous_count = if retained.check == RetainedBootCount::checksum(retained.count)
+        && reset_reason != Some(CHIP_POWER_ON)
+    {
+        retained.count
+    } else {
+        0
+    };
+
+    let count = previous_count.wrapping_add(1);
+    unsafe {
+        RETAINED_BOOT_COUNT = RetainedBootCount {
+            check: RetainedBootCount::checksum(count),
+            count,
+        };
+    }
+
+    let reset_reason = reset_reason_name(reset_reason);
+    info!("Boot {} since power on, reset reason: {}", count, reset_reason);
+    crate::status::record_boot(count, reset_reason);
+}
+
+/// Returns a readable name for the ESP32-C3's reset reason `code`, from the
+/// RTC_CNTL reset state register.
+fn reset_reason_name(code: Option<u8>) -> &'static str {
+    match code {
+        Some(CHIP_POWER_ON) => "power on",
+        Some(0x03) => "software reset",
+        Some(0x05) => "deep sleep",
+        Some(0x07) => "TIMG0 watchdog",
+        Some(0x08) => "TIMG1 watchdog",
+        Some(0x09) => "RTC watchdog",
+        Some(0x0B) => "TIMG0 watchdog (CPU)",
+        Some(0x0C) => "software reset (CPU)",
+        Some(0x0D) => "RTC watchdog (CPU)",
+        Some(0x0F) => "brownout",
+        Some(0x10) => "RTC watchdog (system)",
+        Some(0x11) => "TIMG1 watchdog (CPU)",
+        Some(0x12) => "super watchdog",
+        Some(0x13) => "clock glitch",
+        Some(0x14) => "eFuse CRC error",
+        Some(0x15) => "USB UART",
+        Some(0x16) => "USB JTAG",
+        Some(0x17) => "power glitch",
+        _ => "unknown",
+    }
+}
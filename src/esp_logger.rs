@@ -1,28 +1,243 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{Deque, String};
+
+// Include the file and line each message was logged from, after the module.
+// This makes it easy to tell apart messages logged from several places, but
+// makes every line longer.
+const LOG_LOCATION: bool = false;
+// Print each line to serial as a JSON object, for a log aggregator, instead of
+// colored text. Each has the "level", "uptime_ms" (null until the time driver
+// is running), "target" and "msg", and the "file" and "line" if LOG_LOCATION is
+// set. The log buffer and syslog are not affected.
+const JSON_LOGS: bool = false;
+// The log level of particular modules, overriding the level passed to
+// `init_logger`. Each is matched against the start of a message's target, which
+// is its module path, e.g. "tilt_relay::tilt_scanner". The first match is used,
+// so put more specific modules first. For example, to debug Bluetooth while
+// keeping the rest of the log quiet:
+//
+//     &[
+//         ("tilt_relay::tilt_scanner", log::LevelFilter::Debug),
+//         ("esp_wifi", log::LevelFilter::Warn),
+//     ]
+const MODULE_LOG_LEVELS: &[(&str, log::LevelFilter)] = &[];
+// Keep this many of the latest lines of the log in memory, so they can be
+// viewed at /logs on the status server without a serial cable
+const LOG_BUFFER_LINES: usize = 32;
+// Longer lines are truncated in the buffer
+const LOG_BUFFER_LINE_LENGTH: usize = 120;
+/// The most that `write_log_buffer` writes, with a newline after each line
+pub const LOG_BUFFER_SIZE: usize = LOG_BUFFER_LINES * (LOG_BUFFER_LINE_LENGTH + 1);
+
+/// The level for modules that aren't in MODULE_LOG_LEVELS, as a LevelFilter
+/// cast to usize
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(log::LevelFilter::Trace as usize);
+
+/// Set once the embassy time driver is running, so log lines can be timestamped
+static TIME_READY: AtomicBool = AtomicBool::new(false);
+
+/// The latest lines of the log, oldest first. The lock is only held while
+/// copying lines in or out, and nothing is logged while it is held.
+static LOG_BUFFER: Mutex<CriticalSectionRawMutex, RefCell<Deque<String<LOG_BUFFER_LINE_LENGTH>, LOG_BUFFER_LINES>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Starts logging messages at `level` and above, or at the level in
+/// MODULE_LOG_LEVELS for the modules there.
 pub fn init_logger(level: log::LevelFilter) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+
+    // The log macros skip anything above the max level without asking the
+    // logger, so it has to allow the most verbose module
+    let max_level = MODULE_LOG_LEVELS.iter().map(|(_, level)| *level).fold(level, core::cmp::max);
+
     unsafe {
         log::set_logger_racy(&EspLogger).unwrap();
-        log::set_max_level(level);
+        log::set_max_level(max_level);
     }
 }
 
+/// Starts timestamping log lines with the time since boot. This must only be
+/// called after `embassy::init`, since Instant can't be used before then.
+pub fn enable_timestamps() {
+    TIME_READY.store(true, Ordering::Relaxed);
+}
+
+/// Writes the lines in the log buffer to `w`, oldest first.
+pub fn write_log_buffer(w: &mut impl core::fmt::Write) -> core::fmt::Result {
+    LOG_BUFFER.lock(|buffer| {
+        for line in buffer.borrow().iter() {
+            writeln!(w, "{}", line)?;
+        }
+
+        Ok(())
+    })
+}
+
 struct EspLogger;
 
 impl log::Log for EspLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let target = metadata.target();
+        let module_level = MODULE_LOG_LEVELS.iter().find(|(module, _)| {
+            target.strip_prefix(module).map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+        });
+
+        match module_level {
+            Some((_, level)) => metadata.level() <= *level,
+            None => metadata.level() as usize <= DEFAULT_LEVEL.load(Ordering::Relaxed),
+        }
     }
 
     fn log(&self, record: &log::Record) {
-        let level = match record.level() {
-            log::Level::Error => "\x1b[31mE",
-            log::Level::Warn => "\x1b[33mW",
-            log::Level::Info => "\x1b[36mI",
-            log::Level::Debug => "\x1b[35mD",
-            log::Level::Trace => "T",
-        };
+        if !log::Log::enabled(self, record.metadata()) {
+            return;
+        }
 
-        esp_println::println!("{} {}\x1b[0m", level, record.args());
+        let ms = TIME_READY.load(Ordering::Relaxed).then(|| Instant::now().as_millis());
+
+        if JSON_LOGS {
+            print_json(ms, record);
+        } else {
+            print_text(ms, record);
+        }
+
+        capture(ms, record);
+
+        if crate::syslog::SYSLOG_ENABLED {
+            crate::syslog::queue(record);
+        }
     }
 
     fn flush(&self) {}
-}
\ No newline at end of file
+}
+
+/// Prints the `record` to serial as a line of colored text.
+fn print_text(ms: Option<u64>, record: &log::Record) {
+    let color = match record.level() {
+        log::Level::Error => "\x1b[31m",
+        log::Level::Warn => "\x1b[33m",
+        log::Level::Info => "\x1b[36m",
+        log::Level::Debug => "\x1b[35m",
+        log::Level::Trace => "",
+    };
+
+    esp_println::print!("{}{} ", color, level_letter(record.level()));
+
+    if let Some(ms) = ms {
+        esp_println::print!("[{:>6}.{:03}] ", ms / 1000, ms % 1000);
+    }
+
+    esp_println::print!("{}", record.target());
+
+    if LOG_LOCATION {
+        if let (Some(file), Some(line)) = (record.file(), record.line()) {
+            esp_println::print!(" {}:{}", file, line);
+        }
+    }
+
+    esp_println::println!(": {}\x1b[0m", record.args());
+}
+
+/// Prints the `record` to serial as a JSON object on one line.
+fn print_json(ms: Option<u64>, record: &log::Record) {
+    use core::fmt::Write;
+
+    esp_println::print!("{{\"level\":\"{}\",\"uptime_ms\":", record.level());
+
+    match ms {
+        Some(ms) => esp_println::print!("{}", ms),
+        None => esp_println::print!("null"),
+    }
+
+    esp_println::print!(",\"target\":\"");
+    write!(JsonEscaper, "{}", record.target()).ok();
+
+    if LOG_LOCATION {
+        if let (Some(file), Some(line)) = (record.file(), record.line()) {
+            esp_println::print!("\",\"file\":\"");
+            write!(JsonEscaper, "{}", file).ok();
+            esp_println::print!("\",\"line\":{},\"msg\":\"", line);
+        } else {
+            esp_println::print!("\",\"msg\":\"");
+        }
+    } else {
+        esp_println::print!("\",\"msg\":\"");
+    }
+
+    write!(JsonEscaper, "{}", record.args()).ok();
+    esp_println::println!("\"}}");
+}
+
+/// Prints what is written to it to serial, escaped for a JSON string, so a
+/// message with quotes or raw bytes in it can't break the JSON.
+struct JsonEscaper;
+
+impl core::fmt::Write for JsonEscaper {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Print the runs of characters that don't need escaping as they are
+        let mut start = 0;
+
+        for (i, c) in s.char_indices() {
+            if c != '"' && c != '\\' && !c.is_control() {
+                continue;
+            }
+
+            esp_println::print!("{}", &s[start..i]);
+            start = i + c.len_utf8();
+
+            match c {
+                '"' => esp_println::print!("\\\""),
+                '\\' => esp_println::print!("\\\\"),
+                '\n' => esp_println::print!("\\n"),
+                '\r' => esp_println::print!("\\r"),
+                '\t' => esp_println::print!("\\t"),
+                c => esp_println::print!("\\u{:04x}", c as u32),
+            }
+        }
+
+        esp_println::print!("{}", &s[start..]);
+        Ok(())
+    }
+}
+
+/// Returns the letter that stands for `level` in the text log.
+fn level_letter(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "E",
+        log::Level::Warn => "W",
+        log::Level::Info => "I",
+        log::Level::Debug => "D",
+        log::Level::Trace => "T",
+    }
+}
+
+/// Adds the `record` to the log buffer, without colors, dropping the oldest
+/// line if it is full.
+fn capture(ms: Option<u64>, record: &log::Record) {
+    use core::fmt::Write;
+
+    // A line that is too long keeps whatever fit
+    let mut line = String::new();
+    write!(line, "{} ", level_letter(record.level())).ok();
+
+    if let Some(ms) = ms {
+        write!(line, "[{:>6}.{:03}] ", ms / 1000, ms % 1000).ok();
+    }
+
+    write!(line, "{}: {}", record.target(), record.args()).ok();
+
+    LOG_BUFFER.lock(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+
+        if buffer.is_full() {
+            buffer.pop_front();
+        }
+
+        buffer.push_back(line).ok();
+    });
+}
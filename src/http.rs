@@ -0,0 +1,364 @@
+use embedded_io::asynch::Read;
+
+/// The blank line that separates the headers from the body
+const HEADERS_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+/// Reads an HTTP response from `reader` into `response` and returns its length.
+/// This keeps reading until the whole body has arrived according to the
+/// Content-Length header or the chunked transfer encoding, the connection is
+/// closed, or `response` is full, since a response can arrive split across
+/// several reads.
+pub async fn read_response<R: Read>(reader: &mut R, response: &mut [u8]) -> Result<usize, R::Error> {
+    let mut len = 0;
+
+    while len < response.len() {
+        let n = reader.read(&mut response[len..]).await?;
+
+        // The server closed the connection
+        if n == 0 {
+            break;
+        }
+
+        len += n;
+
+        if response_length(&response[..len]).is_some_and(|total| len >= total) {
+            break;
+        }
+    }
+
+    Ok(len)
+}
+
+/// Returns the status code of the HTTP `response`, e.g. 200. This accepts
+/// HTTP/1.0 and HTTP/1.1, and any amount of whitespace around the code.
+/// Returns None if the status line is incomplete or malformed.
+pub fn status_code(response: &[u8]) -> Option<u16> {
+    let line_end = response.windows(2).position(|w| w == b"\r\n")?;
+    let line = core::str::from_utf8(&response[..line_end]).ok()?;
+    let mut parts = line.split_ascii_whitespace();
+
+    let version = parts.next()?;
+    if version != "HTTP/1.0" && version != "HTTP/1.1" {
+        return None;
+    }
+
+    let code = parts.next()?;
+    if code.len() != 3 {
+        return None;
+    }
+
+    code.parse().ok()
+}
+
+/// Returns the length of the headers of the HTTP `response`, including the
+/// blank line after them. Returns None if the headers are incomplete.
+pub fn headers_length(response: &[u8]) -> Option<usize> {
+    response
+        .windows(HEADERS_TERMINATOR.len())
+        .position(|w| w == HEADERS_TERMINATOR)
+        .map(|i| i + HEADERS_TERMINATOR.len())
+}
+
+/// Returns the value of the header called `name` in the HTTP response or
+/// request `headers`, if it exists. Header names are case insensitive.
+pub fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Returns the total length of the HTTP `response`, headers and body, if it can
+/// be known from what has been received so far. That is when the headers are
+/// complete and either have a Content-Length, the status has no body, or the
+/// body is chunked and the last chunk has been received.
+fn response_length(response: &[u8]) -> Option<usize> {
+    let headers_length = headers_length(response)?;
+    let headers = core::str::from_utf8(&response[..headers_length]).ok()?;
+
+    // These responses never have a body
+    let status = status_code(response)?;
+    if (100..200).contains(&status) || status == 204 || status == 304 {
+        return Some(headers_length);
+    }
+
+    let chunked = header(headers, "Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        return chunked_body_length(&response[headers_length..]).map(|length| headers_length + length);
+    }
+
+    let content_length: usize = header(headers, "Content-Length")?.parse().ok()?;
+
+    Some(headers_length + content_length)
+}
+
+/// Returns the length of a chunked `body`, up to the end of the last chunk and
+/// any trailers after it. Returns None if the last chunk hasn't been received
+/// yet, or if the chunks are malformed. The chunks aren't decoded, this only
+/// finds where the body ends.
+fn chunked_body_length(body: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+
+    loop {
+        // Each chunk starts with its size in hex, then maybe some extensions
+        let line_length = body[offset..].windows(2).position(|w| w == b"\r\n")?;
+        let line = core::str::from_utf8(&body[offset..offset + line_length]).ok()?;
+        let size_hex = line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+        offset += line_length + 2;
+
+        if size == 0 {
+            break;
+        }
+
+        // The chunk's data is followed by a line break
+        offset = offset.checked_add(size)?.checked_add(2)?;
+        if offset > body.len() {
+            return None;
+        }
+    }
+
+    // The last chunk is followed by any trailers, then a blank line
+    loop {
+        let line_length = body[offset..].windows(2).position(|w| w == b"\r\n")?;
+        offset += line_length + 2;
+
+        if line_length == 0 {
+            return Some(offset);
+        }
+    }
+}
+
+/// How to handle the response to a request, based on its status
+#[derive(Debug, PartialEq)]
+pub enum ResponseClass {
+    /// The request succeeded (2xx)
+    Success,
+    /// The server is rate limiting or overloaded (429 or 503). The request
+    /// should be retried after waiting longer than usual, or for the number of
+    /// seconds in the Retry-After header if it was sent.
+    RetryLater(Option<u32>),
+    /// The request was invalid (4xx other than 429), so retrying won't help
+    Rejected,
+    /// The response is elsewhere (301, 302, 303, 307, or 308), at the URL in
+    /// the Location header
+    Redirect,
+    /// Any other failure, which may succeed if retried
+    Retry,
+}
+
+/// Classifies the HTTP `response` by its status code, to decide whether the
+/// request succeeded and whether it is worth retrying.
+pub fn classify(response: &[u8]) -> ResponseClass {
+    match status_code(response) {
+        Some(200..=299) => ResponseClass::Success,
+        Some(429) | Some(503) => ResponseClass::RetryLater(retry_after(response)),
+        Some(301 | 302 | 303 | 307 | 308) if location(response).is_some() => ResponseClass::Redirect,
+        Some(400..=499) => ResponseClass::Rejected,
+        _ => ResponseClass::Retry,
+    }
+}
+
+/// Returns whether a post can be sent `since_last_ms` after the last one, or
+/// None if there hasn't been one, when posts must be at least `min_interval_ms`
+/// apart. Posts up to `tolerance_ms` early are allowed, so ones scheduled
+/// exactly `min_interval_ms` apart aren't put off by how long each takes to
+/// send.
+pub const fn post_allowed(since_last_ms: Option<u64>, min_interval_ms: u64, tolerance_ms: u64) -> bool {
+    match since_last_ms {
+        Some(since_last_ms) => since_last_ms.saturating_add(tolerance_ms) >= min_interval_ms,
+        None => true,
+    }
+}
+
+/// Returns how long to wait before the `retry`th retry of a request, counting
+/// from 1: `base_ms` multiplied by `multiplier` for each retry before it, up to
+/// `max_ms`.
+pub const fn backoff_ms(retry: usize, base_ms: u64, multiplier: u64, max_ms: u64) -> u64 {
+    let mut delay = base_ms;
+    let mut i = 1;
+
+    while i < retry && delay < max_ms {
+        delay = delay.saturating_mul(multiplier);
+        i += 1;
+    }
+
+    if delay < max_ms { delay } else { max_ms }
+}
+
+/// Returns the Location header of the `response`, which is where a redirect
+/// points.
+pub fn location(response: &[u8]) -> Option<&str> {
+    let headers_length = headers_length(response)?;
+    let headers = core::str::from_utf8(&response[..headers_length]).ok()?;
+
+    header(headers, "Location")
+}
+
+/// The parts of an absolute http or https URL
+#[derive(Debug, PartialEq)]
+pub struct Url<'a> {
+    pub tls: bool,
+    pub host: &'a str,
+    pub port: u16,
+    /// Includes any query string, and is "/" if the URL has no path
+    pub path: &'a str,
+}
+
+/// Parses an absolute http or https `url`, e.g. from a Location header. The
+/// port is the default for the scheme unless the URL has one. Returns None for
+/// other schemes, relative URLs, and URLs with user info.
+pub fn parse_url(url: &str) -> Option<Url<'_>> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return None;
+    };
+
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let path = if path.is_empty() { "/" } else { path };
+
+    if authority.contains(['@', '?', '#']) {
+        return None;
+    }
+
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some(Url { tls, host, port, path })
+}
+
+/// Returns the number of seconds in the Retry-After header of the `response`.
+/// Returns None if there is no such header, or if it is an HTTP date instead.
+fn retry_after(response: &[u8]) -> Option<u32> {
+    let headers_length = headers_length(response)?;
+    let headers = core::str::from_utf8(&response[..headers_length]).ok()?;
+
+    header(headers, "Retry-After")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_success() {
+        assert_eq!(classify(b"HTTP/1.1 200 OK\r\n\r\n"), ResponseClass::Success);
+        assert_eq!(classify(b"HTTP/1.0 204 No Content\r\n\r\n"), ResponseClass::Success);
+    }
+
+    #[test]
+    fn classify_rate_limited() {
+        assert_eq!(
+            classify(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\n\r\n"),
+            ResponseClass::RetryLater(Some(120)),
+        );
+        assert_eq!(classify(b"HTTP/1.1 503 Service Unavailable\r\n\r\n"), ResponseClass::RetryLater(None));
+        // A date can't be used, so the usual longer backoff applies
+        assert_eq!(
+            classify(b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n"),
+            ResponseClass::RetryLater(None),
+        );
+    }
+
+    #[test]
+    fn classify_rejected() {
+        assert_eq!(classify(b"HTTP/1.1 400 Bad Request\r\n\r\n"), ResponseClass::Rejected);
+        assert_eq!(classify(b"HTTP/1.1 404 Not Found\r\n\r\n"), ResponseClass::Rejected);
+    }
+
+    #[test]
+    fn classify_redirect() {
+        assert_eq!(
+            classify(b"HTTP/1.1 302 Found\r\nLocation: https://example.com/\r\n\r\n"),
+            ResponseClass::Redirect,
+        );
+        // Nowhere to go without a Location
+        assert_eq!(classify(b"HTTP/1.1 302 Found\r\n\r\n"), ResponseClass::Retry);
+    }
+
+    #[test]
+    fn classify_retry() {
+        assert_eq!(classify(b"HTTP/1.1 500 Internal Server Error\r\n\r\n"), ResponseClass::Retry);
+        assert_eq!(classify(b"HTTP/1.1 2"), ResponseClass::Retry);
+        assert_eq!(classify(b"garbage\r\n"), ResponseClass::Retry);
+    }
+
+    #[test]
+    fn post_interval() {
+        let minutes = |m: u64| m * 60 * 1000;
+        let allowed = |since_last| post_allowed(since_last, minutes(15), 30 * 1000);
+
+        assert!(allowed(None));
+        assert!(allowed(Some(minutes(15))));
+        assert!(allowed(Some(minutes(30))));
+        // The previous post took a few seconds longer to send than this one
+        assert!(allowed(Some(minutes(15) - 5 * 1000)));
+        assert!(allowed(Some(minutes(15) - 30 * 1000)));
+        assert!(!allowed(Some(minutes(15) - 31 * 1000)));
+        // e.g. a post delayed by retries, right before the next scan's
+        assert!(!allowed(Some(minutes(1))));
+        assert!(!allowed(Some(0)));
+    }
+
+    #[test]
+    fn backoff_schedule() {
+        let schedule = |n_retries| (1..=n_retries).map(|retry| backoff_ms(retry, 100, 5, 1000)).collect::<Vec<_>>();
+
+        assert_eq!(schedule(1), [100]);
+        assert_eq!(schedule(4), [100, 500, 1000, 1000]);
+        assert_eq!(schedule(6), [100, 500, 1000, 1000, 1000, 1000]);
+        // A multiplier of 1 waits the same every time
+        assert_eq!(backoff_ms(10, 250, 1, 1000), 250);
+    }
+
+    #[test]
+    fn chunked_body() {
+        let body = b"4\r\nWiki\r\n5;name=value\r\npedia\r\n0\r\n\r\n";
+        assert_eq!(chunked_body_length(body), Some(body.len()));
+        // Anything after the end isn't part of the body
+        assert_eq!(chunked_body_length(b"0\r\n\r\nHTTP/1.1"), Some(5));
+
+        let with_trailer = b"4\r\nWiki\r\n0\r\nExpires: never\r\n\r\n";
+        assert_eq!(chunked_body_length(with_trailer), Some(with_trailer.len()));
+    }
+
+    #[test]
+    fn chunked_body_incomplete() {
+        assert_eq!(chunked_body_length(b""), None);
+        assert_eq!(chunked_body_length(b"4\r\nWi"), None);
+        assert_eq!(chunked_body_length(b"4\r\nWiki\r\n0\r\n"), None);
+        assert_eq!(chunked_body_length(b"zz\r\n"), None);
+    }
+
+    #[test]
+    fn chunked_response_in_two_segments() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            6\r\n{\"a\":1\r\n1\r\n}\r\n0\r\n\r\n";
+        let split = response.len() - 12;
+
+        // The end of the body hasn't arrived after the first segment
+        assert_eq!(response_length(&response[..split]), None);
+        assert_eq!(response_length(response), Some(response.len()));
+        assert_eq!(status_code(&response[..split]), Some(200));
+    }
+
+    #[test]
+    fn content_length_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(response_length(&response[..20]), None);
+        assert_eq!(response_length(response), Some(response.len()));
+        assert_eq!(response_length(b"HTTP/1.1 204 No Content\r\n\r\n"), Some(27));
+    }
+}
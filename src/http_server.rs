@@ -0,0 +1,302 @@
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::Instant;
+use embedded_io::asynch::Write as _;
+use esp_wifi::wifi::WifiDevice;
+use log::{info, warn};
+use smoltcp::socket;
+
+use crate::http;
+use crate::payload::{Wrapper, GRAVITY_UNIT, TEMP_UNIT};
+use crate::status::Status;
+use crate::wifi::{sleep_ms, wait_until};
+
+// Serve a status page with the latest readings that can be viewed in a browser
+// at the relay's IP address, Prometheus metrics at /metrics, and the latest
+// lines of the log at /logs
+pub const HTTP_SERVER_ENABLED: bool = true;
+const HTTP_SERVER_PORT: u16 = 80;
+// The largest request that is read. Only the request line is used.
+const MAX_REQUEST_LENGTH: usize = 512;
+const MAX_BODY_LENGTH: usize = 4096;
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+/// The Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+const _: () = assert!(crate::esp_logger::LOG_BUFFER_SIZE <= MAX_BODY_LENGTH);
+
+#[embassy_executor::task]
+pub async fn http_server_task(stack: &'static Stack<WifiDevice<'static>>) {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+    info!("Serving status on port {}", HTTP_SERVER_PORT);
+
+    // Handle one connection at a time
+    loop {
+        if let Err(e) = socket.accept(HTTP_SERVER_PORT).await {
+            warn!("accept error: {:?}", e);
+            sleep_ms(1000).await;
+            continue;
+        }
+
+        socket.set_timeout(Some(embassy_net::SmolDuration::from_secs(10)));
+
+        if let Err(e) = handle_request(&mut socket).await {
+            warn!("Failed to serve request: {:?}", e);
+            socket.abort();
+        }
+
+        socket.close();
+
+        // The socket has to be closed before it can accept again
+        if wait_until(|| socket.state() == socket::tcp::State::Closed).await.is_err() {
+            warn!("Stalled while waiting for socket to close");
+            socket.abort();
+        }
+    }
+}
+
+/// Reads a request from the connected `socket` and writes the response.
+async fn handle_request(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    use core::fmt::Write;
+
+    let mut request = [0u8; MAX_REQUEST_LENGTH];
+    let mut len = 0;
+
+    // Read until the end of the headers, or as much as fits
+    while len < request.len() && http::headers_length(&request[..len]).is_none() {
+        match socket.read(&mut request[len..]).await? {
+            0 => return Ok(()),
+            n => len += n,
+        }
+    }
+
+    let mut body = [0u8; MAX_BODY_LENGTH];
+    let (status, content_type, body) = match request_path(&request[..len]) {
+        Some("/") => ("200 OK", HTML_CONTENT_TYPE, format_status_page(&crate::status::get(), &mut body)),
+        Some("/metrics") => ("200 OK", METRICS_CONTENT_TYPE, format_metrics(&crate::status::get(), &mut body)),
+        Some("/logs") => ("200 OK", TEXT_CONTENT_TYPE, format_logs(&mut body)),
+        _ => ("404 Not Found", HTML_CONTENT_TYPE, "Not found"),
+    };
+
+    let mut header_buffer = [0u8; 256];
+    let mut wrapper = Wrapper::new(&mut header_buffer);
+    write!(wrapper,
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n",
+        status,
+        content_type,
+        body.len(),
+    ).unwrap();
+    let header_length = wrapper.written();
+
+    socket.write_all(&header_buffer[..header_length]).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.flush().await
+}
+
+/// Returns the path of a GET `request`, without any query string.
+fn request_path(request: &[u8]) -> Option<&str> {
+    let line_end = request.windows(2).position(|w| w == b"\r\n")?;
+    let line = core::str::from_utf8(&request[..line_end]).ok()?;
+    let mut parts = line.split_ascii_whitespace();
+
+    if parts.next()? != "GET" {
+        return None;
+    }
+
+    parts.next()?.split('?').next()
+}
+
+/// Formats the `status` as an HTML page in `buffer` and returns it.
+fn format_status_page<'a>(status: &Status, buffer: &'a mut [u8]) -> &'a str {
+    use core::fmt::Write;
+
+    let now = Instant::now();
+    let uptime = now.as_secs();
+
+    let mut wrapper = Wrapper::new(buffer);
+    write!(wrapper,
+        "<!DOCTYPE html>\
+        <html><head><meta name=\"viewport\" content=\"width=device-width\">\
+        <title>Tilt Relay</title></head><body><h1>Tilt Relay</h1>"
+    ).unwrap();
+
+    for (tilt_data, stale) in status.readings.iter().zip(status.stale.iter()) {
+        let tilt_data = match tilt_data {
+            Some(tilt_data) => tilt_data,
+            None => continue,
+        };
+
+        if *stale {
+            write!(wrapper, "<h2>{} (stale, from before reset)</h2><p>", tilt_data.color().name()).unwrap();
+        } else {
+            write!(wrapper, "<h2>{}</h2><p>", tilt_data.color().name()).unwrap();
+        }
+
+        write!(wrapper,
+            "Temperature: {} &deg;{}<br>Gravity: {} {}<br>",
+            tilt_data.temperature_str_in(TEMP_UNIT, &mut [0u8; 6]),
+            TEMP_UNIT.symbol(),
+            tilt_data.gravity_str_in(GRAVITY_UNIT, &mut [0u8; 6]),
+            GRAVITY_UNIT.symbol(),
+        ).unwrap();
+
+        if let Some(battery) = tilt_data.battery() {
+            write!(wrapper, "Battery: {} weeks old<br>", battery).unwrap();
+        }
+
+        write!(wrapper, "Signal: {} dBm</p>", tilt_data.rssi()).unwrap();
+    }
+
+    if status.readings.iter().all(Option::is_none) {
+        write!(wrapper, "<p>No readings yet</p>").unwrap();
+    }
+
+    write!(wrapper, "<h2>Relay</h2><p>").unwrap();
+
+    if let Some(last_scan) = status.last_scan {
+        write!(wrapper, "Last reading: {} seconds ago<br>", (now - last_scan).as_secs()).unwrap();
+    }
+
+    match status.last_post {
+        Some((time, success)) => write!(wrapper,
+            "Last post: {} seconds ago, {}<br>",
+            (now - time).as_secs(),
+            if success { "succeeded" } else { "failed" },
+        ).unwrap(),
+        None => write!(wrapper, "Last post: never<br>").unwrap(),
+    }
+
+    if let Some(wifi) = status.wifi {
+        write!(wrapper,
+            "WiFi: {} dBm on channel {}, access point {:02X?}<br>",
+            wifi.rssi,
+            wifi.channel,
+            wifi.bssid,
+        ).unwrap();
+    }
+
+    write!(wrapper,
+        "Posts: {} succeeded, {} failed<br>\
+        Uptime: {}d {}h {}m<br>\
+        Boots: {}, last reset: {}</p></body></html>",
+        status.n_posts,
+        status.n_failed_posts,
+        uptime / (24 * 60 * 60),
+        uptime / (60 * 60) % 24,
+        uptime / 60 % 60,
+        status.boot_count,
+        status.reset_reason,
+    ).unwrap();
+
+    let len = wrapper.written();
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
+
+/// Formats the lines in the log buffer in `buffer` and returns them, oldest
+/// first.
+fn format_logs(buffer: &mut [u8]) -> &str {
+    let mut wrapper = Wrapper::new(buffer);
+    crate::esp_logger::write_log_buffer(&mut wrapper).unwrap();
+
+    let len = wrapper.written();
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
+
+/// Formats the `status` as Prometheus metrics in `buffer` and returns it. Each
+/// Tilt's readings are labeled with its color.
+fn format_metrics<'a>(status: &Status, buffer: &'a mut [u8]) -> &'a str {
+    use core::fmt::Write;
+
+    let mut wrapper = Wrapper::new(buffer);
+
+    writeln!(wrapper,
+        "# HELP tilt_temperature_f The temperature in Fahrenheit.\n\
+        # TYPE tilt_temperature_f gauge"
+    ).unwrap();
+    for tilt_data in status.readings.iter().flatten() {
+        writeln!(wrapper,
+            "tilt_temperature_f{{color=\"{}\"}} {}",
+            tilt_data.color().name(),
+            tilt_data.temperature_str(&mut [0u8; 6]),
+        ).unwrap();
+    }
+
+    writeln!(wrapper,
+        "# HELP tilt_gravity The specific gravity.\n\
+        # TYPE tilt_gravity gauge"
+    ).unwrap();
+    for tilt_data in status.readings.iter().flatten() {
+        writeln!(wrapper,
+            "tilt_gravity{{color=\"{}\"}} {}",
+            tilt_data.color().name(),
+            tilt_data.gravity_str(&mut [0u8; 6]),
+        ).unwrap();
+    }
+
+    writeln!(wrapper,
+        "# HELP tilt_battery_weeks The number of weeks since the battery was replaced.\n\
+        # TYPE tilt_battery_weeks gauge"
+    ).unwrap();
+    for tilt_data in status.readings.iter().flatten() {
+        if let Some(battery) = tilt_data.battery() {
+            writeln!(wrapper, "tilt_battery_weeks{{color=\"{}\"}} {}", tilt_data.color().name(), battery).unwrap();
+        }
+    }
+
+    writeln!(wrapper,
+        "# HELP tilt_rssi_dbm The signal strength of the Tilt's broadcast.\n\
+        # TYPE tilt_rssi_dbm gauge"
+    ).unwrap();
+    for tilt_data in status.readings.iter().flatten() {
+        writeln!(wrapper, "tilt_rssi_dbm{{color=\"{}\"}} {}", tilt_data.color().name(), tilt_data.rssi()).unwrap();
+    }
+
+    if let Some(wifi) = status.wifi {
+        writeln!(wrapper,
+            "# HELP tilt_relay_wifi_rssi_dbm The signal strength of the WiFi access point.\n\
+            # TYPE tilt_relay_wifi_rssi_dbm gauge\n\
+            tilt_relay_wifi_rssi_dbm {}\n\
+            # HELP tilt_relay_wifi_channel The WiFi channel of the access point.\n\
+            # TYPE tilt_relay_wifi_channel gauge\n\
+            tilt_relay_wifi_channel {}",
+            wifi.rssi,
+            wifi.channel,
+        ).unwrap();
+    }
+
+    writeln!(wrapper,
+        "# HELP tilt_relay_posts_total The number of datapoints posted.\n\
+        # TYPE tilt_relay_posts_total counter\n\
+        tilt_relay_posts_total {}\n\
+        # HELP tilt_relay_failed_posts_total The number of datapoints that failed to post after all retries.\n\
+        # TYPE tilt_relay_failed_posts_total counter\n\
+        tilt_relay_failed_posts_total {}\n\
+        # HELP tilt_relay_scans_total The number of scans for Tilts.\n\
+        # TYPE tilt_relay_scans_total counter\n\
+        tilt_relay_scans_total {}\n\
+        # HELP tilt_relay_uptime_seconds The time since the relay booted.\n\
+        # TYPE tilt_relay_uptime_seconds gauge\n\
+        tilt_relay_uptime_seconds {}\n\
+        # HELP tilt_relay_boots The number of boots since power on, labeled with the reason for the last reset.\n\
+        # TYPE tilt_relay_boots gauge\n\
+        tilt_relay_boots{{reset_reason=\"{}\"}} {}",
+        status.n_posts,
+        status.n_failed_posts,
+        status.n_scans,
+        Instant::now().as_secs(),
+        status.reset_reason,
+        status.boot_count,
+    ).unwrap();
+
+    let len = wrapper.written();
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
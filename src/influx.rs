@@ -0,0 +1,65 @@
+use embassy_net::IpAddress;
+
+use crate::payload::{Wrapper, GRAVITY_UNIT, TEMP_UNIT};
+use crate::tilt::TiltData;
+
+// The InfluxDB server and database to write the Tilt data to
+pub const INFLUXDB_ENDPOINT: (IpAddress, u16) = (IpAddress::v4(192, 168, 0, 100), 8086);
+const INFLUXDB_HOSTNAME: &str = "192.168.0.100";
+const INFLUXDB_DATABASE: &str = "fermentation";
+const INFLUXDB_MEASUREMENT: &str = "tilt";
+
+/// Formats the request that writes the `tilt_data` to InfluxDB using the line
+/// protocol in `buffer` and returns it. The `timestamp` is in seconds since the
+/// Unix epoch. If it is None, InfluxDB uses the time the data was received
+/// instead. Returns an error if the request doesn't fit.
+pub fn format_write(tilt_data: TiltData, timestamp: Option<u64>, buffer: &mut [u8]) -> Result<&str, core::fmt::Error> {
+    use core::fmt::Write;
+
+    let mut line_buffer = [0u8; 256];
+    let line = format_line(tilt_data, timestamp, &mut line_buffer)?;
+
+    let mut wrapper = Wrapper::new(buffer);
+    write!(wrapper,
+        "POST /write?db={}&precision=s HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: text/plain\r\n\
+         Connection: {}\r\n\
+         Content-Length: {}\r\n\r\n{}",
+         INFLUXDB_DATABASE, INFLUXDB_HOSTNAME, crate::wifi::connection_header(), line.len(), line
+    )?;
+
+    let len = wrapper.written();
+    Ok(core::str::from_utf8(&buffer[..len]).unwrap())
+}
+
+/// Formats the `tilt_data` as a line of the InfluxDB line protocol in `buffer`
+/// and returns it, e.g.
+/// `tilt,color=Red temperature=68.5,gravity=1.0500,battery=5i,rssi=-70i`
+/// The battery field is left out if the Tilt didn't transmit it. Returns an
+/// error if the line doesn't fit.
+fn format_line(tilt_data: TiltData, timestamp: Option<u64>, buffer: &mut [u8]) -> Result<&str, core::fmt::Error> {
+    use core::fmt::Write;
+
+    let mut wrapper = Wrapper::new(buffer);
+    write!(wrapper,
+        "{},color={} temperature={},gravity={}",
+        INFLUXDB_MEASUREMENT,
+        tilt_data.color().name(),
+        tilt_data.temperature_str_in(TEMP_UNIT, &mut [0u8; 6]),
+        tilt_data.gravity_str_in(GRAVITY_UNIT, &mut [0u8; 6]),
+    )?;
+
+    if let Some(battery) = tilt_data.battery() {
+        write!(wrapper, ",battery={}i", battery)?;
+    }
+
+    write!(wrapper, ",rssi={}i", tilt_data.rssi())?;
+
+    if let Some(timestamp) = timestamp {
+        write!(wrapper, " {}", timestamp)?;
+    }
+
+    let len = wrapper.written();
+    Ok(core::str::from_utf8(&buffer[..len]).unwrap())
+}
@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use esp32c3_hal::gpio::{GpioPin, Unknown};
+use esp32c3_hal::pulse_control::ConfiguredChannel0;
+use esp_hal_smartled::SmartLedsAdapter;
+use log::warn;
+use smart_leds::{brightness, SmartLedsWrite, RGB8};
+
+// Drive the NeoPixel on the QT Py as a status indicator: blue while scanning,
+// green after a successful post, and off between cycles. It is red between
+// cycles instead while posting is failing or a Tilt has gone offline.
+pub const STATUS_LED_ENABLED: bool = true;
+// The NeoPixel is very bright at full power, so scale it down. 255 is full.
+const LED_BRIGHTNESS: u8 = 10;
+// How long to show a successful post before turning off
+const POSTED_DISPLAY_TIME: Duration = Duration::from_secs(5);
+
+const OFF: RGB8 = RGB8::new(0, 0, 0);
+const BLUE: RGB8 = RGB8::new(0, 0, 255);
+const GREEN: RGB8 = RGB8::new(0, 255, 0);
+const RED: RGB8 = RGB8::new(255, 0, 0);
+
+/// The RMT buffer needs 24 bits for each LED, plus an end marker
+const LED_BUFFER_SIZE: usize = 24 + 1;
+
+/// The NeoPixel on the QT Py, which is on GPIO2 and driven by the RMT.
+pub type StatusLed = SmartLedsAdapter<ConfiguredChannel0<'static, GpioPin<Unknown, 2>>, LED_BUFFER_SIZE>;
+
+#[derive(Clone, Copy)]
+pub enum LedState {
+    Idle,
+    Scanning,
+    Posted,
+    Failing,
+}
+
+static LED_SIGNAL: Signal<CriticalSectionRawMutex, LedState> = Signal::new();
+static TILT_OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Shows the `state` on the status LED. This never waits for the LED to be
+/// updated, so it can't hold up the caller.
+pub fn set_state(state: LedState) {
+    LED_SIGNAL.signal(state);
+}
+
+/// Sets whether any Tilt has gone offline. This is shown the next time the LED
+/// is idle.
+pub fn set_tilt_offline(offline: bool) {
+    TILT_OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+#[embassy_executor::task]
+pub async fn led_task(mut led: StatusLed) {
+    let mut state = LedState::Idle;
+    // Stays set until the next successful post
+    let mut failing = false;
+
+    loop {
+        let color = match state {
+            LedState::Idle if failing || TILT_OFFLINE.load(Ordering::Relaxed) => RED,
+            LedState::Idle => OFF,
+            LedState::Scanning => BLUE,
+            LedState::Posted => {
+                failing = false;
+                GREEN
+            }
+            LedState::Failing => {
+                failing = true;
+                RED
+            }
+        };
+
+        if let Err(e) = led.write(brightness([color].into_iter(), LED_BRIGHTNESS)) {
+            warn!("Failed to set the status LED: {:?}", e);
+        }
+
+        // A successful post is only shown briefly, the other states stay until
+        // they change
+        state = match state {
+            LedState::Posted => with_timeout(POSTED_DISPLAY_TIME, LED_SIGNAL.wait())
+                .await
+                .unwrap_or(LedState::Idle),
+            _ => LED_SIGNAL.wait().await,
+        };
+    }
+}
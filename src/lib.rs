@@ -0,0 +1,11 @@
+//! The parts of the relay that don't depend on the hardware: parsing and
+//! aggregating the Tilts' broadcasts, formatting the data that is posted, and
+//! parsing the HTTP responses to it.
+//! They are built as a library so they can also be compiled and tested on the
+//! host, see the README. The firmware is the binary in main.rs, which uses
+//! them from here.
+#![cfg_attr(not(test), no_std)]
+
+pub mod http;
+pub mod payload;
+pub mod tilt;
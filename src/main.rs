@@ -11,28 +11,56 @@ use esp32c3_hal::{
     embassy,
     peripherals::Peripherals,
     prelude::*,
+    pulse_control::{ClockSource, PulseControl},
     systimer::SystemTimer,
     timer::TimerGroup,
+    Delay,
+    IO,
     Rng,
     Rtc,
 };
+use esp_hal_smartled::smartLedAdapter;
 use log::{error, info};
 use static_cell::StaticCell;
+// The hardware independent modules are in the library, see lib.rs. The leading
+// :: is needed since the tilt_relay module has the same name as the crate.
+use ::tilt_relay::{http, payload, tilt};
 
+mod boot;
 mod esp_logger;
-mod tilt;
+mod http_server;
+mod influx;
+mod led;
+mod mqtt;
+mod ota;
+mod self_test;
+mod sntp;
+mod status;
+mod syslog;
 mod tilt_scanner;
 mod tilt_relay;
 mod wifi;
 
 use crate::tilt_scanner::TiltScanner;
 
+// For debugging, halt on a panic instead of resetting, so the panic can be read
+// over serial. The watchdog isn't started either, since it would reset the
+// halted device. Production builds should reset so the relay recovers.
+const HALT_ON_PANIC: bool = false;
+
 static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
-/// A panic handler that resets the whole device if a panic occurs.
+/// A panic handler that resets the whole device if a panic occurs, or halts it
+/// if HALT_ON_PANIC is set.
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     error!("{:#?}", info);
+
+    if HALT_ON_PANIC {
+        error!("Halted after panic");
+        loop {}
+    }
+
     esp32c3_hal::reset::software_reset();
     // Wait for the reset to occur
     loop {}
@@ -43,12 +71,14 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
 fn main() -> ! {
     esp_logger::init_logger(log::LevelFilter::Info);
     info!("Relay initializing...");
+    boot::record_boot();
 
     let peripherals = Peripherals::take();
     let mut system = peripherals.SYSTEM.split();
     let clocks = ClockControl::configure(system.clock_control, CpuClock::Clock160MHz).freeze();
 
-    // Disable the RTC and TIMG watchdog timers
+    // Disable the RTC and TIMG1 watchdog timers. The TIMG0 watchdog is enabled
+    // once the relay starts, which feeds it.
     let mut rtc = Rtc::new(peripherals.RTC_CNTL);
     let timer_group0 = TimerGroup::new(peripherals.TIMG0, &clocks, &mut system.peripheral_clock_control);
     let mut wdt0 = timer_group0.wdt;
@@ -60,6 +90,21 @@ fn main() -> ! {
     wdt0.disable();
     wdt1.disable();
 
+    // Used by the relay to deep sleep between scans
+    let delay = Delay::new(&clocks);
+
+    // The status LED is the NeoPixel on GPIO2, driven by the RMT
+    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
+    let pulse = PulseControl::new(
+        peripherals.RMT,
+        &mut system.peripheral_clock_control,
+        ClockSource::APB,
+        0,
+        0,
+        0,
+    ).unwrap();
+    let status_led = <smartLedAdapter!(1)>::new(pulse.channel0, io.pins.gpio2);
+
     let mut rng = Rng::new(peripherals.RNG);
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
@@ -72,14 +117,42 @@ fn main() -> ! {
         &clocks,
     ).unwrap();
 
+    // The time driver must be initialized before the scanner, which uses it
+    // to time the discovery of Tilts.
+    embassy::init(&clocks, timer_group0.timer0);
+    esp_logger::enable_timestamps();
+
     let mut tilt_scanner = TiltScanner::new(bluetooth);
+
+    // The Bluetooth part of the self test runs now, while nothing else is using
+    // the controller. The WiFi part runs once the network stack is up.
+    if self_test::SELF_TEST {
+        let n_advertisers = tilt_scanner.self_test(self_test::SELF_TEST_SCAN_DURATION);
+        self_test::record_bluetooth_result(n_advertisers);
+
+        if self_test::HALT_AFTER_SELF_TEST {
+            let executor = EXECUTOR.init_with(Executor::new);
+            executor.run(|spawner| {
+                spawner.must_spawn(wifi::run_wifi_task(spawner, seed, wifi));
+            });
+        }
+    }
+
     tilt_scanner.init();
 
-    embassy::init(&clocks, timer_group0.timer0);
+    // Finding the Tilts can take longer than the watchdog timeout, so only
+    // start the watchdog after the scanner is initialized.
+    if !HALT_ON_PANIC {
+        wdt0.start(tilt_relay::WATCHDOG_TIMEOUT_SECS.secs());
+    }
 
     let executor = EXECUTOR.init_with(Executor::new);
     executor.run(|spawner| {
         spawner.must_spawn(wifi::run_wifi_task(spawner, seed, wifi));
-        spawner.must_spawn(tilt_relay::run_relay_task(tilt_scanner));
+        spawner.must_spawn(tilt_relay::run_relay_task(tilt_scanner, wdt0, rtc, delay));
+
+        if led::STATUS_LED_ENABLED {
+            spawner.must_spawn(led::led_task(status_led));
+        }
     });
 }
\ No newline at end of file
@@ -0,0 +1,720 @@
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+use esp_wifi::wifi::WifiDevice;
+use log::{error, info, warn};
+use smoltcp::socket;
+
+use crate::led::LedState;
+use crate::payload::{Wrapper, BATTERY_AS_PERCENT, GRAVITY_UNIT, TEMP_UNIT};
+use crate::tilt::{GravityUnit, TemperatureUnit, TiltColor, TiltData};
+use crate::wifi::{
+    format_json,
+    post_backoff_ms,
+    signal_published,
+    sleep_ms,
+    wait_until,
+    DATA_CHANNEL,
+    MAX_FAILURES,
+    MAX_POST_ATTEMPTS,
+};
+
+// The MQTT broker to publish to, e.g. the Mosquitto add-on in Home Assistant
+const MQTT_BROKER_ENDPOINT: (IpAddress, u16) = (IpAddress::v4(192, 168, 0, 100), 1883);
+const MQTT_CLIENT_ID: &str = "tilt-relay";
+// Set these if the broker requires authentication
+const MQTT_USERNAME: Option<&str> = None;
+const MQTT_PASSWORD: Option<&str> = None;
+// The data from each Tilt is published to "<prefix>/<color>", e.g. "tilt/red"
+const MQTT_TOPIC_PREFIX: &str = "tilt";
+const MQTT_KEEP_ALIVE_SECS: u16 = 60;
+
+// Announce the sensors of each Tilt to Home Assistant using MQTT discovery.
+// This happens once per boot for each Tilt, before its first data is published.
+const HOME_ASSISTANT_DISCOVERY: bool = true;
+const HOME_ASSISTANT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+// Accept commands on "<prefix>/<client ID>/cmd", e.g. "tilt_relay/tilt-relay/cmd":
+// "rescan" looks for the Tilts again, "reboot" resets the relay, and
+// "set_interval:<secs>" fixes the publish interval instead of adapting it to
+// the gravity. The relay only connects to the broker to publish, so commands
+// must be published with the retain flag. A command is cleared once received
+// and acted on at the start of the next cycle. They are lost with DEEP_SLEEP.
+const MQTT_COMMANDS_ENABLED: bool = false;
+const MQTT_COMMAND_TOPIC_PREFIX: &str = "tilt_relay";
+// How long to wait for a retained command after subscribing
+const MQTT_COMMAND_WAIT: Duration = Duration::from_secs(2);
+const COMMAND_QUEUE_SIZE: usize = 4;
+
+const PACKET_TYPE_CONNECT: u8 = 0x10;
+const PACKET_TYPE_CONNACK: u8 = 0x20;
+const PACKET_TYPE_PUBLISH: u8 = 0x30;
+// The low bits of a SUBSCRIBE are reserved and must be 0b0010
+const PACKET_TYPE_SUBSCRIBE: u8 = 0x82;
+const PACKET_TYPE_SUBACK: u8 = 0x90;
+const PACKET_TYPE_DISCONNECT: u8 = 0xE0;
+
+const CONNECT_FLAG_CLEAN_SESSION: u8 = 0x02;
+const CONNECT_FLAG_PASSWORD: u8 = 0x40;
+const CONNECT_FLAG_USERNAME: u8 = 0x80;
+/// MQTT version 3.1.1
+const PROTOCOL_LEVEL: u8 = 0x04;
+/// Only one SUBSCRIBE is sent per connection, so its ID never needs to change
+const SUBSCRIBE_PACKET_ID: u16 = 1;
+/// The SUBACK return code for a refused subscription
+const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+/// The fixed header is 1 byte for the packet type followed by up to 4 bytes
+/// for the length of the rest of the packet.
+const MAX_FIXED_HEADER_LENGTH: usize = 5;
+
+#[derive(Debug)]
+enum MqttError {
+    Tcp(embassy_net::tcp::Error),
+    /// The broker closed the connection before responding
+    Eof,
+    /// The broker responded with something other than a CONNACK or SUBACK
+    UnexpectedResponse,
+    /// The broker refused the connection. Contains the return code.
+    ConnectionRefused(u8),
+    /// The broker refused the subscription to the command topic
+    SubscribeRefused,
+    /// The data doesn't fit in the buffer for the JSON or packet
+    TooLong,
+}
+
+impl From<embassy_net::tcp::Error> for MqttError {
+    fn from(e: embassy_net::tcp::Error) -> Self {
+        MqttError::Tcp(e)
+    }
+}
+
+/// A command received over MQTT, see MQTT_COMMANDS_ENABLED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Look for the Tilts again, e.g. after one is replaced
+    Rescan,
+    /// Reset the relay
+    Reboot,
+    /// Publish at this interval from now on
+    SetInterval(Duration),
+}
+
+impl Command {
+    /// Parses the command in `payload`. It must match exactly, without any
+    /// whitespace, and the interval must be a plain number of seconds.
+    fn parse(payload: &[u8]) -> Option<Command> {
+        match payload {
+            b"rescan" => Some(Command::Rescan),
+            b"reboot" => Some(Command::Reboot),
+            _ => {
+                let secs = payload.strip_prefix(b"set_interval:")?;
+                if secs.is_empty() || !secs.iter().all(u8::is_ascii_digit) {
+                    return None;
+                }
+
+                // Fails if there are too many digits for a u32
+                let secs: u32 = core::str::from_utf8(secs).ok()?.parse().ok()?;
+                Some(Command::SetInterval(Duration::from_secs(secs as u64)))
+            }
+        }
+    }
+}
+
+/// The commands received over MQTT, for the relay task to act on.
+pub static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, Command, COMMAND_QUEUE_SIZE> = Channel::new();
+
+/// Publishes the Tilt data to an MQTT broker. This is the MQTT equivalent of
+/// `wifi::http_task`, with the same retry and failure handling.
+#[embassy_executor::task]
+pub async fn mqtt_task(stack: &'static Stack<WifiDevice<'static>>) {
+    if wait_until(|| stack.is_link_up()).await.is_err() {
+        panic!("Stalled while waiting for link to come up");
+    }
+
+    if wait_until(|| stack.config().is_some()).await.is_err() {
+        panic!("Stalled while waiting for config to be ready");
+    }
+
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(embassy_net::SmolDuration::from_secs(10)));
+
+    let mut n_failures = 0;
+    // Which Tilt colors have been announced to Home Assistant
+    let mut announced = [!HOME_ASSISTANT_DISCOVERY; TiltColor::COUNT];
+
+    loop {
+        // Wait for the relay to scan for the Tilts and signal us with data
+        let (scan_time, readings) = DATA_CHANNEL.receive().await;
+
+        if crate::wifi::is_idle() {
+            warn!("WiFi is idle, not publishing");
+            signal_published(false);
+            continue;
+        }
+
+        crate::wifi::update_wifi_info();
+        let mut all_published = true;
+
+        // Publish the data from each Tilt separately. Notices have nothing new
+        // to publish.
+        for tilt_data in readings.iter().flatten().copied().filter(TiltData::is_reading) {
+            let announce = &mut announced[tilt_data.color().index()];
+
+            let mut attempt = 1;
+            let mut success = false;
+
+            while !success && attempt <= MAX_POST_ATTEMPTS {
+                // Retries should sleep with some backoff
+                if attempt > 1 {
+                    sleep_ms(post_backoff_ms(attempt - 1)).await;
+                }
+
+                attempt += 1;
+
+                // Close the socket
+                if socket.state() != socket::tcp::State::Closed {
+                    socket.close();
+
+                    // Wait for the socket to actually close. If the broker
+                    // never acknowledges the close, abort it so the next
+                    // connection starts cleanly.
+                    if wait_until(|| socket.state() == socket::tcp::State::Closed).await.is_err() {
+                        warn!("Stalled while waiting for socket to close, aborting it");
+                        socket.abort();
+                    }
+                }
+
+                if let Err(e) = socket.connect(MQTT_BROKER_ENDPOINT).await {
+                    warn!("connect error: {:?}", e);
+                    continue;
+                }
+
+                match publish(&mut socket, tilt_data, scan_time, !*announce).await {
+                    Ok(_) => {
+                        success = true;
+                        *announce = true;
+                    }
+                    Err(e) => warn!("MQTT error: {:?}", e),
+                }
+
+                socket.close();
+            }
+
+            // Limit the number of times we can completely fail to publish, the
+            // same as wifi::http_task does.
+            crate::status::record_post(success);
+            crate::led::set_state(if success { LedState::Posted } else { LedState::Failing });
+            if success {
+                n_failures = 0;
+            } else {
+                error!("Failed to publish {} tilt data", tilt_data.color().name());
+                all_published = false;
+                n_failures += 1;
+
+                if n_failures >= MAX_FAILURES {
+                    panic!("Too many failures, panicking to induce a reset...");
+                }
+            }
+        }
+
+        if MQTT_COMMANDS_ENABLED {
+            check_commands(&mut socket).await;
+        }
+
+        signal_published(all_published);
+    }
+}
+
+/// Connects to the broker on the `socket` to receive any retained command, see
+/// `receive_command`. Failures are only logged, since the command stays
+/// retained until the next check.
+async fn check_commands(socket: &mut TcpSocket<'_>) {
+    if socket.state() != socket::tcp::State::Closed {
+        socket.close();
+
+        if wait_until(|| socket.state() == socket::tcp::State::Closed).await.is_err() {
+            warn!("Stalled while waiting for socket to close, aborting it");
+            socket.abort();
+        }
+    }
+
+    match socket.connect(MQTT_BROKER_ENDPOINT).await {
+        Ok(_) => {
+            if let Err(e) = receive_command(socket).await {
+                warn!("MQTT command error: {:?}", e);
+            }
+        }
+        Err(e) => warn!("connect error: {:?}", e),
+    }
+
+    socket.close();
+}
+
+/// Performs a complete MQTT session on the connected `socket`: connects to the
+/// broker, subscribes to the command topic, and waits up to MQTT_COMMAND_WAIT
+/// for the retained command. If there is one, it is cleared so it only runs
+/// once, and queued on COMMAND_CHANNEL if it is valid.
+async fn receive_command(socket: &mut TcpSocket<'_>) -> Result<(), MqttError> {
+    let mut buffer = [0u8; 128];
+    write_all(socket, connect_packet(&mut buffer)).await?;
+    read_connack(socket).await?;
+
+    let mut topic_buffer = [0u8; 64];
+    let topic = command_topic(&mut topic_buffer);
+    write_all(socket, subscribe_packet(&mut buffer, topic)).await?;
+
+    // The broker sends the SUBACK, then the retained command if there is one
+    let wait_end_time = Instant::now() + MQTT_COMMAND_WAIT;
+    let mut subscribed = false;
+    let mut received = None;
+
+    while received.is_none() {
+        let (packet_type, len) = match select(read_packet(socket, &mut buffer), Timer::at(wait_end_time)).await {
+            Either::First(result) => result?,
+            Either::Second(_) => break,
+        };
+        let body = &buffer[..len];
+
+        if packet_type == PACKET_TYPE_SUBACK {
+            // The packet ID, then the return code for the one topic
+            if len != 3 {
+                return Err(MqttError::UnexpectedResponse);
+            }
+            if body[2] == SUBSCRIBE_FAILURE {
+                return Err(MqttError::SubscribeRefused);
+            }
+            subscribed = true;
+        } else if packet_type & 0xF0 == PACKET_TYPE_PUBLISH {
+            // With QoS 0, the topic is followed directly by the payload. An
+            // empty payload is the command being cleared.
+            let topic_len = match body {
+                [high, low, ..] => 2 + u16::from_be_bytes([*high, *low]) as usize,
+                _ => return Err(MqttError::UnexpectedResponse),
+            };
+            let payload = body.get(topic_len..).ok_or(MqttError::UnexpectedResponse)?;
+            if !payload.is_empty() {
+                received = Some(Command::parse(payload));
+                if received == Some(None) {
+                    warn!("Unrecognized MQTT command: {:?}", core::str::from_utf8(payload).unwrap_or("<invalid UTF-8>"));
+                }
+            }
+        }
+    }
+
+    if !subscribed {
+        warn!("No SUBACK for {} from the broker", topic);
+    }
+
+    if let Some(command) = received {
+        // Publishing an empty retained message deletes the retained command
+        write_all(socket, publish_packet(&mut buffer, topic, &[], true)).await?;
+
+        if let Some(command) = command {
+            info!("Received MQTT command: {:?}", command);
+            if COMMAND_CHANNEL.try_send(command).is_err() {
+                warn!("Too many MQTT commands queued, dropping {:?}", command);
+            }
+        }
+    }
+
+    write_all(socket, &[PACKET_TYPE_DISCONNECT, 0x00]).await?;
+
+    Ok(())
+}
+
+/// Returns the topic that commands for this relay are received on.
+fn command_topic(buffer: &mut [u8; 64]) -> &str {
+    use core::fmt::Write;
+
+    let mut wrapper = Wrapper::new(buffer);
+    write!(wrapper, "{}/{}/cmd", MQTT_COMMAND_TOPIC_PREFIX, MQTT_CLIENT_ID).unwrap();
+
+    let len = wrapper.written();
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
+
+/// Performs a complete MQTT session on the connected `socket`: connects to the
+/// broker, publishes the `tilt_data` with QoS 0, then disconnects. If
+/// `discovery` is set, the Home Assistant discovery config for the Tilt is
+/// published before the data.
+async fn publish(
+    socket: &mut TcpSocket<'_>,
+    tilt_data: TiltData,
+    scan_time: Instant,
+    discovery: bool,
+) -> Result<(), MqttError> {
+    let mut buffer = [0u8; 640];
+    write_all(socket, connect_packet(&mut buffer)).await?;
+    read_connack(socket).await?;
+
+    if discovery {
+        publish_discovery(socket, &mut buffer, tilt_data).await?;
+    }
+
+    let mut json_buffer = [0u8; 384];
+    let json = format_json(tilt_data, crate::sntp::unix_time(scan_time), &mut json_buffer)
+        .map_err(|_| MqttError::TooLong)?;
+
+    let mut topic_buffer = [0u8; 64];
+    let topic = topic_for(tilt_data, &mut topic_buffer);
+
+    write_all(socket, publish_packet(&mut buffer, topic, json.as_bytes(), false)).await?;
+    info!("Published to {}: {}", topic, json);
+
+    write_all(socket, &[PACKET_TYPE_DISCONNECT, 0x00]).await?;
+
+    Ok(())
+}
+
+/// Returns the topic the data from the Tilt that transmitted `tilt_data`
+/// is published to.
+fn topic_for(tilt_data: TiltData, buffer: &mut [u8; 64]) -> &str {
+    use core::fmt::Write;
+
+    let mut wrapper = Wrapper::new(buffer);
+    write!(wrapper, "{}/{}", MQTT_TOPIC_PREFIX, color_id(tilt_data.color(), &mut [0u8; 8])).unwrap();
+
+    let len = wrapper.written();
+    core::str::from_utf8(&buffer[..len]).unwrap()
+}
+
+/// Returns the lowercase name of the `color`, for use in topics and IDs.
+fn color_id(color: TiltColor, buffer: &mut [u8; 8]) -> &str {
+    let name = color.name();
+    let id = &mut buffer[..name.len()];
+    id.copy_from_slice(name.as_bytes());
+    id.make_ascii_lowercase();
+
+    core::str::from_utf8(id).unwrap()
+}
+
+/// A sensor announced to Home Assistant.
+struct Sensor {
+    /// Identifies the sensor within the Tilt's device
+    object_id: &'static str,
+    name: &'static str,
+    /// The field of the JSON payload that contains the sensor's value
+    field: &'static str,
+    device_class: Option<&'static str>,
+    unit: &'static str,
+}
+
+/// Returns the sensors of a Tilt, with units matching the published payload.
+fn sensors() -> [Sensor; 3] {
+    [
+        Sensor {
+            object_id: "temperature",
+            name: "Temperature",
+            field: "temp",
+            device_class: Some("temperature"),
+            unit: match TEMP_UNIT {
+                TemperatureUnit::Fahrenheit => "°F",
+                TemperatureUnit::Celsius => "°C",
+            },
+        },
+        Sensor {
+            object_id: "gravity",
+            name: "Gravity",
+            field: "gravity",
+            device_class: None,
+            unit: match GRAVITY_UNIT {
+                GravityUnit::SpecificGravity => "SG",
+                GravityUnit::Plato => "°P",
+                GravityUnit::Brix => "°Bx",
+            },
+        },
+        // Home Assistant's battery device class must be a percentage
+        if BATTERY_AS_PERCENT {
+            Sensor {
+                object_id: "battery",
+                name: "Battery",
+                field: "battery",
+                device_class: Some("battery"),
+                unit: "%",
+            }
+        } else {
+            Sensor {
+                object_id: "battery",
+                name: "Battery Age",
+                field: "battery",
+                device_class: None,
+                unit: "weeks",
+            }
+        },
+    ]
+}
+
+/// Publishes the retained Home Assistant discovery config for each sensor of
+/// the Tilt that transmitted `tilt_data`. The unique IDs are derived from the
+/// Tilt's BLE address so they don't collide with other Tilts or relays.
+async fn publish_discovery(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+    tilt_data: TiltData,
+) -> Result<(), MqttError> {
+    use core::fmt::Write;
+
+    let mut color_buffer = [0u8; 8];
+    let color = color_id(tilt_data.color(), &mut color_buffer);
+
+    let mut state_topic_buffer = [0u8; 64];
+    let state_topic = topic_for(tilt_data, &mut state_topic_buffer);
+
+    // The address is little endian and prefixed by the address type
+    let mut device_id_buffer = [0u8; 32];
+    let mut wrapper = Wrapper::new(&mut device_id_buffer);
+    write!(wrapper, "tilt_").unwrap();
+    for b in tilt_data.address()[1..].iter().rev() {
+        write!(wrapper, "{:02x}", b).unwrap();
+    }
+    let len = wrapper.written();
+    let device_id = core::str::from_utf8(&device_id_buffer[..len]).unwrap();
+
+    for sensor in sensors() {
+        let mut topic_buffer = [0u8; 128];
+        let mut wrapper = Wrapper::new(&mut topic_buffer);
+        write!(wrapper,
+            "{}/sensor/tilt_{}/{}/config",
+            HOME_ASSISTANT_DISCOVERY_PREFIX, color, sensor.object_id,
+        ).unwrap();
+        let len = wrapper.written();
+        let topic = core::str::from_utf8(&topic_buffer[..len]).unwrap();
+
+        let mut config_buffer = [0u8; 512];
+        let mut wrapper = Wrapper::new(&mut config_buffer);
+        write!(wrapper,
+            "{{ \
+            \"name\": \"{name}\", \
+            \"unique_id\": \"{device_id}_{object_id}\", \
+            \"state_topic\": \"{state_topic}\", \
+            \"value_template\": \"{{{{ value_json.{field} }}}}\", \
+            \"unit_of_measurement\": \"{unit}\", \
+            \"state_class\": \"measurement\", ",
+            name = sensor.name,
+            device_id = device_id,
+            object_id = sensor.object_id,
+            state_topic = state_topic,
+            field = sensor.field,
+            unit = sensor.unit,
+        ).unwrap();
+        if let Some(device_class) = sensor.device_class {
+            write!(wrapper, "\"device_class\": \"{}\", ", device_class).unwrap();
+        }
+        write!(wrapper,
+            "\"device\": {{ \
+            \"identifiers\": [\"{}\"], \
+            \"name\": \"Tilt {}\", \
+            \"manufacturer\": \"Baron Brew Equipment\", \
+            \"model\": \"Tilt Hydrometer\" \
+            }} }}",
+            device_id, tilt_data.color().name(),
+        ).unwrap();
+        let len = wrapper.written();
+        let config = &config_buffer[..len];
+
+        write_all(socket, publish_packet(buffer, topic, config, true)).await?;
+        info!("Announced {} to Home Assistant", topic);
+    }
+
+    Ok(())
+}
+
+/// Waits for the broker's CONNACK packet and checks that the connection was
+/// accepted.
+async fn read_connack(socket: &mut TcpSocket<'_>) -> Result<(), MqttError> {
+    // A CONNACK is always 4 bytes
+    let mut buffer = [0u8; 4];
+    read_exact(socket, &mut buffer).await?;
+
+    if buffer[0] != PACKET_TYPE_CONNACK || buffer[1] != 0x02 {
+        return Err(MqttError::UnexpectedResponse);
+    }
+
+    // The last byte is the return code, with 0 indicating success
+    if buffer[3] != 0x00 {
+        return Err(MqttError::ConnectionRefused(buffer[3]));
+    }
+
+    Ok(())
+}
+
+/// Reads the next packet from the broker on the `socket`. Returns its type, the
+/// first byte of the fixed header, and the length of the rest of the packet,
+/// which is read into the start of `buffer`.
+async fn read_packet(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<(u8, usize), MqttError> {
+    let mut packet_type = [0u8; 1];
+    read_exact(socket, &mut packet_type).await?;
+
+    // The length is encoded the same as by `PacketBuilder::finish`
+    let mut len = 0;
+    for i in 0..(MAX_FIXED_HEADER_LENGTH - 1) {
+        let mut byte = [0u8; 1];
+        read_exact(socket, &mut byte).await?;
+        len |= ((byte[0] & 0x7F) as usize) << (7 * i);
+
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let body = buffer.get_mut(..len).ok_or(MqttError::TooLong)?;
+    read_exact(socket, body).await?;
+
+    Ok((packet_type[0], len))
+}
+
+/// Fills `buffer` from the `socket`, since the data may not arrive all at once.
+async fn read_exact(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<(), MqttError> {
+    let mut len = 0;
+
+    while len < buffer.len() {
+        match socket.read(&mut buffer[len..]).await? {
+            0 => return Err(MqttError::Eof),
+            n => len += n,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes all of `data` to the `socket` and flushes it.
+async fn write_all(socket: &mut TcpSocket<'_>, mut data: &[u8]) -> Result<(), MqttError> {
+    while !data.is_empty() {
+        match socket.write(data).await? {
+            0 => return Err(MqttError::Eof),
+            n => data = &data[n..],
+        }
+    }
+
+    Ok(socket.flush().await?)
+}
+
+/// Constructs an MQTT CONNECT packet in `buffer` using the configured client
+/// ID and credentials.
+fn connect_packet(buffer: &mut [u8]) -> &[u8] {
+    let mut flags = CONNECT_FLAG_CLEAN_SESSION;
+    if MQTT_USERNAME.is_some() {
+        flags |= CONNECT_FLAG_USERNAME;
+    }
+    if MQTT_PASSWORD.is_some() {
+        flags |= CONNECT_FLAG_PASSWORD;
+    }
+
+    let mut packet = PacketBuilder::new(buffer);
+    packet.put_str("MQTT");
+    packet.put_u8(PROTOCOL_LEVEL);
+    packet.put_u8(flags);
+    packet.put_u16(MQTT_KEEP_ALIVE_SECS);
+    packet.put_str(MQTT_CLIENT_ID);
+
+    if let Some(username) = MQTT_USERNAME {
+        packet.put_str(username);
+    }
+    if let Some(password) = MQTT_PASSWORD {
+        packet.put_str(password);
+    }
+
+    packet.finish(PACKET_TYPE_CONNECT)
+}
+
+/// Constructs an MQTT PUBLISH packet in `buffer` with QoS 0. If `retain` is
+/// set, the broker keeps the message and sends it to new subscribers.
+fn publish_packet<'a>(buffer: &'a mut [u8], topic: &str, payload: &[u8], retain: bool) -> &'a [u8] {
+    let packet_type = if retain {
+        PACKET_TYPE_PUBLISH | 0x01
+    } else {
+        PACKET_TYPE_PUBLISH
+    };
+
+    let mut packet = PacketBuilder::new(buffer);
+    packet.put_str(topic);
+    packet.put_bytes(payload);
+    packet.finish(packet_type)
+}
+
+/// Constructs an MQTT SUBSCRIBE packet in `buffer` for the `topic`, with QoS 0.
+fn subscribe_packet<'a>(buffer: &'a mut [u8], topic: &str) -> &'a [u8] {
+    let mut packet = PacketBuilder::new(buffer);
+    packet.put_u16(SUBSCRIBE_PACKET_ID);
+    packet.put_str(topic);
+    packet.put_u8(0x00);
+    packet.finish(PACKET_TYPE_SUBSCRIBE)
+}
+
+/// A helper for constructing MQTT control packets. The contents of the packet
+/// are written first, leaving room for the fixed header, which can only be
+/// written once the length of the contents is known.
+struct PacketBuilder<'a> {
+    buffer: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> PacketBuilder<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            offset: MAX_FIXED_HEADER_LENGTH,
+        }
+    }
+
+    fn put_u8(&mut self, value: u8) {
+        self.put_bytes(&[value]);
+    }
+
+    /// MQTT integers are big endian
+    fn put_u16(&mut self, value: u16) {
+        self.put_bytes(&value.to_be_bytes());
+    }
+
+    /// MQTT strings are prefixed with their length
+    fn put_str(&mut self, s: &str) {
+        self.put_u16(s.len() as u16);
+        self.put_bytes(s.as_bytes());
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        self.buffer[self.offset..(self.offset + bytes.len())].copy_from_slice(bytes);
+        self.offset += bytes.len();
+    }
+
+    /// Writes the fixed header with the given `packet_type` and returns the
+    /// complete packet. The length of the contents is encoded 7 bits at a time,
+    /// least significant first, with the high bit set if more bytes follow.
+    fn finish(self, packet_type: u8) -> &'a [u8] {
+        let mut header = [0u8; MAX_FIXED_HEADER_LENGTH];
+        let mut header_len = 1;
+        let mut remaining = self.offset - MAX_FIXED_HEADER_LENGTH;
+
+        header[0] = packet_type;
+
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+
+            header[header_len] = byte;
+            header_len += 1;
+
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        let start = MAX_FIXED_HEADER_LENGTH - header_len;
+        let end = self.offset;
+        let buffer = self.buffer;
+        buffer[start..MAX_FIXED_HEADER_LENGTH].copy_from_slice(&header[..header_len]);
+
+        let buffer: &'a [u8] = buffer;
+        &buffer[start..end]
+    }
+}
@@ -0,0 +1,316 @@
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, Stack};
+use embassy_time::{Duration, Timer};
+use embedded_io::asynch::Write as _;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::{FlashStorage, FlashStorageError};
+use esp_wifi::wifi::WifiDevice;
+use log::{info, warn};
+
+use crate::http;
+use crate::payload::Wrapper;
+use crate::wifi::wait_until;
+
+// Periodically check an HTTP server for new firmware and install it. The
+// server should respond to the request with 204 No Content if there is no
+// update, or 200 OK with the image as the body if there is. The response must
+// include a Content-Length header, and an X-Firmware-CRC32 header with the
+// CRC-32 of the image in hexadecimal.
+pub const OTA_ENABLED: bool = false;
+const OTA_ENDPOINT: (IpAddress, u16) = (IpAddress::v4(192, 168, 0, 100), 8000);
+const OTA_HOSTNAME: &str = "192.168.0.100";
+const OTA_PATH: &str = "/tilt-relay/firmware.bin";
+// How often to check for an update. There is also a check after every boot.
+const OTA_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Sent to the server in the X-Firmware-Version header so it can decide whether
+// it has a newer image
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// These must match partitions.csv
+const OTADATA_OFFSET: u32 = 0xD000;
+const OTA_PARTITION_OFFSETS: [u32; 2] = [0x10000, 0x200000];
+const OTA_PARTITION_SIZE: u32 = 0x1F0000;
+const SECTOR_SIZE: usize = 4096;
+
+/// The first byte of every ESP application image
+const IMAGE_MAGIC: u8 = 0xE9;
+/// The OTA data entry is a u32 sequence number, a 20-byte label, a u32 state
+/// and a u32 CRC of the sequence number
+const OTADATA_ENTRY_LENGTH: usize = 32;
+const OTADATA_STATE_INDEX: usize = 24;
+const OTADATA_CRC_INDEX: usize = 28;
+/// The state the bootloader expects when app rollback is disabled
+const OTA_STATE_UNDEFINED: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug)]
+enum OtaError {
+    Connect(embassy_net::tcp::ConnectError),
+    Tcp(embassy_net::tcp::Error),
+    Flash(FlashStorageError),
+    InvalidResponse,
+    UnexpectedStatus,
+    TooLarge(u32),
+    LengthMismatch { expected: u32, actual: u32 },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    InvalidImage,
+}
+
+impl From<embassy_net::tcp::Error> for OtaError {
+    fn from(e: embassy_net::tcp::Error) -> Self {
+        OtaError::Tcp(e)
+    }
+}
+
+impl From<FlashStorageError> for OtaError {
+    fn from(e: FlashStorageError) -> Self {
+        OtaError::Flash(e)
+    }
+}
+
+/// A valid entry in the OTA data partition, which tells the bootloader which
+/// app partition to boot
+#[derive(Clone, Copy)]
+struct OtaEntry {
+    seq: u32,
+    // Which of the two copies of the OTA data this came from
+    sector: usize,
+}
+
+#[embassy_executor::task]
+pub async fn ota_task(stack: &'static Stack<WifiDevice<'static>>) {
+    if wait_until(|| stack.is_link_up()).await.is_err() {
+        panic!("Stalled while waiting for link to come up");
+    }
+
+    if wait_until(|| stack.config().is_some()).await.is_err() {
+        panic!("Stalled while waiting for config to be ready");
+    }
+
+    loop {
+        match update(stack).await {
+            Ok(false) => info!("Firmware is up to date"),
+            Ok(true) => {
+                info!("Firmware updated, restarting...");
+                esp32c3_hal::reset::software_reset();
+            }
+            Err(e) => warn!("Firmware update failed: {:?}", e),
+        }
+
+        Timer::after(OTA_CHECK_INTERVAL).await;
+    }
+}
+
+/// Asks the server for new firmware and writes it to the inactive OTA
+/// partition. The boot partition is only switched once the written image has
+/// been read back and verified. Returns whether there was an update.
+async fn update(stack: &'static Stack<WifiDevice<'static>>) -> Result<bool, OtaError> {
+    let mut rx_buffer = [0u8; 4096];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(embassy_net::SmolDuration::from_secs(10)));
+
+    socket.connect(OTA_ENDPOINT).await.map_err(OtaError::Connect)?;
+
+    let result = download(&mut socket).await;
+
+    socket.close();
+    if result.is_err() {
+        socket.abort();
+    }
+
+    result
+}
+
+/// Sends the update request on the connected `socket` and writes the image in
+/// the response, if there is one.
+async fn download(socket: &mut TcpSocket<'_>) -> Result<bool, OtaError> {
+    use core::fmt::Write;
+
+    let mut request_buffer = [0u8; 256];
+    let mut wrapper = Wrapper::new(&mut request_buffer);
+    write!(wrapper,
+        "GET {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        X-Firmware-Version: {}\r\n\
+        Connection: close\r\n\
+        \r\n",
+        OTA_PATH,
+        OTA_HOSTNAME,
+        FIRMWARE_VERSION,
+    ).unwrap();
+    let len = wrapper.written();
+
+    socket.write_all(&request_buffer[..len]).await?;
+    socket.flush().await?;
+
+    // Read until the end of the headers. Anything after them is the start of
+    // the image.
+    let mut response = [0u8; 1024];
+    let mut len = 0;
+    let headers_end = loop {
+        if let Some(headers_length) = http::headers_length(&response[..len]) {
+            break headers_length;
+        }
+
+        if len == response.len() {
+            return Err(OtaError::InvalidResponse);
+        }
+
+        match socket.read(&mut response[len..]).await? {
+            0 => return Err(OtaError::InvalidResponse),
+            n => len += n,
+        }
+    };
+
+    let headers = core::str::from_utf8(&response[..headers_end]).map_err(|_| OtaError::InvalidResponse)?;
+
+    match http::status_code(headers.as_bytes()) {
+        Some(204) | Some(304) => return Ok(false),
+        Some(200) => {}
+        _ => return Err(OtaError::UnexpectedStatus),
+    }
+
+    let image_length = http::header(headers, "Content-Length")
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(OtaError::InvalidResponse)?;
+    let expected_crc = http::header(headers, "X-Firmware-CRC32")
+        .and_then(|v| u32::from_str_radix(v, 16).ok())
+        .ok_or(OtaError::InvalidResponse)?;
+
+    if image_length == 0 {
+        return Err(OtaError::InvalidImage);
+    }
+
+    if image_length > OTA_PARTITION_SIZE {
+        return Err(OtaError::TooLarge(image_length));
+    }
+
+    let mut flash = FlashStorage::new();
+    let current = read_otadata(&mut flash)?;
+    // With no valid OTA data, the bootloader boots the first partition
+    let current_seq = current.map_or(1, |entry| entry.seq);
+    let new_seq = current_seq + 1;
+    // The bootloader boots partition (seq - 1) % the number of partitions
+    let partition_offset = OTA_PARTITION_OFFSETS[(new_seq - 1) as usize % OTA_PARTITION_OFFSETS.len()];
+
+    info!("Downloading {} byte firmware to partition at {:#X}", image_length, partition_offset);
+
+    // Write the image a sector at a time, starting with what was read with the
+    // headers
+    let mut sector = [0xFFu8; SECTOR_SIZE];
+    let mut filled = len - headers_end;
+    let mut received = filled as u32;
+    let mut written = 0;
+    sector[..filled].copy_from_slice(&response[headers_end..len]);
+
+    if received > image_length {
+        return Err(OtaError::LengthMismatch { expected: image_length, actual: received });
+    }
+
+    loop {
+        if filled == SECTOR_SIZE || (received == image_length && filled > 0) {
+            if written == 0 && sector[0] != IMAGE_MAGIC {
+                return Err(OtaError::InvalidImage);
+            }
+
+            sector[filled..].fill(0xFF);
+            flash.write(partition_offset + written, &sector)?;
+            written += SECTOR_SIZE as u32;
+            filled = 0;
+        }
+
+        if received == image_length {
+            break;
+        }
+
+        // Never read past the end of the image
+        let remaining = (image_length - received) as usize;
+        let end = SECTOR_SIZE.min(filled + remaining);
+
+        match socket.read(&mut sector[filled..end]).await? {
+            0 => return Err(OtaError::LengthMismatch { expected: image_length, actual: received }),
+            n => {
+                filled += n;
+                received += n as u32;
+            }
+        }
+    }
+
+    // Read back what was written to make sure it matches what the server sent
+    let mut crc = 0;
+    let mut offset = 0;
+    while offset < image_length {
+        let n = (image_length - offset).min(SECTOR_SIZE as u32) as usize;
+        flash.read(partition_offset + offset, &mut sector)?;
+        crc = crc32_le(crc, &sector[..n]);
+        offset += n as u32;
+
+        embassy_futures::yield_now().await;
+    }
+
+    if crc != expected_crc {
+        return Err(OtaError::ChecksumMismatch { expected: expected_crc, actual: crc });
+    }
+
+    // Switch the boot partition by writing a newer OTA data entry over the
+    // older copy, so the current entry is kept if this is interrupted
+    let target_sector = current.map_or(0, |entry| 1 - entry.sector);
+    sector.fill(0xFF);
+    sector[..4].copy_from_slice(&new_seq.to_le_bytes());
+    sector[OTADATA_STATE_INDEX..OTADATA_STATE_INDEX + 4].copy_from_slice(&OTA_STATE_UNDEFINED.to_le_bytes());
+    sector[OTADATA_CRC_INDEX..OTADATA_CRC_INDEX + 4].copy_from_slice(&crc32_le(u32::MAX, &new_seq.to_le_bytes()).to_le_bytes());
+    flash.write(OTADATA_OFFSET + (target_sector * SECTOR_SIZE) as u32, &sector)?;
+
+    Ok(true)
+}
+
+/// Reads both copies of the OTA data and returns the one the bootloader is
+/// using, which is the valid entry with the highest sequence number. Returns
+/// None if neither is valid, e.g. after the OTA data has been erased.
+fn read_otadata(flash: &mut FlashStorage) -> Result<Option<OtaEntry>, OtaError> {
+    let mut current: Option<OtaEntry> = None;
+
+    for sector in 0..2 {
+        let mut entry = [0u8; OTADATA_ENTRY_LENGTH];
+        flash.read(OTADATA_OFFSET + (sector * SECTOR_SIZE) as u32, &mut entry)?;
+
+        let seq = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        let crc = u32::from_le_bytes([
+            entry[OTADATA_CRC_INDEX],
+            entry[OTADATA_CRC_INDEX + 1],
+            entry[OTADATA_CRC_INDEX + 2],
+            entry[OTADATA_CRC_INDEX + 3],
+        ]);
+
+        if seq == u32::MAX || crc != crc32_le(u32::MAX, &entry[..4]) {
+            continue;
+        }
+
+        if current.map_or(true, |c| seq > c.seq) {
+            current = Some(OtaEntry { seq, sector });
+        }
+    }
+
+    Ok(current)
+}
+
+/// Calculates a little-endian CRC-32 of `data`, continuing from `crc`. This
+/// matches the ROM's crc32_le, so a `crc` of 0 gives the standard CRC-32 and
+/// u32::MAX gives the CRC the bootloader uses for OTA data.
+fn crc32_le(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
@@ -0,0 +1,285 @@
+use crate::tilt::{DataKind, GravityUnit, TemperatureUnit, TiltData};
+
+// The names of the fields in the posted JSON. Brewfather needs these, but a
+// webhook can expect others.
+const JSON_FIELDS: JsonFields = BREWFATHER_FIELDS;
+
+const BREWFATHER_FIELDS: JsonFields = JsonFields {
+    name: "name",
+    temperature: "temp",
+    temperature_unit: "temp_unit",
+    gravity: "gravity",
+    gravity_unit: "gravity_unit",
+    battery: "battery",
+    rssi: "rssi",
+    samples: "samples",
+    timestamp: "timestamp",
+};
+
+// The unit the temperature is posted in. The Tilt reports Fahrenheit, so
+// Celsius is converted before posting.
+pub const TEMP_UNIT: TemperatureUnit = TemperatureUnit::Fahrenheit;
+// The unit the gravity is posted in. The Tilt reports specific gravity, so
+// Plato and Brix are converted before posting.
+pub const GRAVITY_UNIT: GravityUnit = GravityUnit::SpecificGravity;
+// Post the battery as an estimated percentage remaining instead of the number
+// of weeks since it was replaced.
+pub const BATTERY_AS_PERCENT: bool = false;
+// Also post the minimum and maximum temperature and gravity seen during the scan
+// as temp_min, temp_max, gravity_min, and gravity_max
+pub const INCLUDE_MIN_MAX: bool = false;
+// Also post the specific gravity in points, e.g. 50 for 1.050, as gravity_points
+pub const INCLUDE_GRAVITY_POINTS: bool = false;
+// Also post an estimate of the Tilt's battery voltage from its age, as
+// battery_voltage. It is not measured, see `TiltData::battery_voltage_str`.
+pub const INCLUDE_BATTERY_VOLTAGE: bool = false;
+// Also post the signal strength of the relay's WiFi connection as wifi_rssi
+pub const INCLUDE_WIFI_RSSI: bool = false;
+
+/// The names of the fields in the JSON for each Tilt's data
+struct JsonFields {
+    name: &'static str,
+    temperature: &'static str,
+    temperature_unit: &'static str,
+    gravity: &'static str,
+    gravity_unit: &'static str,
+    battery: &'static str,
+    rssi: &'static str,
+    samples: &'static str,
+    timestamp: &'static str,
+}
+
+/// Formats the `tilt_data` as a JSON object in `buffer` and returns it, or an
+/// error if it doesn't fit.
+/// The `timestamp`, in seconds since the Unix epoch, is only included if the
+/// time is known.
+/// A notice doesn't include the stale temperature and gravity. For a Tilt that
+/// has gone offline it is a comment saying so, which Brewfather shows on the
+/// batch's graph. A heartbeat has the relay's `uptime_secs` and the Tilt's
+/// battery.
+/// The battery is left out if the Tilt didn't transmit it, rather than posted
+/// as 0, which would read as a dead battery.
+/// The signal strength of the relay's WiFi connection, `wifi_rssi`, is only
+/// included if INCLUDE_WIFI_RSSI is set and it is connected.
+pub fn format_json(
+    tilt_data: TiltData,
+    timestamp: Option<u64>,
+    uptime_secs: u64,
+    wifi_rssi: Option<i8>,
+    buffer: &mut [u8],
+) -> Result<&str, core::fmt::Error> {
+    use core::fmt::Write;
+
+    let mut wrapper = Wrapper::new(buffer);
+    let battery = if BATTERY_AS_PERCENT {
+        tilt_data.battery_percent()
+    } else {
+        tilt_data.battery()
+    };
+
+    if !tilt_data.is_reading() {
+        write!(wrapper, "{{ \"{}\": \"Tilt {}\"", JSON_FIELDS.name, tilt_data.color().name())?;
+
+        match tilt_data.kind() {
+            DataKind::Offline => write!(wrapper, ", \"comment\": \"Tilt is offline\"")?,
+            _ => write!(wrapper,
+                ", \"status\": \"heartbeat\", \"uptime\": {}",
+                uptime_secs,
+            )?,
+        }
+
+        if let (DataKind::Heartbeat, Some(battery)) = (tilt_data.kind(), battery) {
+            write!(wrapper, ", \"{}\": {}", JSON_FIELDS.battery, battery)?;
+        }
+
+        if let Some(timestamp) = timestamp {
+            write!(wrapper, ", \"{}\": {}", JSON_FIELDS.timestamp, timestamp)?;
+        }
+
+        write!(wrapper, " }}")?;
+
+        let len = wrapper.written();
+        return Ok(core::str::from_utf8(&buffer[..len]).unwrap());
+    }
+
+    write!(wrapper,
+        "{{ \
+        \"{}\": \"Tilt {}\", \
+        \"{}\": {}, \
+        \"{}\": \"{}\", \
+        \"{}\": {}, \
+        \"{}\": \"{}\"",
+        JSON_FIELDS.name,
+        tilt_data.color().name(),
+        JSON_FIELDS.temperature,
+        tilt_data.temperature_str_in(TEMP_UNIT, &mut [0u8; 6]),
+        JSON_FIELDS.temperature_unit,
+        TEMP_UNIT.symbol(),
+        JSON_FIELDS.gravity,
+        tilt_data.gravity_str_in(GRAVITY_UNIT, &mut [0u8; 6]),
+        JSON_FIELDS.gravity_unit,
+        GRAVITY_UNIT.symbol(),
+    )?;
+
+    if let Some(battery) = battery {
+        write!(wrapper, ", \"{}\": {}", JSON_FIELDS.battery, battery)?;
+    }
+
+    write!(wrapper,
+        ", \"{}\": {}, \"{}\": {}",
+        JSON_FIELDS.rssi,
+        tilt_data.rssi(),
+        JSON_FIELDS.samples,
+        tilt_data.n_samples(),
+    )?;
+
+    if INCLUDE_MIN_MAX {
+        let (minimum, maximum) = (tilt_data.minimum(), tilt_data.maximum());
+
+        write!(wrapper,
+            ", \"temp_min\": {}, \
+            \"temp_max\": {}, \
+            \"gravity_min\": {}, \
+            \"gravity_max\": {}",
+            minimum.temperature_str_in(TEMP_UNIT, &mut [0u8; 6]),
+            maximum.temperature_str_in(TEMP_UNIT, &mut [0u8; 6]),
+            minimum.gravity_str_in(GRAVITY_UNIT, &mut [0u8; 6]),
+            maximum.gravity_str_in(GRAVITY_UNIT, &mut [0u8; 6]),
+        )?;
+    }
+
+    if let Some(unsmoothed) = tilt_data.unsmoothed() {
+        write!(wrapper,
+            ", \"temp_raw\": {}, \"gravity_raw\": {}",
+            unsmoothed.temperature_str_in(TEMP_UNIT, &mut [0u8; 6]),
+            unsmoothed.gravity_str_in(GRAVITY_UNIT, &mut [0u8; 6]),
+        )?;
+    }
+
+    if INCLUDE_WIFI_RSSI {
+        if let Some(rssi) = wifi_rssi {
+            write!(wrapper, ", \"wifi_rssi\": {}", rssi)?;
+        }
+    }
+
+    if INCLUDE_BATTERY_VOLTAGE {
+        if let Some(voltage) = tilt_data.battery_voltage_str(&mut [0u8; 6]) {
+            write!(wrapper, ", \"battery_voltage\": {}", voltage)?;
+        }
+    }
+
+    if INCLUDE_GRAVITY_POINTS {
+        write!(wrapper, ", \"gravity_points\": {}", tilt_data.gravity_points())?;
+    }
+
+    if let Some(attenuation) = tilt_data.attenuation_str(&mut [0u8; 6]) {
+        write!(wrapper, ", \"attenuation\": {}", attenuation)?;
+    }
+
+    if let Some(timestamp) = timestamp {
+        write!(wrapper, ", \"{}\": {}", JSON_FIELDS.timestamp, timestamp)?;
+    }
+
+    write!(wrapper, " }}")?;
+
+    let len = wrapper.written();
+    Ok(core::str::from_utf8(&buffer[..len]).unwrap())
+}
+
+/// A helper that allows using the `write!` macro to write to a byte buffer.
+pub struct Wrapper<'a> {
+    buffer: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> Wrapper<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Wrapper {
+            buffer,
+            offset: 0,
+        }
+    }
+
+    /// Returns the number of bytes that have been written to the buffer.
+    pub fn written(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> core::fmt::Write for Wrapper<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let remainder = &mut self.buffer[self.offset..];
+
+        if remainder.len() < bytes.len() {
+            return Err(core::fmt::Error);
+        }
+
+        let remainder = &mut remainder[..bytes.len()];
+        remainder.copy_from_slice(bytes);
+
+        self.offset += bytes.len();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilt::TiltColor;
+
+    // These expect the default configuration above
+    fn format(tilt_data: TiltData, timestamp: Option<u64>) -> String {
+        format_json(tilt_data, timestamp, 3600, Some(-50), &mut [0u8; 512]).unwrap().to_string()
+    }
+
+    fn reading(battery: Option<u8>) -> TiltData {
+        TiltData::new(TiltColor::Red, [0; 7], 685, 10500, battery, -70)
+    }
+
+    #[test]
+    fn reading_json() {
+        assert_eq!(
+            format(reading(Some(5)), Some(1_700_000_000)),
+            "{ \"name\": \"Tilt Red\", \"temp\": 68.5, \"temp_unit\": \"F\", \"gravity\": 1.0500, \
+            \"gravity_unit\": \"G\", \"battery\": 5, \"rssi\": -70, \"samples\": 1, \"timestamp\": 1700000000 }",
+        );
+    }
+
+    #[test]
+    fn unknown_battery_is_left_out() {
+        assert_eq!(
+            format(reading(None), None),
+            "{ \"name\": \"Tilt Red\", \"temp\": 68.5, \"temp_unit\": \"F\", \"gravity\": 1.0500, \
+            \"gravity_unit\": \"G\", \"rssi\": -70, \"samples\": 1 }",
+        );
+    }
+
+    #[test]
+    fn gravity_below_water_json() {
+        let tilt_data = TiltData::new(TiltColor::Red, [0; 7], 685, 9980, None, -70);
+        assert!(format(tilt_data, None).contains("\"gravity\": 0.9980,"));
+    }
+
+    #[test]
+    fn notice_json() {
+        assert_eq!(
+            format(reading(Some(5)).as_notice(DataKind::Offline), None),
+            "{ \"name\": \"Tilt Red\", \"comment\": \"Tilt is offline\" }",
+        );
+        assert_eq!(
+            format(reading(Some(5)).as_notice(DataKind::Heartbeat), None),
+            "{ \"name\": \"Tilt Red\", \"status\": \"heartbeat\", \"uptime\": 3600, \"battery\": 5 }",
+        );
+        assert_eq!(
+            format(reading(None).as_notice(DataKind::Heartbeat), None),
+            "{ \"name\": \"Tilt Red\", \"status\": \"heartbeat\", \"uptime\": 3600 }",
+        );
+    }
+
+    #[test]
+    fn too_long_is_an_error() {
+        assert!(format_json(reading(Some(5)), None, 0, None, &mut [0u8; 32]).is_err());
+    }
+}
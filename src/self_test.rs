@@ -0,0 +1,75 @@
+use core::cell::Cell;
+
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use esp_wifi::wifi::WifiDevice;
+use log::{error, info};
+
+use crate::wifi::sleep_ms;
+
+// Check the radios at startup, e.g. after flashing a new board: reset the
+// Bluetooth controller and scan for any advertisers, then connect to WiFi and
+// look up the endpoint with DNS. A PASS/FAIL summary is logged at the end.
+pub const SELF_TEST: bool = false;
+// Stop after the self test instead of continuing to normal operation
+pub const HALT_AFTER_SELF_TEST: bool = true;
+// How long to scan for advertisers. Any Bluetooth device counts, not just Tilts.
+pub const SELF_TEST_SCAN_DURATION: Duration = Duration::from_secs(10);
+// How long to wait for WiFi to connect and get an IP address
+const SELF_TEST_WIFI_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The Bluetooth part's result, the number of advertisers seen or None if the
+/// controller failed. It runs before the executor starts, so it is left here
+/// for `self_test_task` to report.
+static BLUETOOTH_RESULT: Mutex<CriticalSectionRawMutex, Cell<Option<usize>>> = Mutex::new(Cell::new(None));
+
+/// Records the result of the Bluetooth part of the self test.
+pub fn record_bluetooth_result(n_advertisers: Option<usize>) {
+    BLUETOOTH_RESULT.lock(|result| result.set(n_advertisers));
+}
+
+/// Runs the WiFi part of the self test once the network stack is up, then logs
+/// the summary of both parts.
+#[embassy_executor::task]
+pub async fn self_test_task(stack: &'static Stack<WifiDevice<'static>>) {
+    let wifi_end_time = Instant::now() + SELF_TEST_WIFI_TIMEOUT;
+    while !(stack.is_link_up() && stack.config().is_some()) && Instant::now() < wifi_end_time {
+        sleep_ms(500).await;
+    }
+
+    let wifi_passed = stack.config().is_some();
+    let endpoint_ip = if wifi_passed {
+        crate::wifi::resolve_endpoint(stack).await
+    } else {
+        None
+    };
+
+    let bluetooth_result = BLUETOOTH_RESULT.lock(|result| result.get());
+    let passed = bluetooth_result.is_some() && wifi_passed && endpoint_ip.is_some();
+
+    info!("Self test summary:");
+    match bluetooth_result {
+        Some(n_advertisers) => info!("  Bluetooth: PASS ({} advertisers)", n_advertisers),
+        None => error!("  Bluetooth: FAIL"),
+    }
+    match stack.config() {
+        Some(config) => info!("  WiFi: PASS ({})", config.address),
+        None => error!("  WiFi: FAIL (no IP address after {}s)", SELF_TEST_WIFI_TIMEOUT.as_secs()),
+    }
+    match endpoint_ip {
+        Some(ip) => info!("  DNS: PASS ({})", ip),
+        None => error!("  DNS: FAIL"),
+    }
+
+    if passed {
+        info!("Self test: PASS");
+    } else {
+        error!("Self test: FAIL");
+    }
+
+    if HALT_AFTER_SELF_TEST {
+        info!("Halted after the self test");
+    }
+}
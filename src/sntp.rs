@@ -0,0 +1,116 @@
+use core::cell::Cell;
+
+use embassy_net::udp::UdpSocket;
+use embassy_net::{PacketMetadata, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{with_timeout, Duration, Instant};
+use esp_wifi::wifi::WifiDevice;
+use log::{info, warn};
+use smoltcp::wire::DnsQueryType;
+
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+// The local port the query is sent from
+const LOCAL_PORT: u16 = 12300;
+// How long to wait for the server to respond before giving up
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+// The clock drifts, so sync it again after this long
+const SYNC_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const NTP_PACKET_LENGTH: usize = 48;
+/// Leap indicator 0, version 3, mode 3 (client)
+const NTP_CLIENT_HEADER: u8 = 0x1B;
+/// The offset of the server's transmit timestamp in the response
+const NTP_TRANSMIT_TIMESTAMP_START: usize = 40;
+/// NTP time starts in 1900, Unix time starts in 1970
+const NTP_TO_UNIX_SECS: u64 = 2_208_988_800;
+
+/// The Unix time in milliseconds when the device booted, i.e. at Instant 0,
+/// along with when it was last synced. None until the first successful sync.
+static BOOT_TIME: Mutex<CriticalSectionRawMutex, Cell<Option<(u64, Instant)>>> = Mutex::new(Cell::new(None));
+
+#[derive(Debug)]
+enum SntpError {
+    Dns(embassy_net::dns::Error),
+    NoAddress,
+    Bind(embassy_net::udp::BindError),
+    Udp(embassy_net::udp::Error),
+    Timeout,
+    InvalidResponse,
+}
+
+/// Returns the Unix time in seconds at `instant`, or None if the time has
+/// never been synced.
+pub fn unix_time(instant: Instant) -> Option<u64> {
+    let (boot_time_ms, _) = BOOT_TIME.lock(|t| t.get())?;
+
+    Some((boot_time_ms + instant.as_millis()) / 1000)
+}
+
+/// Syncs the time with the NTP server if it has never been synced or if the
+/// last sync was more than SYNC_INTERVAL ago. Failure is logged, and the
+/// previously synced time (if any) continues to be used.
+pub async fn sync_if_stale(stack: &'static Stack<WifiDevice<'static>>) {
+    let stale = match BOOT_TIME.lock(|t| t.get()) {
+        Some((_, synced_at)) => Instant::now() - synced_at > SYNC_INTERVAL,
+        None => true,
+    };
+
+    if !stale {
+        return;
+    }
+
+    match sync(stack).await {
+        Ok(boot_time_ms) => info!("Synced time, booted at {} ms since the Unix epoch", boot_time_ms),
+        Err(e) => warn!("Failed to sync time: {:?}", e),
+    }
+}
+
+/// Queries the NTP server for the current time and records the boot time.
+async fn sync(stack: &'static Stack<WifiDevice<'static>>) -> Result<u64, SntpError> {
+    let addresses = stack.dns_query(NTP_SERVER, DnsQueryType::A).await.map_err(SntpError::Dns)?;
+    let server = *addresses.first().ok_or(SntpError::NoAddress)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; NTP_PACKET_LENGTH];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; NTP_PACKET_LENGTH];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    socket.bind(LOCAL_PORT).map_err(SntpError::Bind)?;
+
+    let mut packet = [0u8; NTP_PACKET_LENGTH];
+    packet[0] = NTP_CLIENT_HEADER;
+
+    let sent_at = Instant::now();
+    socket.send_to(&packet, (server, NTP_PORT)).await.map_err(SntpError::Udp)?;
+
+    let (len, _) = with_timeout(NTP_TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|_| SntpError::Timeout)?
+        .map_err(SntpError::Udp)?;
+    let received_at = Instant::now();
+
+    if len < NTP_PACKET_LENGTH {
+        return Err(SntpError::InvalidResponse);
+    }
+
+    // The transmit timestamp is 32 bits of seconds followed by 32 bits of
+    // fractional seconds, both big endian
+    let timestamp = &packet[NTP_TRANSMIT_TIMESTAMP_START..];
+    let secs = u32::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]) as u64;
+    let fraction = u32::from_be_bytes([timestamp[4], timestamp[5], timestamp[6], timestamp[7]]) as u64;
+
+    if secs < NTP_TO_UNIX_SECS {
+        return Err(SntpError::InvalidResponse);
+    }
+
+    // Assume the server sent the response halfway through the round trip
+    let unix_time_ms = (secs - NTP_TO_UNIX_SECS) * 1000 + ((fraction * 1000) >> 32);
+    let round_trip = received_at - sent_at;
+    let boot_time_ms = unix_time_ms + round_trip.as_millis() / 2 - received_at.as_millis();
+
+    BOOT_TIME.lock(|t| t.set(Some((boot_time_ms, received_at))));
+
+    Ok(boot_time_ms)
+}
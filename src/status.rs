@@ -0,0 +1,127 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::tilt::{TiltColor, TiltReadings};
+
+/// The relay's latest readings and how posting them has gone, for the status
+/// page and metrics served by http_server.
+#[derive(Clone, Copy)]
+pub struct Status {
+    /// The latest reading from each Tilt, which may be from different scans
+    pub readings: TiltReadings,
+    /// Whether each Tilt's reading is from before the relay last reset, kept in
+    /// RTC memory, rather than from a scan since
+    pub stale: [bool; TiltColor::COUNT],
+    /// When the latest scan with data finished
+    pub last_scan: Option<Instant>,
+    /// When the latest post finished, and whether it succeeded
+    pub last_post: Option<(Instant, bool)>,
+    pub n_posts: u32,
+    pub n_failed_posts: u32,
+    pub n_scans: u32,
+    /// The access point the relay is connected to, as of the latest publish
+    pub wifi: Option<WifiInfo>,
+    /// The number of boots since power on, including this one
+    pub boot_count: u32,
+    /// Why the relay last reset, e.g. "power on" or "TIMG0 watchdog"
+    pub reset_reason: &'static str,
+}
+
+/// Diagnostics for the WiFi connection.
+#[derive(Clone, Copy)]
+pub struct WifiInfo {
+    /// The signal strength of the access point in dBm
+    pub rssi: i8,
+    /// The MAC address of the access point, to tell which one it is in a mesh
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<Status>> = Mutex::new(Cell::new(Status {
+    readings: [None; TiltColor::COUNT],
+    stale: [false; TiltColor::COUNT],
+    last_scan: None,
+    last_post: None,
+    n_posts: 0,
+    n_failed_posts: 0,
+    n_scans: 0,
+    wifi: None,
+    boot_count: 0,
+    reset_reason: "unknown",
+}));
+
+/// Returns a copy of the current status.
+pub fn get() -> Status {
+    STATUS.lock(|status| status.get())
+}
+
+/// Records the number of boots since power on and the reason for the last
+/// reset.
+pub fn record_boot(boot_count: u32, reset_reason: &'static str) {
+    update(|status| {
+        status.boot_count = boot_count;
+        status.reset_reason = reset_reason;
+    });
+}
+
+/// Records the `readings` retained from before the relay reset, so there is
+/// something to show until the first scan. They are marked as stale.
+pub fn record_retained_readings(readings: &TiltReadings) {
+    update(|status| {
+        status.readings = *readings;
+        status.stale = readings.map(|reading| reading.is_some());
+    });
+}
+
+/// Records that a scan finished, whether or not it found any Tilts.
+pub fn record_scan() {
+    update(|status| status.n_scans += 1);
+}
+
+/// Records the `readings` from a scan that finished at `scan_time`. Tilts
+/// without a reading keep their previous one.
+pub fn record_readings(scan_time: Instant, readings: &TiltReadings) {
+    update(|status| {
+        for ((latest, stale), reading) in status.readings.iter_mut()
+            .zip(status.stale.iter_mut())
+            .zip(readings.iter())
+        {
+            if reading.map_or(false, |tilt_data| tilt_data.is_reading()) {
+                *latest = *reading;
+                *stale = false;
+            }
+        }
+
+        status.last_scan = Some(scan_time);
+    });
+}
+
+/// Records the latest diagnostics for the WiFi connection, or None if it is
+/// not connected.
+pub fn record_wifi(wifi: Option<WifiInfo>) {
+    update(|status| status.wifi = wifi);
+}
+
+/// Records whether posting a datapoint succeeded, after all retries.
+pub fn record_post(success: bool) {
+    update(|status| {
+        status.last_post = Some((Instant::now(), success));
+
+        if success {
+            status.n_posts += 1;
+        } else {
+            status.n_failed_posts += 1;
+        }
+    });
+}
+
+fn update(f: impl FnOnce(&mut Status)) {
+    STATUS.lock(|cell| {
+        let mut status = cell.get();
+        f(&mut status);
+        cell.set(status);
+    });
+}
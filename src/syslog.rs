@@ -0,0 +1,97 @@
+use embassy_net::udp::UdpSocket;
+use embassy_net::{IpAddress, PacketMetadata, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use esp_wifi::wifi::WifiDevice;
+use heapless::String;
+use log::Level;
+
+use crate::payload::Wrapper;
+use crate::wifi::sleep_ms;
+
+// Send the log to a syslog collector over UDP, for when the relay is somewhere
+// its serial output can't be seen. The log is still printed to serial as well.
+// Lines logged before WiFi is up are sent once it is, as long as they fit in
+// the queue.
+pub const SYSLOG_ENABLED: bool = false;
+const SYSLOG_COLLECTOR: (IpAddress, u16) = (IpAddress::v4(192, 168, 0, 100), 514);
+// The local port the messages are sent from
+const LOCAL_PORT: u16 = 51400;
+// Identifies the relay in the collector's log
+const SYSLOG_HOSTNAME: &str = "tilt-relay";
+const SYSLOG_APP_NAME: &str = "tilt-relay";
+// How many lines can wait to be sent. Lines are dropped while it is full, so
+// logging never waits on the network.
+const QUEUE_SIZE: usize = 16;
+// Longer messages are truncated
+const MAX_MESSAGE_LENGTH: usize = 192;
+
+/// The syslog facility "local use 0"
+const FACILITY_LOCAL0: u8 = 16;
+/// The longest header `syslog_task` writes before the message
+const MAX_HEADER_LENGTH: usize = 64;
+
+static QUEUE: Channel<CriticalSectionRawMutex, (Level, String<MAX_MESSAGE_LENGTH>), QUEUE_SIZE> = Channel::new();
+
+/// Queues the log `record` to be sent to the collector. This never waits, so it
+/// is safe to call from the logger. The record is dropped if the queue is full.
+pub fn queue(record: &log::Record) {
+    use core::fmt::Write;
+
+    // A message that is too long keeps whatever fit
+    let mut message = String::new();
+    write!(message, "{}: {}", record.target(), record.args()).ok();
+
+    QUEUE.try_send((record.level(), message)).ok();
+}
+
+#[embassy_executor::task]
+pub async fn syslog_task(stack: &'static Stack<WifiDevice<'static>>) {
+    use core::fmt::Write;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 4 * (MAX_HEADER_LENGTH + MAX_MESSAGE_LENGTH)];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        panic!("Failed to bind syslog socket: {:?}", e);
+    }
+
+    // Nothing can be sent without an IP address
+    while stack.config().is_none() {
+        sleep_ms(1000).await;
+    }
+
+    loop {
+        let (level, message) = QUEUE.receive().await;
+
+        // RFC 5424, without a timestamp or structured data
+        let mut packet = [0u8; MAX_HEADER_LENGTH + MAX_MESSAGE_LENGTH];
+        let mut wrapper = Wrapper::new(&mut packet);
+        write!(wrapper,
+            "<{}>1 - {} {} - - - {}",
+            FACILITY_LOCAL0 * 8 + severity(level),
+            SYSLOG_HOSTNAME,
+            SYSLOG_APP_NAME,
+            message,
+        ).unwrap();
+        let len = wrapper.written();
+
+        // Failures aren't logged, since that would queue another message that
+        // would likely fail too. The message is just lost, e.g. while WiFi is
+        // down.
+        socket.send_to(&packet[..len], SYSLOG_COLLECTOR).await.ok();
+    }
+}
+
+/// Returns the syslog severity of a log `level`.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
@@ -1,19 +1,96 @@
-use log::info;
+use log::{info, warn};
 
 const TEMPERATURE_DECIMAL_PLACES: usize = 1;
 const GRAVITY_DECIMAL_PLACES: usize = 4;
+const PLATO_DECIMAL_PLACES: usize = 2;
+const BRIX_DECIMAL_PLACES: usize = 2;
+const ATTENUATION_DECIMAL_PLACES: usize = 1;
+// val_to_str needs at least one digit before the decimal point
+const _: () = assert!(TEMPERATURE_DECIMAL_PLACES >= 1 && TEMPERATURE_DECIMAL_PLACES <= 4);
+const _: () = assert!(GRAVITY_DECIMAL_PLACES >= 1 && GRAVITY_DECIMAL_PLACES <= 4);
+const _: () = assert!(PLATO_DECIMAL_PLACES >= 1 && PLATO_DECIMAL_PLACES <= 4);
+const _: () = assert!(BRIX_DECIMAL_PLACES >= 1 && BRIX_DECIMAL_PLACES <= 4);
+const _: () = assert!(ATTENUATION_DECIMAL_PLACES >= 1 && ATTENUATION_DECIMAL_PLACES <= 4);
+// These may be negative, which signed_val_to_str needs room for
+const _: () = assert!(TEMPERATURE_DECIMAL_PLACES < 4 && PLATO_DECIMAL_PLACES < 4 && BRIX_DECIMAL_PLACES < 4);
+const _: () = assert!(ATTENUATION_DECIMAL_PLACES < 4);
 
-/// The start of the Tilt's BLE advertising packet. The data is always the same.
-const PACKET_PRE_ADDRESS: [u8; 6] = [
+/// The standard Tilt transmits the temperature in whole degrees and the gravity
+/// to three decimal places, while the Tilt Pro transmits both with one more
+/// decimal place, which is what the decimal places above are for. A standard
+/// Tilt's values are scaled up to match when they are parsed, so both models
+/// are handled the same way from then on.
+const STANDARD_TEMPERATURE_DECIMAL_PLACES: usize = 0;
+const STANDARD_GRAVITY_DECIMAL_PLACES: usize = 3;
+/// What a standard Tilt's values are multiplied by to match. The subtractions
+/// fail to compile if a standard Tilt would have more decimal places.
+const STANDARD_TEMPERATURE_SCALE: u16 =
+    10u16.pow((TEMPERATURE_DECIMAL_PLACES - STANDARD_TEMPERATURE_DECIMAL_PLACES) as u32);
+const STANDARD_GRAVITY_SCALE: u16 = 10u16.pow((GRAVITY_DECIMAL_PLACES - STANDARD_GRAVITY_DECIMAL_PLACES) as u32);
+/// Both models use the same UUIDs, so they are told apart by the gravity. A
+/// standard Tilt's is around 1000 and a Tilt Pro's is around 10000, so a raw
+/// gravity at least this high is from a Tilt Pro.
+const MIN_PRO_RAW_GRAVITY: u16 = 5000;
+
+/// Corrects the gravity reported by the Tilt, e.g. if it doesn't read 1.000 in
+/// plain water. This is in the scaled units of the gravity, so 20 is 0.0020.
+/// The corrected gravity saturates at 0 rather than wrapping.
+const GRAVITY_CALIBRATION_OFFSET: i16 = 0;
+/// Corrects the temperature reported by the Tilt. This is in the scaled units
+/// of the temperature, so -15 is -1.5°F. The corrected temperature saturates at
+/// 0 rather than wrapping.
+const TEMPERATURE_CALIBRATION_OFFSET: i16 = 0;
+
+/// The Tilt manufacturer recommends replacing the battery every 52 weeks.
+const BATTERY_LIFE_WEEKS: u8 = 52;
+/// The voltage of a fresh coin cell, in millivolts, for the estimate in
+/// `battery_voltage_str`.
+const BATTERY_FRESH_MILLIVOLTS: u16 = 3000;
+/// The voltage the battery is assumed to have dropped to after
+/// BATTERY_LIFE_WEEKS, in millivolts. It stays there after that.
+const BATTERY_CUTOFF_MILLIVOLTS: u16 = 2500;
+const _: () = assert!(BATTERY_CUTOFF_MILLIVOLTS <= BATTERY_FRESH_MILLIVOLTS);
+
+/// The length of a TiltData serialized by `TiltData::to_bytes`: the color, the
+/// address, the temperature, the gravity, the battery and the RSSI.
+pub const SERIALIZED_LENGTH: usize = 1 + PACKET_ADDRESS_LENGTH + 2 + 2 + 1 + 1;
+/// The battery is serialized as this when it wasn't transmitted
+const SERIALIZED_NO_BATTERY: u8 = 0xFF;
+// The offsets in to_bytes and from_bytes assume this layout
+const _: () = assert!(SERIALIZED_LENGTH == 14);
+
+/// The specific gravity of water, 1.000, scaled the same way as the gravity
+/// transmitted by the Tilt.
+pub const GRAVITY_OF_WATER: u16 = 10u16.pow(GRAVITY_DECIMAL_PLACES as u32);
+
+/// The freezing point of water in Fahrenheit, scaled the same way as the
+/// temperature transmitted by the Tilt.
+const FREEZING_POINT_F: i32 = 32 * 10i32.pow(TEMPERATURE_DECIMAL_PLACES as u32);
+
+/// The start of every LE Meta Event packet
+const EVENT_HEADER: [u8; 2] = [
     0x04, // Packet type: Event
     0x3E, // LE Meta Event
-    0x2A, // Length of event parameters
-    0x02, // Subevent type "LE Advertising Report"
-    0x01, // Number of reports in event
-    0x03, // Event type "Non connectable undirected advertising"
 ];
+/// The length of the event parameters, which is everything after this byte
+const EVENT_PARAMS_LENGTH_INDEX: usize = EVENT_HEADER.len();
+const SUBEVENT_INDEX: usize = EVENT_PARAMS_LENGTH_INDEX + 1;
+/// Subevent type "LE Advertising Report"
+const SUBEVENT_ADVERTISING_REPORT: u8 = 0x02;
+/// The controller can batch several advertising reports into one event. Each
+/// report is an event type, an address, the length of the data, the data, then
+/// the RSSI.
+const NUM_REPORTS_INDEX: usize = SUBEVENT_INDEX + 1;
+const REPORTS_START: usize = NUM_REPORTS_INDEX + 1;
 
-/// The rest of the fixed part of the packet after the address. 
+/// The event type of a report. This is "Non connectable undirected
+/// advertising" for the Tilt's broadcasts, but active scanning can also get the
+/// data in a "Scan Response".
+const EVENT_TYPE_INDEX: usize = 0;
+const EVENT_TYPE_ADV_NONCONN_IND: u8 = 0x03;
+const EVENT_TYPE_SCAN_RSP: u8 = 0x04;
+
+/// The rest of the fixed part of the report after the address. 
 /// This precedes the sensor data.
 const PACKET_POST_ADDRESS: [u8; 10] = [
     0x1E, // Length of data in report
@@ -31,35 +108,416 @@ const PACKET_POST_ADDRESS: [u8; 10] = [
 /// The length of the BLE address in the packet. This includes 1 byte for the
 /// address type followed by 6 bytes for the address.
 const PACKET_ADDRESS_LENGTH: usize = 7;
-const ADDRESS_START: usize = PACKET_PRE_ADDRESS.len();
+/// The BLE address of a Tilt as it appears in the packet, including the
+/// address type prefix byte.
+pub type TiltAddress = [u8; PACKET_ADDRESS_LENGTH];
+const ADDRESS_START: usize = EVENT_TYPE_INDEX + 1;
 const POST_ADDRESS_START: usize = ADDRESS_START + PACKET_ADDRESS_LENGTH;
+/// The length of the report's data follows the address
+const REPORT_DATA_LENGTH_INDEX: usize = POST_ADDRESS_START;
 const PACKET_DATA_START: usize = POST_ADDRESS_START + PACKET_POST_ADDRESS.len();
 const UUID_LENGTH: usize = 16;
+/// The length of a Tilt's advertising report, including the trailing RSSI
 const PACKET_LENGTH: usize = PACKET_DATA_START + UUID_LENGTH + 2 + 2 + 1 + 1;
+/// The length of the manufacturer specific data in the report
+const MANUFACTURER_DATA_LENGTH_INDEX: usize = POST_ADDRESS_START + 4;
+/// The length of the iBeacon data, which ends PACKET_POST_ADDRESS
+const IBEACON_LENGTH_INDEX: usize = PACKET_DATA_START - 1;
+/// Each of these bytes in a report declares the length of everything after it,
+/// up to the trailing RSSI
+const NESTED_LENGTH_INDICES: [usize; 3] = [
+    REPORT_DATA_LENGTH_INDEX,
+    MANUFACTURER_DATA_LENGTH_INDEX,
+    IBEACON_LENGTH_INDEX,
+];
+const _: () = assert!(PACKET_POST_ADDRESS[REPORT_DATA_LENGTH_INDEX - POST_ADDRESS_START] as usize
+    == PACKET_LENGTH - REPORT_DATA_LENGTH_INDEX - 2);
+const _: () = assert!(PACKET_POST_ADDRESS[MANUFACTURER_DATA_LENGTH_INDEX - POST_ADDRESS_START] as usize
+    == PACKET_LENGTH - MANUFACTURER_DATA_LENGTH_INDEX - 2);
+const _: () = assert!(PACKET_POST_ADDRESS[IBEACON_LENGTH_INDEX - POST_ADDRESS_START] as usize
+    == PACKET_LENGTH - IBEACON_LENGTH_INDEX - 2);
+
+/// Every Tilt UUID is the same except for the 4th byte, which identifies the
+/// color of the Tilt. The color byte is 0x00 here.
+const TILT_UUID: [u8; UUID_LENGTH] = [
+    0xA4, 0x95, 0xBB, 0x00, 0xC5, 0xB1, 0x4B, 0x44,
+    0xB5, 0x12, 0x13, 0x70, 0xF0, 0x2D, 0x74, 0xDE,
+];
+const TILT_UUID_COLOR_INDEX: usize = 3;
+
+/// The number of raw samples kept per Tilt for computing the median. This bounds
+/// the memory and the time to sort them however chatty a Tilt is or however
+/// long the scan is. Once there are more, a random subset of this many is kept.
+const MAX_SAMPLES: usize = 64;
+/// Seeds the generator that picks which samples to keep. It only needs to be
+/// uncorrelated with the samples, so it doesn't need to differ between boots.
+const SAMPLE_RNG_SEED: u32 = 0x2545_F491;
+
+/// Gravity samples further than this from the running average are discarded as
+/// outliers. This is in the scaled units of the gravity, so 100 is 0.0100.
+const OUTLIER_GRAVITY_THRESHOLD: u16 = 100;
+/// Outliers are only discarded once this many samples have been added, so that
+/// the running average is stable enough to compare against.
+const OUTLIER_MIN_SAMPLES: u32 = 5;
+
+/// Samples outside these bounds can't be real and are discarded, e.g. a
+/// gravity of 0 from a corrupt broadcast. Both bounds are inclusive and in the
+/// scaled units, so the gravity is 0.900 to 1.200 and the temperature is 32°F
+/// to 212°F.
+const MIN_VALID_GRAVITY: u16 = GRAVITY_OF_WATER / 10 * 9;
+const MAX_VALID_GRAVITY: u16 = GRAVITY_OF_WATER / 10 * 12;
+const MIN_VALID_TEMPERATURE: u16 = FREEZING_POINT_F as u16;
+const MAX_VALID_TEMPERATURE: u16 = 212 * 10u16.pow(TEMPERATURE_DECIMAL_PLACES as u32);
+// A valid gravity below 1.000 is still reported, and formats with a single
+// leading zero, e.g. "0.9980", rather than being trimmed or rounded up. Every
+// valid gravity fits the buffer of gravity_str.
+const _: () = assert!(MIN_VALID_GRAVITY < GRAVITY_OF_WATER);
+const _: () = assert!(FixedDecimal::new(MIN_VALID_GRAVITY, GRAVITY_DECIMAL_PLACES as u8).formatted_len()
+    == GRAVITY_DECIMAL_PLACES + 2);
+const _: () = assert!(FixedDecimal::new(GRAVITY_OF_WATER - 1, GRAVITY_DECIMAL_PLACES as u8).formatted_len()
+    == GRAVITY_DECIMAL_PLACES + 2);
+const _: () = assert!(FixedDecimal::new(MAX_VALID_GRAVITY, GRAVITY_DECIMAL_PLACES as u8).formatted_len() <= 6);
+
+/// The color of a Tilt. Each color broadcasts a different iBeacon UUID, which
+/// allows several Tilts to be used at once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TiltColor {
+    Red,
+    Green,
+    Black,
+    Purple,
+    Orange,
+    Blue,
+    Yellow,
+    Pink,
+}
+
+impl TiltColor {
+    /// The number of Tilt colors.
+    pub const COUNT: usize = 8;
+
+    /// Returns the color of the Tilt that broadcasts `uuid`, or None if the
+    /// UUID does not belong to a Tilt.
+    pub fn from_uuid(uuid: &[u8; UUID_LENGTH]) -> Option<TiltColor> {
+        // Compare everything except the color byte
+        let prefix = TILT_UUID_COLOR_INDEX;
+        if uuid[..prefix] != TILT_UUID[..prefix] || uuid[(prefix + 1)..] != TILT_UUID[(prefix + 1)..] {
+            return None;
+        }
+
+        match uuid[TILT_UUID_COLOR_INDEX] {
+            0x10 => Some(TiltColor::Red),
+            0x20 => Some(TiltColor::Green),
+            0x30 => Some(TiltColor::Black),
+            0x40 => Some(TiltColor::Purple),
+            0x50 => Some(TiltColor::Orange),
+            0x60 => Some(TiltColor::Blue),
+            0x70 => Some(TiltColor::Yellow),
+            0x80 => Some(TiltColor::Pink),
+            _ => None,
+        }
+    }
+
+    /// Returns a unique index for the color in the range [0, COUNT).
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Returns the color with the given `index`, the inverse of `index`, or
+    /// None if it is out of range.
+    pub fn from_index(index: usize) -> Option<TiltColor> {
+        match index {
+            0 => Some(TiltColor::Red),
+            1 => Some(TiltColor::Green),
+            2 => Some(TiltColor::Black),
+            3 => Some(TiltColor::Purple),
+            4 => Some(TiltColor::Orange),
+            5 => Some(TiltColor::Blue),
+            6 => Some(TiltColor::Yellow),
+            7 => Some(TiltColor::Pink),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the color.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TiltColor::Red => "Red",
+            TiltColor::Green => "Green",
+            TiltColor::Black => "Black",
+            TiltColor::Purple => "Purple",
+            TiltColor::Orange => "Orange",
+            TiltColor::Blue => "Blue",
+            TiltColor::Yellow => "Yellow",
+            TiltColor::Pink => "Pink",
+        }
+    }
+}
+
+/// The unit used when reporting the temperature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TemperatureUnit {
+    Fahrenheit,
+    Celsius,
+}
+
+impl TemperatureUnit {
+    /// Returns the single letter abbreviation for the unit.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Fahrenheit => "F",
+            TemperatureUnit::Celsius => "C",
+        }
+    }
+}
+
+/// The unit used when reporting the gravity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GravityUnit {
+    SpecificGravity,
+    Plato,
+    Brix,
+}
+
+impl GravityUnit {
+    /// Returns the abbreviation for the unit that Brewfather expects.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            GravityUnit::SpecificGravity => "G",
+            GravityUnit::Plato => "P",
+            GravityUnit::Brix => "BRIX",
+        }
+    }
+}
 
 /// The sensor data transmitted by the Tilt.
 #[derive(Copy, Clone, Debug)]
 pub struct TiltData {
+    color: TiltColor,
+    address: TiltAddress,
     temperature: u16,
     gravity: u16,
     battery: Option<u8>,
+    rssi: i8,
+    // The range of the readings this was aggregated from. These are the same
+    // as the temperature and gravity for a single reading.
+    min_temperature: u16,
+    max_temperature: u16,
+    min_gravity: u16,
+    max_gravity: u16,
+    // How many readings this was aggregated from
+    n_samples: u32,
+    // The gravity at the start of fermentation, if it is known
+    original_gravity: Option<u16>,
+    // The temperature and gravity before they were smoothed, if they were
+    unsmoothed: Option<(u16, u16)>,
+    kind: DataKind,
+}
+
+/// What a TiltData represents. Only readings have fresh data, the others are
+/// notices about the Tilt that carry its last reading.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataKind {
+    Reading,
+    /// The Tilt has stopped broadcasting
+    Offline,
+    /// There was no data from the Tilt this cycle, but the relay is alive
+    Heartbeat,
 }
 
 impl TiltData {
-    pub fn new(temperature: u16, gravity: u16, battery: Option<u8>) -> Self {
+    pub fn new(
+        color: TiltColor,
+        address: TiltAddress,
+        temperature: u16,
+        gravity: u16,
+        battery: Option<u8>,
+        rssi: i8,
+    ) -> Self {
         Self {
+            color,
+            address,
             temperature,
             gravity,
             battery,
+            rssi,
+            min_temperature: temperature,
+            max_temperature: temperature,
+            min_gravity: gravity,
+            max_gravity: gravity,
+            n_samples: 1,
+            original_gravity: None,
+            unsmoothed: None,
+            kind: DataKind::Reading,
         }
     }
 
+    /// Returns a copy of this data as a notice of the given `kind`. The
+    /// readings in it are stale, so they shouldn't be posted as if they were
+    /// new.
+    pub fn as_notice(mut self, kind: DataKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Returns what this data represents.
+    pub fn kind(&self) -> DataKind {
+        self.kind
+    }
+
+    /// Returns whether this is a fresh reading rather than a notice.
+    pub fn is_reading(&self) -> bool {
+        self.kind == DataKind::Reading
+    }
+
+    /// Returns a copy of this data with the `original_gravity` of the batch,
+    /// scaled the same as the gravity, which is needed for `attenuation_str`.
+    pub fn with_original_gravity(mut self, original_gravity: u16) -> Self {
+        self.original_gravity = Some(original_gravity);
+        self
+    }
+
+    /// Returns a copy of this data with the smoothed `temperature` and
+    /// `gravity` in place of its own, which are kept for `unsmoothed`.
+    pub fn with_smoothed(mut self, temperature: u16, gravity: u16) -> Self {
+        self.unsmoothed = Some(self.unsmoothed.unwrap_or((self.temperature, self.gravity)));
+        self.temperature = temperature;
+        self.gravity = gravity;
+        self
+    }
+
+    /// Returns a copy of this data with the temperature and gravity from before
+    /// they were smoothed, so they can be formatted the same way, or None if
+    /// they weren't smoothed.
+    pub fn unsmoothed(&self) -> Option<TiltData> {
+        let (temperature, gravity) = self.unsmoothed?;
+        Some(TiltData::new(self.color, self.address, temperature, gravity, self.battery, self.rssi))
+    }
+
+    /// Serializes the reading in this data into a fixed layout, e.g. to keep it
+    /// in RTC memory. The color is stored as its index plus one, so all zeros
+    /// never deserialize. The range, samples and original gravity aren't kept.
+    pub fn to_bytes(&self) -> [u8; SERIALIZED_LENGTH] {
+        let mut bytes = [0u8; SERIALIZED_LENGTH];
+        bytes[0] = self.color.index() as u8 + 1;
+        bytes[1..8].copy_from_slice(&self.address);
+        bytes[8..10].copy_from_slice(&self.temperature.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.gravity.to_le_bytes());
+        bytes[12] = self.battery.unwrap_or(SERIALIZED_NO_BATTERY);
+        bytes[13] = self.rssi as u8;
+        bytes
+    }
+
+    /// Deserializes a reading serialized by `to_bytes`, or returns None if the
+    /// `bytes` don't hold one.
+    pub fn from_bytes(bytes: &[u8; SERIALIZED_LENGTH]) -> Option<TiltData> {
+        let color = TiltColor::from_index((bytes[0] as usize).checked_sub(1)?)?;
+        let address: TiltAddress = bytes[1..8].try_into().unwrap();
+        let temperature = u16::from_le_bytes([bytes[8], bytes[9]]);
+        let gravity = u16::from_le_bytes([bytes[10], bytes[11]]);
+        let battery = match bytes[12] {
+            SERIALIZED_NO_BATTERY => None,
+            battery => Some(battery),
+        };
+
+        Some(TiltData::new(color, address, temperature, gravity, battery, bytes[13] as i8))
+    }
+
+    /// Returns a copy of this data with the given range of temperature and
+    /// gravity readings it was aggregated from, and the number of readings.
+    fn with_aggregate(mut self, temperature: (u16, u16), gravity: (u16, u16), n_samples: u32) -> Self {
+        (self.min_temperature, self.max_temperature) = temperature;
+        (self.min_gravity, self.max_gravity) = gravity;
+        self.n_samples = n_samples;
+        self
+    }
+
+    /// Returns how many readings this data was aggregated from. Few samples
+    /// mean a noisier average, e.g. when the Tilt is far from the relay.
+    pub fn n_samples(&self) -> u32 {
+        self.n_samples
+    }
+
+    /// Returns a copy of this data with the minimum temperature and gravity it
+    /// was aggregated from, so they can be formatted the same way.
+    pub fn minimum(&self) -> TiltData {
+        TiltData::new(self.color, self.address, self.min_temperature, self.min_gravity, self.battery, self.rssi)
+    }
+
+    /// Returns a copy of this data with the maximum temperature and gravity it
+    /// was aggregated from, so they can be formatted the same way.
+    pub fn maximum(&self) -> TiltData {
+        TiltData::new(self.color, self.address, self.max_temperature, self.max_gravity, self.battery, self.rssi)
+    }
+
+    /// Returns the color of the Tilt that transmitted the data.
+    pub fn color(&self) -> TiltColor {
+        self.color
+    }
+
+    /// Returns the BLE address of the Tilt that transmitted the data.
+    /// This includes the address type prefix byte.
+    pub fn address(&self) -> &TiltAddress {
+        &self.address
+    }
+
     /// Returns the number of weeks since the Tilt's battery was replaced.
     /// Returns None if this value was not transmitted by the Tilt.
     pub fn battery(&self) -> Option<u8> {
         self.battery
     }
 
+    /// Returns an estimate of the remaining battery as a percentage, assuming
+    /// it drains linearly from 100% when replaced to 0% after
+    /// BATTERY_LIFE_WEEKS. Returns None if the battery was not transmitted.
+    pub fn battery_percent(&self) -> Option<u8> {
+        let weeks_left = BATTERY_LIFE_WEEKS - self.battery?.min(BATTERY_LIFE_WEEKS);
+        let percent = (weeks_left as u16 * 100 + BATTERY_LIFE_WEEKS as u16 / 2) / BATTERY_LIFE_WEEKS as u16;
+
+        Some(percent as u8)
+    }
+
+    /// Returns an estimate of the battery's voltage, e.g. "2.75", assuming it
+    /// drops linearly from BATTERY_FRESH_MILLIVOLTS when replaced to
+    /// BATTERY_CUTOFF_MILLIVOLTS after BATTERY_LIFE_WEEKS. The Tilt doesn't
+    /// measure its battery, so this is only as good as that assumption. Returns
+    /// None if the battery was not transmitted.
+    pub fn battery_voltage_str<'a>(&self, buffer: &'a mut [u8; 6]) -> Option<&'a str> {
+        let weeks = self.battery?.min(BATTERY_LIFE_WEEKS) as u32;
+        let drop = (BATTERY_FRESH_MILLIVOLTS - BATTERY_CUTOFF_MILLIVOLTS) as u32 * weeks / BATTERY_LIFE_WEEKS as u32;
+        let millivolts = BATTERY_FRESH_MILLIVOLTS as u32 - drop;
+
+        // Hundredths of a volt are plenty for an estimate
+        Some(val_to_str(((millivolts + 5) / 10) as u16, 2, buffer))
+    }
+
+    /// Returns the temperature in Fahrenheit multiplied by
+    /// 10^TEMPERATURE_DECIMAL_PLACES, e.g. 682 for 68.2°F.
+    pub fn temperature(&self) -> u16 {
+        self.temperature
+    }
+
+    /// Returns the specific gravity multiplied by 10^GRAVITY_DECIMAL_PLACES,
+    /// e.g. 10500 for 1.0500.
+    pub fn gravity(&self) -> u16 {
+        self.gravity
+    }
+
+    /// Returns the gravity in points, the thousandths above the gravity of
+    /// water, rounded to the nearest point, e.g. 50 for 1.050. Gravities below
+    /// that of water are 0.
+    pub fn gravity_points(&self) -> u16 {
+        let above_water = self.gravity.saturating_sub(GRAVITY_OF_WATER) as u32;
+        let water = GRAVITY_OF_WATER as u32;
+
+        ((above_water * 1000 + water / 2) / water) as u16
+    }
+
+    /// Returns the signal strength of the Tilt's broadcast in dBm.
+    pub fn rssi(&self) -> i8 {
+        self.rssi
+    }
+
     /// Returns the temperature as a string.
     /// The Tilt transmits the temperature as an integer representing a floating
     /// point number that has been scaled to avoid floating point imprecision.
@@ -68,124 +526,691 @@ impl TiltData {
         val_to_str(self.temperature, TEMPERATURE_DECIMAL_PLACES, buffer)
     }
 
+    /// Returns the temperature converted to Celsius as a string.
+    /// The conversion is done on the scaled integer so no floating point is
+    /// needed. The result is rounded to the nearest tenth of a degree, and may
+    /// be negative.
+    pub fn temperature_celsius_str<'a>(&self, buffer: &'a mut [u8; 6]) -> &'a str {
+        let scaled = (self.temperature as i32 - FREEZING_POINT_F) * 5;
+        let celsius = div_round(scaled as i128, 9) as i32;
+
+        signed_val_to_str(celsius, TEMPERATURE_DECIMAL_PLACES, buffer)
+    }
+
+    /// Returns the temperature in the given `unit` as a string.
+    pub fn temperature_str_in<'a>(&self, unit: TemperatureUnit, buffer: &'a mut [u8; 6]) -> &'a str {
+        match unit {
+            TemperatureUnit::Fahrenheit => self.temperature_str(buffer),
+            TemperatureUnit::Celsius => self.temperature_celsius_str(buffer),
+        }
+    }
+
     /// Returns the gravity as a string.
     /// The gravity is transmitted in a similar fashion as the temperature.
+    /// A gravity below that of water, e.g. late in a dry fermentation, keeps
+    /// its leading zero, so 9980 is "0.9980".
     pub fn gravity_str<'a>(&self, buffer: &'a mut [u8; 6]) -> &'a str {
         val_to_str(self.gravity, GRAVITY_DECIMAL_PLACES, buffer)
     }
+
+    /// Returns the gravity converted to degrees Plato as a string, using
+    /// P = -616.868 + 1111.14*SG - 630.272*SG^2 + 135.997*SG^3
+    /// The polynomial is evaluated exactly with integers, so the only error is
+    /// from rounding the result to the nearest hundredth of a degree (±0.005).
+    /// That is finer than the 0.0001 resolution of the gravity, which is about
+    /// 0.025 degrees Plato.
+    /// The result is negative for gravities below about 1.000.
+    pub fn gravity_plato_str<'a>(&self, buffer: &'a mut [u8; 6]) -> &'a str {
+        // The coefficients are scaled by 10^3 and the gravity is scaled by
+        // 10^4, so every term is scaled to 10^15.
+        let g = self.gravity as i128;
+        let scaled = -616_868 * 10i128.pow(12)
+            + 1_111_140 * g * 10i128.pow(8)
+            - 630_272 * g * g * 10i128.pow(4)
+            + 135_997 * g * g * g;
+        let plato = div_round(scaled, 10i128.pow(15 - PLATO_DECIMAL_PLACES as u32));
+
+        signed_val_to_str(plato as i32, PLATO_DECIMAL_PLACES, buffer)
+    }
+
+    /// Returns the gravity converted to degrees Brix as a string, using
+    /// B = ((182.4601*SG - 775.6821)*SG + 1262.7794)*SG - 669.5622
+    /// This is evaluated exactly with integers like `gravity_plato_str`.
+    /// Brix measures the sugar content, so this is only meaningful for
+    /// unfermented wort or must. Once fermentation starts the alcohol lowers
+    /// the gravity further than the sugar alone would, so this under-reads.
+    pub fn gravity_brix_str<'a>(&self, buffer: &'a mut [u8; 6]) -> &'a str {
+        // The coefficients are scaled by 10^4 and the gravity is scaled by
+        // 10^4, so every term is scaled to 10^16.
+        let g = self.gravity as i128;
+        let scaled = 1_824_601 * g * g * g
+            - 7_756_821 * g * g * 10i128.pow(4)
+            + 12_627_794 * g * 10i128.pow(8)
+            - 6_695_622 * 10i128.pow(12);
+        let brix = div_round(scaled, 10i128.pow(16 - BRIX_DECIMAL_PLACES as u32));
+
+        signed_val_to_str(brix as i32, BRIX_DECIMAL_PLACES, buffer)
+    }
+
+    /// Returns the apparent attenuation as a percentage string, using
+    /// (OG - gravity) / (OG - 1.000)
+    /// Returns None if the original gravity isn't known, or if it is at or
+    /// below 1.000, since there is nothing to attenuate.
+    pub fn attenuation_str<'a>(&self, buffer: &'a mut [u8; 6]) -> Option<&'a str> {
+        let original_gravity = self.original_gravity? as i128;
+        let water = GRAVITY_OF_WATER as i128;
+
+        if original_gravity <= water {
+            return None;
+        }
+
+        let scaled = (original_gravity - self.gravity as i128) * 100 * 10i128.pow(ATTENUATION_DECIMAL_PLACES as u32);
+        let attenuation = div_round(scaled, original_gravity - water);
+
+        Some(signed_val_to_str(attenuation as i32, ATTENUATION_DECIMAL_PLACES, buffer))
+    }
+
+    /// Returns the gravity in the given `unit` as a string.
+    pub fn gravity_str_in<'a>(&self, unit: GravityUnit, buffer: &'a mut [u8; 6]) -> &'a str {
+        match unit {
+            GravityUnit::SpecificGravity => self.gravity_str(buffer),
+            GravityUnit::Plato => self.gravity_plato_str(buffer),
+            GravityUnit::Brix => self.gravity_brix_str(buffer),
+        }
+    }
+}
+
+/// A number scaled to avoid floating point, like the temperature and gravity
+/// transmitted by the Tilt. It is equal to `value` / (10 ^ `decimals`), so
+/// 10500 with 4 decimals is 1.0500.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FixedDecimal {
+    pub value: u16,
+    pub decimals: u8,
+}
+
+impl FixedDecimal {
+    pub const fn new(value: u16, decimals: u8) -> Self {
+        Self { value, decimals }
+    }
+
+    /// Returns the number of characters `write_to` needs: every digit of the
+    /// value, padded with zeros to leave at least one before the decimal point,
+    /// and the point if there are any decimals.
+    pub const fn formatted_len(&self) -> usize {
+        let mut n_digits = 1;
+        let mut rest = self.value / 10;
+        while rest > 0 {
+            n_digits += 1;
+            rest /= 10;
+        }
+
+        if n_digits < self.decimals as usize + 1 {
+            n_digits = self.decimals as usize + 1;
+        }
+
+        if self.decimals > 0 {
+            n_digits + 1
+        } else {
+            n_digits
+        }
+    }
+
+    /// Writes the number to the start of `buffer` with exactly `decimals`
+    /// digits after the decimal point, e.g. "1.0500", and returns it. Returns an
+    /// error if the buffer is shorter than `formatted_len`.
+    pub fn write_to<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a str, core::fmt::Error> {
+        let len = self.formatted_len();
+        let buffer = buffer.get_mut(..len).ok_or(core::fmt::Error)?;
+        let point = len - self.decimals as usize - 1;
+        let mut value = self.value;
+
+        // Fill the buffer back to front with the base-10 digits of the value
+        for (i, b) in buffer.iter_mut().enumerate().rev() {
+            if self.decimals > 0 && i == point {
+                *b = b'.';
+            } else {
+                *b = b'0' + (value % 10) as u8;
+                value /= 10;
+            }
+        }
+
+        Ok(core::str::from_utf8(buffer).unwrap())
+    }
 }
 
 /// Converts `val` to a string, but places a decimal point such that there are
 /// `decimal_places` digits after the decimal point.
 /// The resulting value is equal to `val` / (10 ^ `decimal_places`).
-fn val_to_str(mut val: u16, decimal_places: usize, buffer: &mut [u8; 6]) -> &str {
-    buffer[buffer.len() - decimal_places - 1] = b'.';
+/// The buffer has room for all 5 digits of any u16 and the decimal point, so
+/// `decimal_places` must be at most 4.
+fn val_to_str(val: u16, decimal_places: usize, buffer: &mut [u8; 6]) -> &str {
+    FixedDecimal::new(val, decimal_places as u8).write_to(buffer).unwrap()
+}
 
-    // Fill the buffer back to front with the base-10 digits of the value
-    for b in buffer.iter_mut().rev() {
-        // Skip the decimal point
-        if *b != b'.' {
-            *b = b'0' + (val % 10) as u8;
-            val /= 10;
-        }
+/// Same as `val_to_str`, but `val` may be negative, in which case it is
+/// prefixed with a minus sign. Values are clamped to the range
+/// [-9999, 65535] so they fit in the buffer, so `decimal_places` must be less
+/// than 4 to leave room for the sign.
+fn signed_val_to_str(val: i32, decimal_places: usize, buffer: &mut [u8; 6]) -> &str {
+    assert!(decimal_places < buffer.len() - 2);
+
+    if val >= 0 {
+        return val_to_str(val.min(u16::MAX as i32) as u16, decimal_places, buffer);
+    }
+
+    // Format the digits after the sign
+    let len = FixedDecimal::new(val.unsigned_abs().min(9999) as u16, decimal_places as u8)
+        .write_to(&mut buffer[1..])
+        .unwrap()
+        .len();
+    buffer[0] = b'-';
+
+    core::str::from_utf8(&buffer[..(len + 1)]).unwrap()
+}
+
+/// Corrects the scaled `value` by the calibration `offset`, saturating at 0 and
+/// u16::MAX rather than wrapping.
+fn calibrate(value: u16, offset: i16) -> u16 {
+    value.saturating_add_signed(offset)
+}
+
+/// Divides `n` by `d`, rounding half away from zero. `d` must be positive.
+fn div_round(n: i128, d: i128) -> i128 {
+    if n >= 0 {
+        (n + d / 2) / d
+    } else {
+        -((-n + d / 2) / d)
     }
+}
 
-    let mut start = 0;
+/// The aggregated data for each Tilt color, indexed by `TiltColor::index`.
+/// A color is None if no data was received from a Tilt of that color.
+pub type TiltReadings = [Option<TiltData>; TiltColor::COUNT];
 
-    // Trim leading zeros, except the one right before the decimal (if present).
-    for b in buffer.iter() {
-        match b {
-            b'0' => start += 1,
-            b'.' => {
-                // Include the 0 leading the decimal point
-                start -= 1;
-                break;
-            },
-            _ => break,
+/// Statistics for aggregating multiple TiltDatas. Data is aggregated
+/// separately for each Tilt color.
+#[derive(Default)]
+pub struct TiltStats {
+    colors: [ColorStats; TiltColor::COUNT],
+}
+
+impl TiltStats {
+    pub fn new() -> Self {
+        Self::default()
+    }    
+    
+    /// Returns the aggregate of all added TiltData for each Tilt color.
+    /// See `ColorStats::aggregate` for how the data is aggregated.
+    pub fn aggregate(&self) -> TiltReadings {
+        self.aggregate_each(ColorStats::aggregate)
+    }
+
+    /// Returns the aggregate of all added TiltData for each Tilt color, using
+    /// the median temperature and gravity.
+    /// See `ColorStats::aggregate_median` for how the data is aggregated.
+    pub fn aggregate_median(&self) -> TiltReadings {
+        self.aggregate_each(ColorStats::aggregate_median)
+    }
+
+    /// Aggregates the stats for each color with `f`.
+    fn aggregate_each(&self, f: impl Fn(&ColorStats) -> Option<TiltData>) -> TiltReadings {
+        let mut readings = [None; TiltColor::COUNT];
+
+        for (reading, stats) in readings.iter_mut().zip(self.colors.iter()) {
+            *reading = f(stats);
         }
+
+        readings
     }
 
-    core::str::from_utf8(&buffer[start..]).unwrap()
+    /// Adds `data` so that it will be included in the aggregate value for the
+    /// color of the Tilt that transmitted it.
+    pub fn add(&mut self, data: TiltData) {
+        self.colors[data.color.index()].add(data);
+    }
 }
 
-/// Statistics for aggregating multiple TiltDatas.
+/// Statistics for aggregating multiple TiltDatas from a single Tilt color.
 #[derive(Default)]
-pub struct TiltStats {
+struct ColorStats {
+    color: Option<TiltColor>,
+    address: TiltAddress,
     // u32 for summing u16 will never overflow for our use case
     sum_temperature: u32,
     sum_gravity: u32,
+    min_temperature: u16,
+    max_temperature: u16,
+    min_gravity: u16,
+    max_gravity: u16,
     max_battery: Option<u8>,
+    sum_rssi: i32,
     n_data: u32,
+    n_rejected: u32,
+    samples: SampleBuffer,
 }
 
-impl TiltStats {
-    pub fn new() -> Self {
-        Self::default()
-    }    
-    
+impl ColorStats {
     /// Returns a TiltData whose values are the aggregate of all added TiltData.
-    /// The temperature and gravity values are averaged while the battery is the
-    /// maximum battery value of all added TiltData.
+    /// The temperature, gravity, and RSSI values are averaged while the battery
+    /// is the maximum battery value of all added TiltData. It also has the
+    /// minimum and maximum temperature and gravity.
     /// Returns None if no data has been added.
-    pub fn aggregate(&self) -> Option<TiltData> {
-        if self.n_data == 0 {
-            return None;
-        }
+    fn aggregate(&self) -> Option<TiltData> {
+        // Avoid dividing by zero. aggregate_with returns None in that case.
+        self.aggregate_with(
+            (self.sum_temperature / self.n_data.max(1)) as u16,
+            (self.sum_gravity / self.n_data.max(1)) as u16,
+        )
+    }
+
+    /// Same as `aggregate`, but the temperature and gravity values are the
+    /// median of the added TiltData instead of the average. This is more robust
+    /// to an occasional bad reading.
+    /// Only MAX_SAMPLES are kept, so if more than that are added the median is
+    /// of a random sample of them, which is spread over the whole scan. The
+    /// other values still reflect all of the added TiltData.
+    fn aggregate_median(&self) -> Option<TiltData> {
+        self.aggregate_with(
+            median(self.samples.temperatures()),
+            median(self.samples.gravities()),
+        )
+    }
+
+    /// Returns a TiltData with the given `temperature` and `gravity` and the
+    /// aggregate of the other values, or None if no data has been added.
+    fn aggregate_with(&self, temperature: u16, gravity: u16) -> Option<TiltData> {
+        let color = self.color?;
 
         Some(TiltData::new(
-            (self.sum_temperature / self.n_data) as u16,
-            (self.sum_gravity / self.n_data) as u16,
+            color,
+            self.address,
+            temperature,
+            gravity,
             self.max_battery,
+            (self.sum_rssi / self.n_data as i32) as i8,
+        ).with_aggregate(
+            (self.min_temperature, self.max_temperature),
+            (self.min_gravity, self.max_gravity),
+            self.n_data,
         ))
     }
 
     /// Adds `data` so that it will be included in the aggregate value.
-    pub fn add(&mut self, data: TiltData) {
+    /// The data is discarded if its temperature or gravity is out of the valid
+    /// range, or if its gravity is an outlier.
+    fn add(&mut self, data: TiltData) {
+        if !(MIN_VALID_GRAVITY..=MAX_VALID_GRAVITY).contains(&data.gravity)
+            || !(MIN_VALID_TEMPERATURE..=MAX_VALID_TEMPERATURE).contains(&data.temperature) {
+
+            self.n_rejected += 1;
+            warn!(
+                "Rejected invalid temperature {} and gravity {} from {} Tilt ({} rejected)",
+                data.temperature, data.gravity, data.color.name(), self.n_rejected,
+            );
+            return;
+        }
+
+        if self.n_data >= OUTLIER_MIN_SAMPLES {
+            let mean_gravity = self.sum_gravity / self.n_data;
+
+            if mean_gravity.abs_diff(data.gravity as u32) > OUTLIER_GRAVITY_THRESHOLD as u32 {
+                self.n_rejected += 1;
+                warn!(
+                    "Rejected outlier gravity {} from {} Tilt, average is {} ({} rejected)",
+                    data.gravity, data.color.name(), mean_gravity, self.n_rejected,
+                );
+                return;
+            }
+        }
+
+        if self.n_data == 0 {
+            self.min_temperature = data.temperature;
+            self.max_temperature = data.temperature;
+            self.min_gravity = data.gravity;
+            self.max_gravity = data.gravity;
+        } else {
+            self.min_temperature = self.min_temperature.min(data.temperature);
+            self.max_temperature = self.max_temperature.max(data.temperature);
+            self.min_gravity = self.min_gravity.min(data.gravity);
+            self.max_gravity = self.max_gravity.max(data.gravity);
+        }
+
+        self.color = Some(data.color);
+        self.address = data.address;
         self.sum_temperature += data.temperature as u32;
         self.sum_gravity += data.gravity as u32;
         self.max_battery = self.max_battery.max(data.battery);
+        self.sum_rssi += data.rssi as i32;
         self.n_data += 1;
+        self.samples.push(data.temperature, data.gravity);
+    }
+}
+
+/// A fixed size buffer of raw temperature and gravity samples. Once it is full,
+/// it keeps a uniformly random subset of all the samples pushed, using
+/// reservoir sampling, so the samples represent the whole scan rather than
+/// only its start or end.
+struct SampleBuffer {
+    temperatures: [u16; MAX_SAMPLES],
+    gravities: [u16; MAX_SAMPLES],
+    len: usize,
+    // The number of samples pushed, including those that weren't kept
+    n_pushed: u32,
+    rng: u32,
+}
+
+impl Default for SampleBuffer {
+    fn default() -> Self {
+        Self {
+            temperatures: [0; MAX_SAMPLES],
+            gravities: [0; MAX_SAMPLES],
+            len: 0,
+            n_pushed: 0,
+            rng: SAMPLE_RNG_SEED,
+        }
+    }
+}
+
+impl SampleBuffer {
+    fn push(&mut self, temperature: u16, gravity: u16) {
+        // Once full, the nth sample replaces a random one with probability
+        // MAX_SAMPLES / n, which keeps every sample equally likely to be kept
+        let index = if self.len < MAX_SAMPLES {
+            self.len += 1;
+            self.len - 1
+        } else {
+            self.next_random() as usize % (self.n_pushed as usize + 1)
+        };
+
+        if index < MAX_SAMPLES {
+            self.temperatures[index] = temperature;
+            self.gravities[index] = gravity;
+        }
+
+        self.n_pushed = self.n_pushed.saturating_add(1);
+    }
+
+    /// Returns the next number from a xorshift generator, which is plenty
+    /// random for choosing samples.
+    fn next_random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    /// The order of the samples is not preserved once the buffer is full.
+    fn temperatures(&self) -> &[u16] {
+        &self.temperatures[..self.len]
+    }
+
+    fn gravities(&self) -> &[u16] {
+        &self.gravities[..self.len]
+    }
+}
+
+/// Returns the median of `values`, or 0 if it is empty. If there are an even
+/// number of values, the two middle values are averaged.
+fn median(values: &[u16]) -> u16 {
+    if values.is_empty() {
+        return 0;
+    }
+
+    let mut buffer = [0u16; MAX_SAMPLES];
+    let sorted = &mut buffer[..values.len()];
+    sorted.copy_from_slice(values);
+    sorted.sort_unstable();
+
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        ((sorted[mid - 1] as u32 + sorted[mid] as u32) / 2) as u16
+    } else {
+        sorted[mid]
+    }
+}
+
+
+/// A single report from an LE Advertising Report event, from any device.
+pub struct AdvertisingReport<'a>(&'a [u8]);
+
+impl<'a> AdvertisingReport<'a> {
+    /// Returns the BLE address of the advertiser, including the address type
+    /// prefix byte.
+    pub fn address(&self) -> TiltAddress {
+        let mut address: TiltAddress = [0u8; PACKET_ADDRESS_LENGTH];
+        address.copy_from_slice(&self.0[ADDRESS_START..(ADDRESS_START + PACKET_ADDRESS_LENGTH)]);
+        address
+    }
+
+    /// Returns the RSSI the report was received at, which is its last byte.
+    pub fn rssi(&self) -> i8 {
+        self.0[self.0.len() - 1] as i8
+    }
+
+    /// Attempts to parse the report as any iBeacon's packet, including a Tilt's.
+    pub fn parse_ibeacon(&self) -> Option<IBeacon> {
+        IBeacon::try_parse_report(self.0)
+    }
+
+    /// Attempts to parse the report as a Tilt's packet.
+    pub fn parse_tilt(&self) -> Option<TiltPacket> {
+        TiltPacket::try_parse_report(self.0)
+    }
+}
+
+/// Iterates over every report in LE Advertising Report events, whether or not
+/// it is from a Tilt. Created by AdvertisingReports::parse_all.
+pub struct AdvertisingReports<'a> {
+    reports: &'a [u8],
+    n_remaining: u8,
+    // Any events after the current one that were read at the same time
+    next_events: &'a [u8],
+}
+
+impl<'a> AdvertisingReports<'a> {
+    /// Parses `buffer` as LE Advertising Report events and returns an
+    /// iterator over the reports in them. The controller can return several
+    /// events from one read (see esp-wifi#174), so each event in `buffer` is
+    /// parsed in turn. Other events are skipped.
+    pub fn parse_all(buffer: &'a [u8]) -> Self {
+        // Find where this event ends from the length of its parameters
+        let end = match buffer.get(EVENT_PARAMS_LENGTH_INDEX) {
+            Some(params_length) if buffer[0] == EVENT_HEADER[0] => {
+                buffer.len().min(EVENT_PARAMS_LENGTH_INDEX + 1 + *params_length as usize)
+            }
+            _ => return Self { reports: &[], n_remaining: 0, next_events: &[] },
+        };
+
+        let mut reports = Self { reports: &[], n_remaining: 0, next_events: &buffer[end..] };
+
+        if end < REPORTS_START
+            || !buffer.starts_with(&EVENT_HEADER)
+            || buffer[SUBEVENT_INDEX] != SUBEVENT_ADVERTISING_REPORT {
+
+            return reports;
+        }
+
+        reports.reports = &buffer[REPORTS_START..end];
+        reports.n_remaining = buffer[NUM_REPORTS_INDEX];
+        reports
+    }
+}
+
+impl<'a> Iterator for AdvertisingReports<'a> {
+    type Item = AdvertisingReport<'a>;
+
+    fn next(&mut self) -> Option<AdvertisingReport<'a>> {
+        loop {
+            if self.n_remaining > 0 {
+                self.n_remaining -= 1;
+
+                // The report's length depends on the length of its data. Stop
+                // if the event is too short to hold the rest of the report.
+                let report_length = self.reports
+                    .get(REPORT_DATA_LENGTH_INDEX)
+                    .map(|data_length| REPORT_DATA_LENGTH_INDEX + 1 + *data_length as usize + 1)
+                    .filter(|report_length| *report_length <= self.reports.len());
+
+                if let Some(report_length) = report_length {
+                    let (report, rest) = self.reports.split_at(report_length);
+                    self.reports = rest;
+
+                    return Some(AdvertisingReport(report));
+                }
+            }
+
+            // This event has no more reports, so move on to the next one
+            self.n_remaining = 0;
+
+            if self.next_events.is_empty() {
+                return None;
+            }
+
+            *self = Self::parse_all(self.next_events);
+        }
+    }
+}
+
+/// Iterates over the Tilt packets in LE Advertising Report events. Created by
+/// TiltPacket::parse_all.
+pub struct TiltPackets<'a>(AdvertisingReports<'a>);
+
+impl<'a> Iterator for TiltPackets<'a> {
+    type Item = TiltPacket;
+
+    fn next(&mut self) -> Option<TiltPacket> {
+        self.0.find_map(|report| report.parse_tilt())
     }
 }
 
+/// Returns whether every length declared in the `report` matches the number of
+/// bytes actually in it, so a truncated report is rejected before any of its
+/// data is read.
+fn lengths_match(report: &[u8]) -> bool {
+    NESTED_LENGTH_INDICES.iter().all(|&i| match report.get(i) {
+        Some(length) => i + 1 + *length as usize + 1 == report.len(),
+        None => false,
+    })
+}
+
+/// The raw fields of an iBeacon's advertising packet, from any iBeacon. A Tilt
+/// is an iBeacon with one of the Tilt UUIDs, see `TiltPacket`, but other
+/// iBeacons can give the fields their own meaning.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IBeacon {
+    uuid: [u8; UUID_LENGTH],
+    major: u16,
+    minor: u16,
+    power: i8,
+    rssi: i8,
+}
+
+impl IBeacon {
+    /// Attempts to parse a single advertising `report` as an iBeacon's packet.
+    /// Only its structure is checked, so the UUID can be anything.
+    fn try_parse_report(report: &[u8]) -> Option<IBeacon> {
+        if !lengths_match(report)
+            || report.len() != PACKET_LENGTH
+            || !matches!(report[EVENT_TYPE_INDEX], EVENT_TYPE_ADV_NONCONN_IND | EVENT_TYPE_SCAN_RSP)
+            || !report[POST_ADDRESS_START..].starts_with(&PACKET_POST_ADDRESS) {
+
+            return None;
+        }
+
+        // This is the structure of an iBeacon packet's data part
+        let (uuid, data) = report[PACKET_DATA_START..].split_at(UUID_LENGTH);
+
+        Some(Self {
+            uuid: uuid.try_into().unwrap(),
+            major: u16::from_be_bytes([data[0], data[1]]),
+            minor: u16::from_be_bytes([data[2], data[3]]),
+            power: data[4] as i8,
+            rssi: data[5] as i8,
+        })
+    }
+
+    /// Returns the iBeacon's UUID, which identifies what it is.
+    pub fn uuid(&self) -> &[u8; UUID_LENGTH] {
+        &self.uuid
+    }
+
+    /// Returns the iBeacon's major field as transmitted.
+    pub fn major(&self) -> u16 {
+        self.major
+    }
+
+    /// Returns the iBeacon's minor field as transmitted.
+    pub fn minor(&self) -> u16 {
+        self.minor
+    }
+
+    /// Returns the iBeacon's measured power field as transmitted, which is
+    /// normally its RSSI at 1 m.
+    pub fn power(&self) -> i8 {
+        self.power
+    }
+
+    /// Returns the RSSI the packet was received at, in dBm.
+    pub fn rssi(&self) -> i8 {
+        self.rssi
+    }
+}
 
 /// Represents a parsed Tilt BLE advertising packet
 pub struct TiltPacket {
-    address: [u8; PACKET_ADDRESS_LENGTH],
+    address: TiltAddress,
     data: TiltData, 
+    // The raw iBeacon fields the data was parsed from
+    ibeacon: IBeacon,
 }
 
 impl TiltPacket {
-    /// Attempts to parse `buffer` as a Tilt's BLE advertising packet.
-    /// If successful, returns a new packet with the parsed data. None otherwise.
+    /// Attempts to parse `buffer` as an LE Advertising Report event containing
+    /// a Tilt's BLE advertising packet. If successful, returns a new packet
+    /// with the parsed data from the first Tilt's report. None otherwise.
     pub fn try_parse(buffer: &[u8]) -> Option<TiltPacket> {
-        if buffer.len() < PACKET_LENGTH
-            || !buffer.starts_with(&PACKET_PRE_ADDRESS)
-            || !buffer[POST_ADDRESS_START..].starts_with(&PACKET_POST_ADDRESS) {
-        
-            return None;
-        }
+        Self::parse_all(buffer).next()
+    }
+
+    /// Parses `buffer` as LE Advertising Report events and returns an
+    /// iterator over the packets of every Tilt reported in them. The
+    /// controller can return several events from one read (see esp-wifi#174),
+    /// so each event in `buffer` is parsed in turn. Reports from other devices
+    /// and other events are skipped.
+    pub fn parse_all(buffer: &[u8]) -> TiltPackets<'_> {
+        TiltPackets(AdvertisingReports::parse_all(buffer))
+    }
+
+    /// Attempts to parse a single advertising `report` as a Tilt's packet.
+    fn try_parse_report(report: &[u8]) -> Option<TiltPacket> {
+        let ibeacon = IBeacon::try_parse_report(report)?;
+
+        // The UUID identifies the color of the Tilt. Other iBeacons are ignored.
+        let color = TiltColor::from_uuid(ibeacon.uuid())?;
 
         // Extract the Tilt's BLE address
-        let address_buf = &buffer[ADDRESS_START..(ADDRESS_START + PACKET_ADDRESS_LENGTH)];
-        let mut address = [0u8; PACKET_ADDRESS_LENGTH];
-        address.copy_from_slice(address_buf);
+        let address = AdvertisingReport(report).address();
 
-        // This is the structure of an iBeacon packet's data part
-        let (uuid, mut data) = &buffer[PACKET_DATA_START..].split_at(UUID_LENGTH);
-        let major = (data[0] as u16) << 8 | data[1] as u16;
-        data = &data[2..];
-        let minor = (data[0] as u16) << 8 | data[1] as u16;
-        data = &data[2..];
-        let power = data[0] as i8;
-        let rssi = data[1] as i8;
-
-        info!("UUID: {:02X?}", uuid);
+        let (major, minor, power, rssi) = (ibeacon.major, ibeacon.minor, ibeacon.power, ibeacon.rssi);
+
+        info!("UUID: {:02X?} ({})", ibeacon.uuid, color.name());
         info!("major: {}", major);
         info!("minor: {}", minor);
         info!("power: {}", power);
         info!("rssi: {}", rssi);
 
+        // Temperature is the major data field, gravity is the minor
+        let (temperature, gravity) = if minor >= MIN_PRO_RAW_GRAVITY {
+            (major, minor)
+        } else {
+            info!("Standard Tilt, scaling to the Tilt Pro's precision");
+            (
+                major.saturating_mul(STANDARD_TEMPERATURE_SCALE),
+                minor.saturating_mul(STANDARD_GRAVITY_SCALE),
+            )
+        };
+
         // The "Measured Power" field alternates between -59 and a non-negative
         // number. When the Tilt manufacturer was contacted they said the
         // non-negative number is the number of weeks since the battery was
@@ -200,14 +1225,21 @@ impl TiltPacket {
 
         Some(Self {
             address,
-            // Temperature is the major data field, gravity is the minor
-            data: TiltData::new(major, minor, battery),
+            data: TiltData::new(
+                color,
+                address,
+                calibrate(temperature, TEMPERATURE_CALIBRATION_OFFSET),
+                calibrate(gravity, GRAVITY_CALIBRATION_OFFSET),
+                battery,
+                rssi,
+            ),
+            ibeacon,
         })
     }
 
     /// Returns the BLE address of the Tilt device.
     /// This includes the address type prefix byte.
-    pub fn address(&self) -> &[u8; PACKET_ADDRESS_LENGTH] {
+    pub fn address(&self) -> &TiltAddress {
         &self.address
     }
 
@@ -215,4 +1247,376 @@ impl TiltPacket {
     pub fn data(&self) -> TiltData {
         self.data
     }
-}
\ No newline at end of file
+
+    /// Returns the raw iBeacon fields the data was parsed from.
+    pub fn ibeacon(&self) -> &IBeacon {
+        &self.ibeacon
+    }
+
+    /// Returns the iBeacon's major field as transmitted, which the Tilt uses
+    /// for the temperature before scaling and calibration.
+    pub fn major(&self) -> u16 {
+        self.ibeacon.major
+    }
+
+    /// Returns the iBeacon's minor field as transmitted, which the Tilt uses
+    /// for the gravity before scaling and calibration.
+    pub fn minor(&self) -> u16 {
+        self.ibeacon.minor
+    }
+
+    /// Returns the iBeacon's measured power field as transmitted, which the
+    /// Tilt alternates between -59 and the battery's age in weeks.
+    pub fn power(&self) -> i8 {
+        self.ibeacon.power
+    }
+
+    /// Returns the RSSI the packet was received at, in dBm.
+    pub fn rssi(&self) -> i8 {
+        self.ibeacon.rssi
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOLDEN_ADDRESS: TiltAddress = [0x00, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11];
+
+    /// An LE Advertising Report event from a Green Tilt Pro reading 68.5°F and
+    /// 1.0090, with a battery 5 weeks old, received at -64 dBm. This is built
+    /// by hand from the HCI and iBeacon specs rather than from the consts above,
+    /// so it catches mistakes in them.
+    const GOLDEN_PACKET: [u8; 45] = [
+        0x04, 0x3E, 0x2A, // LE Meta Event with 42 bytes of parameters
+        0x02, 0x01, // 1 LE Advertising Report
+        0x03, // Non connectable undirected advertising
+        0x00, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // Public address
+        0x1E, 0x02, 0x01, 0x04, 0x1A, 0xFF, 0x4C, 0x00, 0x02, 0x15,
+        0xA4, 0x95, 0xBB, 0x20, 0xC5, 0xB1, 0x4B, 0x44, // Green Tilt UUID
+        0xB5, 0x12, 0x13, 0x70, 0xF0, 0x2D, 0x74, 0xDE,
+        0x02, 0xAD, // Major: 685
+        0x27, 0x6A, // Minor: 10090
+        0x05, // Power: 5 weeks
+        0xC0, // RSSI: -64 dBm
+    ];
+
+    /// Returns the report in GOLDEN_PACKET with the color byte of the UUID
+    /// replaced by `color_byte`.
+    fn golden_report(color_byte: u8) -> [u8; PACKET_LENGTH] {
+        let mut report: [u8; PACKET_LENGTH] = GOLDEN_PACKET[REPORTS_START..].try_into().unwrap();
+        report[PACKET_DATA_START + TILT_UUID_COLOR_INDEX] = color_byte;
+        report
+    }
+
+    /// Returns a single reading with the scaled `temperature` and `gravity`.
+    fn reading(temperature: u16, gravity: u16) -> TiltData {
+        TiltData::new(TiltColor::Red, GOLDEN_ADDRESS, temperature, gravity, None, -60)
+    }
+
+    /// Returns an LE Advertising Report event holding the `reports`.
+    fn event(reports: &[&[u8]]) -> Vec<u8> {
+        let mut event = vec![0x04, 0x3E, 0x00, SUBEVENT_ADVERTISING_REPORT, reports.len() as u8];
+        for report in reports {
+            event.extend_from_slice(report);
+        }
+        event[EVENT_PARAMS_LENGTH_INDEX] = (event.len() - SUBEVENT_INDEX) as u8;
+        event
+    }
+
+    #[test]
+    fn plato() {
+        assert_eq!(reading(680, 10000).gravity_plato_str(&mut [0; 6]), "0.00");
+        assert_eq!(reading(680, 10500).gravity_plato_str(&mut [0; 6]), "12.39");
+        assert_eq!(reading(680, 11000).gravity_plato_str(&mut [0; 6]), "23.77");
+    }
+
+    #[test]
+    fn plato_matches_polynomial() {
+        for gravity in (9900..=12000).step_by(50) {
+            let sg = gravity as f64 / 10_000.0;
+            let expected = -616.868 + 1111.14 * sg - 630.272 * sg * sg + 135.997 * sg * sg * sg;
+            let plato: f64 = reading(680, gravity).gravity_plato_str(&mut [0; 6]).parse().unwrap();
+            assert!((plato - expected).abs() <= 0.005, "{} is {}, not {}", gravity, plato, expected);
+        }
+    }
+
+    #[test]
+    fn gravity_calibration() {
+        // A point is 0.001, which is 10 in the scaled units
+        let point = 10i16.pow(GRAVITY_DECIMAL_PLACES as u32 - 3);
+        assert_eq!(calibrate(9980, 2 * point), 10000);
+        assert_eq!(calibrate(10020, -2 * point), 10000);
+        assert_eq!(calibrate(10, -2 * point), 0);
+        assert_eq!(calibrate(u16::MAX - 10, 2 * point), u16::MAX);
+    }
+
+    #[test]
+    fn temperature_calibration() {
+        // -15 is -1.5°F
+        let corrected = reading(calibrate(682, -15), 10000);
+        assert_eq!(corrected.temperature_str(&mut [0; 6]), "66.7");
+        assert_eq!(calibrate(10, -15), 0);
+    }
+
+    #[test]
+    fn multiple_reports_in_one_event() {
+        // Another device's report with 3 bytes of data, then a Red and a Green Tilt
+        let other = [0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x03, 0x02, 0x01, 0x06, 0xB0];
+        let event = event(&[&other, &golden_report(0x10), &golden_report(0x20)]);
+
+        assert_eq!(AdvertisingReports::parse_all(&event).count(), 3);
+        let colors: Vec<_> = TiltPacket::parse_all(&event).map(|packet| packet.data().color()).collect();
+        assert_eq!(colors, [TiltColor::Red, TiltColor::Green]);
+        assert_eq!(TiltPacket::try_parse(&event).unwrap().data().color(), TiltColor::Red);
+    }
+
+    #[test]
+    fn val_to_str_full_range() {
+        let cases = [
+            (0, "0.0", "0.0000"),
+            (1, "0.1", "0.0001"),
+            (9999, "999.9", "0.9999"),
+            (10000, "1000.0", "1.0000"),
+            (65535, "6553.5", "6.5535"),
+        ];
+
+        for (val, one_decimal, four_decimals) in cases {
+            assert_eq!(val_to_str(val, 1, &mut [0; 6]), one_decimal);
+            assert_eq!(val_to_str(val, 4, &mut [0; 6]), four_decimals);
+        }
+    }
+
+    #[test]
+    fn signed_val_to_str_negative() {
+        assert_eq!(signed_val_to_str(-5, 1, &mut [0; 6]), "-0.5");
+        assert_eq!(signed_val_to_str(-100, 1, &mut [0; 6]), "-10.0");
+        assert_eq!(signed_val_to_str(-9999, 1, &mut [0; 6]), "-999.9");
+        // Clamped to fit the buffer
+        assert_eq!(signed_val_to_str(-12345, 1, &mut [0; 6]), "-999.9");
+    }
+
+    #[test]
+    fn signed_val_to_str_around_zero() {
+        assert_eq!(signed_val_to_str(-1, 1, &mut [0; 6]), "-0.1");
+        assert_eq!(signed_val_to_str(0, 1, &mut [0; 6]), "0.0");
+        assert_eq!(signed_val_to_str(1, 1, &mut [0; 6]), "0.1");
+
+        // 31.9°F, 32°F and 32.1°F in Celsius
+        assert_eq!(reading(319, 10000).temperature_celsius_str(&mut [0; 6]), "-0.1");
+        assert_eq!(reading(320, 10000).temperature_celsius_str(&mut [0; 6]), "0.0");
+        assert_eq!(reading(321, 10000).temperature_celsius_str(&mut [0; 6]), "0.1");
+    }
+
+    #[test]
+    fn truncated_packet() {
+        for len in 0..GOLDEN_PACKET.len() {
+            assert!(TiltPacket::try_parse(&GOLDEN_PACKET[..len]).is_none(), "{} bytes", len);
+        }
+
+        // The event's length matches, but the report is shorter than it declares
+        let report = golden_report(0x20);
+        let mut truncated = report[..(PACKET_LENGTH - 3)].to_vec();
+        truncated.push(report[PACKET_LENGTH - 1]);
+        assert!(TiltPacket::try_parse(&event(&[&truncated])).is_none());
+
+        // The report is complete, but its iBeacon data declares the wrong length
+        let mut report = golden_report(0x20);
+        report[IBEACON_LENGTH_INDEX] -= 1;
+        assert!(TiltPacket::try_parse(&event(&[&report])).is_none());
+    }
+
+    #[test]
+    fn brix() {
+        // Common reference points, e.g. 1.040 is about 10 Brix
+        assert_eq!(reading(680, 10000).gravity_brix_str(&mut [0; 6]), "0.00");
+        assert_eq!(reading(680, 10400).gravity_brix_str(&mut [0; 6]), "9.99");
+        assert_eq!(reading(680, 10800).gravity_brix_str(&mut [0; 6]), "19.33");
+        assert_eq!(reading(680, 11000).gravity_brix_str(&mut [0; 6]), "23.77");
+    }
+
+    #[test]
+    fn brix_matches_polynomial() {
+        for gravity in (9900..=12000).step_by(50) {
+            let sg = gravity as f64 / 10_000.0;
+            let expected = ((182.4601 * sg - 775.6821) * sg + 1262.7794) * sg - 669.5622;
+            let brix: f64 = reading(680, gravity).gravity_brix_str(&mut [0; 6]).parse().unwrap();
+            assert!((brix - expected).abs() <= 0.005, "{} is {}, not {}", gravity, brix, expected);
+        }
+    }
+
+    #[test]
+    fn other_ibeacon_is_not_a_tilt() {
+        // An iBeacon with the same structure, but another vendor's UUID
+        let mut report = golden_report(0x20);
+        report[PACKET_DATA_START..][..UUID_LENGTH].copy_from_slice(&[
+            0xE2, 0xC5, 0x6D, 0xB5, 0xDF, 0xFB, 0x48, 0xD2,
+            0xB0, 0x60, 0xD0, 0xF5, 0xA7, 0x10, 0x96, 0xE0,
+        ]);
+        assert!(lengths_match(&report));
+        assert!(TiltPacket::try_parse(&event(&[&report])).is_none());
+
+        // A Tilt UUID with an unknown color
+        assert!(TiltPacket::try_parse(&event(&[&golden_report(0x90)])).is_none());
+    }
+
+    #[test]
+    fn concatenated_events() {
+        // A Command Complete event, then two advertising events, read at once
+        let mut buffer = vec![0x04, 0x0E, 0x04, 0x01, 0x0C, 0x20, 0x00];
+        buffer.extend(event(&[&golden_report(0x10)]));
+        buffer.extend(event(&[&golden_report(0x20)]));
+
+        let colors: Vec<_> = TiltPacket::parse_all(&buffer).map(|packet| packet.data().color()).collect();
+        assert_eq!(colors, [TiltColor::Red, TiltColor::Green]);
+    }
+
+    #[test]
+    fn truncated_report_ends_its_event() {
+        // The event claims 2 reports but is cut off part way through the second.
+        // The next event is still parsed.
+        let mut first = event(&[&golden_report(0x10), &golden_report(0x20)]);
+        first.truncate(first.len() - 10);
+        first[EVENT_PARAMS_LENGTH_INDEX] = (first.len() - SUBEVENT_INDEX) as u8;
+        first.extend(event(&[&golden_report(0x30)]));
+
+        let colors: Vec<_> = TiltPacket::parse_all(&first).map(|packet| packet.data().color()).collect();
+        assert_eq!(colors, [TiltColor::Red, TiltColor::Black]);
+    }
+
+    #[test]
+    fn gravity_points() {
+        assert_eq!(reading(680, 10000).gravity_points(), 0);
+        assert_eq!(reading(680, 10500).gravity_points(), 50);
+        assert_eq!(reading(680, 11200).gravity_points(), 120);
+        // Rounded to the nearest point, and 0 below the gravity of water
+        assert_eq!(reading(680, 10505).gravity_points(), 51);
+        assert_eq!(reading(680, 9980).gravity_points(), 0);
+    }
+
+    #[test]
+    fn valid_range_boundaries() {
+        let is_kept = |temperature, gravity| {
+            let mut stats = TiltStats::new();
+            stats.add(reading(temperature, gravity));
+            stats.aggregate()[TiltColor::Red.index()].is_some()
+        };
+
+        assert!(is_kept(MIN_VALID_TEMPERATURE, MIN_VALID_GRAVITY));
+        assert!(is_kept(MAX_VALID_TEMPERATURE, MAX_VALID_GRAVITY));
+        assert!(!is_kept(MIN_VALID_TEMPERATURE - 1, 10000));
+        assert!(!is_kept(MAX_VALID_TEMPERATURE + 1, 10000));
+        assert!(!is_kept(680, MIN_VALID_GRAVITY - 1));
+        assert!(!is_kept(680, MAX_VALID_GRAVITY + 1));
+        assert!(!is_kept(680, 0));
+    }
+
+    #[test]
+    fn invalid_samples_are_not_averaged() {
+        let mut stats = TiltStats::new();
+        stats.add(reading(680, 10500));
+        stats.add(reading(680, 0));
+        stats.add(reading(5000, 10500));
+
+        let aggregate = stats.aggregate()[TiltColor::Red.index()].unwrap();
+        assert_eq!((aggregate.temperature(), aggregate.gravity()), (680, 10500));
+        assert_eq!(aggregate.n_samples(), 1);
+    }
+
+    #[test]
+    fn standard_tilt_is_scaled_to_pro_precision() {
+        // A standard Tilt sends whole degrees and three decimal places, 68°F
+        // and 1.050
+        let mut report = golden_report(0x20);
+        report[(PACKET_DATA_START + UUID_LENGTH)..][..4].copy_from_slice(&[0x00, 0x44, 0x04, 0x1A]);
+
+        let data = TiltPacket::try_parse(&event(&[&report])).unwrap().data();
+        assert_eq!(data.temperature(), 680);
+        assert_eq!(data.gravity(), 10500);
+        assert_eq!(data.temperature_str(&mut [0; 6]), "68.0");
+        assert_eq!(data.gravity_str(&mut [0; 6]), "1.0500");
+    }
+
+    #[test]
+    fn raw_ibeacon_fields() {
+        let packet = TiltPacket::try_parse(&GOLDEN_PACKET).unwrap();
+        assert_eq!((packet.major(), packet.minor(), packet.power(), packet.rssi()), (0x02AD, 0x276A, 5, -64));
+
+        // Another vendor's iBeacon isn't a Tilt, but its raw fields are still
+        // available from the report
+        let mut report = golden_report(0x20);
+        report[PACKET_DATA_START] = 0xE2;
+        report[(PACKET_DATA_START + UUID_LENGTH)..].copy_from_slice(&[0x12, 0x34, 0xAB, 0xCD, 0xC5, 0xB0]);
+        let event = event(&[&report]);
+
+        let advertising_report = AdvertisingReports::parse_all(&event).next().unwrap();
+        assert!(advertising_report.parse_tilt().is_none());
+
+        let ibeacon = advertising_report.parse_ibeacon().unwrap();
+        assert_eq!(ibeacon.uuid()[..], report[PACKET_DATA_START..][..UUID_LENGTH]);
+        assert_eq!(ibeacon.major(), 0x1234);
+        assert_eq!(ibeacon.minor(), 0xABCD);
+        assert_eq!(ibeacon.power(), 0xC5u8 as i8);
+        assert_eq!(ibeacon.rssi(), 0xB0u8 as i8);
+    }
+
+    #[test]
+    fn golden_packet() {
+        let packet = TiltPacket::try_parse(&GOLDEN_PACKET).unwrap();
+        assert_eq!(*packet.address(), GOLDEN_ADDRESS);
+
+        let data = packet.data();
+        assert_eq!(data.color(), TiltColor::Green);
+        assert_eq!(*data.address(), GOLDEN_ADDRESS);
+        assert_eq!(data.temperature(), 685);
+        assert_eq!(data.temperature_str(&mut [0; 6]), "68.5");
+        assert_eq!(data.gravity(), 10090);
+        assert_eq!(data.gravity_str(&mut [0; 6]), "1.0090");
+        assert_eq!(data.battery(), Some(5));
+        assert_eq!(data.rssi(), -64);
+    }
+
+    #[test]
+    fn golden_packet_layout() {
+        assert_eq!(GOLDEN_PACKET.len(), REPORTS_START + PACKET_LENGTH);
+
+        let report = &GOLDEN_PACKET[REPORTS_START..];
+        assert_eq!(report[ADDRESS_START..POST_ADDRESS_START], GOLDEN_ADDRESS);
+        assert_eq!(report[POST_ADDRESS_START..PACKET_DATA_START], PACKET_POST_ADDRESS);
+        assert_eq!(TiltColor::from_uuid(report[PACKET_DATA_START..][..UUID_LENGTH].try_into().unwrap()),
+            Some(TiltColor::Green));
+        assert!(lengths_match(report));
+    }
+
+    #[test]
+    fn color_index_round_trip() {
+        for index in 0..TiltColor::COUNT {
+            assert_eq!(TiltColor::from_index(index).unwrap().index(), index);
+        }
+        assert_eq!(TiltColor::from_index(TiltColor::COUNT), None);
+    }
+
+    #[test]
+    fn gravity_below_water() {
+        assert_eq!(reading(680, 9900).gravity_str(&mut [0; 6]), "0.9900");
+        assert_eq!(reading(680, 9980).gravity_str(&mut [0; 6]), "0.9980");
+        assert_eq!(reading(680, 9990).gravity_str(&mut [0; 6]), "0.9990");
+        assert_eq!(reading(680, 9999).gravity_str(&mut [0; 6]), "0.9999");
+        assert_eq!(val_to_str(998, 3, &mut [0; 6]), "0.998");
+    }
+
+    #[test]
+    fn gravity_below_water_from_packet() {
+        // Standard Tilts reading 0.990, 0.998 and 0.999, through aggregation
+        for (raw, expected) in [(990u16, "0.9900"), (998, "0.9980"), (999, "0.9990")] {
+            let mut report = golden_report(0x20);
+            report[(PACKET_DATA_START + UUID_LENGTH)..][..2].copy_from_slice(&68u16.to_be_bytes());
+            report[(PACKET_DATA_START + UUID_LENGTH + 2)..][..2].copy_from_slice(&raw.to_be_bytes());
+
+            let mut stats = TiltStats::new();
+            stats.add(TiltPacket::try_parse(&event(&[&report])).unwrap().data());
+            let aggregate = stats.aggregate()[TiltColor::Green.index()].unwrap();
+            assert_eq!(aggregate.gravity_str(&mut [0; 6]), expected);
+        }
+    }
+}
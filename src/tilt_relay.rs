@@ -1,28 +1,854 @@
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp32c3_hal::rtc_cntl::sleep::TimerWakeupSource;
+use esp32c3_hal::macros::ram;
+use esp32c3_hal::peripherals::TIMG0;
+use esp32c3_hal::prelude::*;
+use esp32c3_hal::timer::Wdt;
+use esp32c3_hal::{Delay, Rtc};
+use log::{error, info, warn};
 
+use crate::led::LedState;
+use crate::mqtt::Command;
+use crate::tilt::{DataKind, TiltColor, TiltData, TiltReadings, TiltStats, GRAVITY_OF_WATER, SERIALIZED_LENGTH};
 use crate::tilt_scanner::TiltScanner;
 
 // Brewfather allows us to post data at most every 15 minutes
-const PUBLISH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const MIN_PUBLISH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Once the gravity is stable, the interval doubles after each post up to this,
+// to save bandwidth and power. It returns to the minimum when the gravity
+// starts changing again.
+const MAX_PUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// The gravity is stable if it changed by less than this between posts. It is
+// scaled the same as the gravity from the Tilt, so 10 is 0.0010.
+const STABLE_GRAVITY_THRESHOLD: u16 = 10;
 // Scan for 1 minute before each post to ensure we pick up the Tilt broadcast
 const SCAN_DURATION: Duration = Duration::from_secs(60);
 
+// The scan must fit between posts
+const _: () = assert!(SCAN_DURATION.as_ticks() < MIN_PUBLISH_INTERVAL.as_ticks());
+const _: () = assert!(MIN_PUBLISH_INTERVAL.as_ticks() <= MAX_PUBLISH_INTERVAL.as_ticks());
+// Instead of one scan of SCAN_DURATION before each post, scan in short bursts
+// spread over the whole publish interval: scan for the first duration, pause
+// for the second, and repeat until the post. The data from every burst is
+// aggregated, so it is representative of the whole interval, and the radio is
+// only on for a fraction of the time. None scans once before each post. With
+// DEEP_SLEEP, the bursts only span SCAN_DURATION since the relay is asleep for
+// the rest of the interval.
+const SCAN_BURSTS: Option<(Duration, Duration)> = None;
+// Report the median of the scanned data instead of the average. The median is
+// more robust to the occasional bad reading.
+const USE_MEDIAN: bool = false;
+// Print a CSV line over serial for each Tilt's aggregated reading, so the
+// readings can be captured without a server. A header line is printed at boot.
+// The fields are always in this order: the uptime in milliseconds, the
+// temperature in Fahrenheit, the specific gravity, the battery age in weeks
+// (empty if unknown), the number of readings aggregated, the RSSI in dBm, and
+// the Tilt's color.
+const CSV_OUTPUT: bool = false;
+const CSV_HEADER: &str = "uptime_ms,temperature,gravity,battery,samples,rssi,color";
+// Post an exponential moving average of each Tilt's temperature and gravity
+// across publish windows, which is less jittery than each window on its own.
+// This is the weight of the latest window, from 0 to 1, so smaller values
+// smooth more. The raw values are posted alongside as temp_raw and gravity_raw.
+// The average starts at the first reading after boot, so it takes roughly
+// 1 / factor windows to settle and follows that first reading until then. None
+// posts each window as is.
+const SMOOTHING_FACTOR: Option<f32> = None;
+// Filter the posted temperature, but not the gravity, with a low-pass filter
+// that has this time constant, e.g. to hide the swings from a fridge's
+// compressor cycling. The raw temperature is posted alongside as temp_raw. The
+// filter introduces lag: after a sudden change, the posted temperature has
+// moved about 63% of the way after one time constant and 95% after three, and
+// during a steady ramp it trails the real temperature by about one time
+// constant. The filter starts at each Tilt's first reading after boot. None
+// posts the temperature as is.
+const TEMPERATURE_TIME_CONSTANT: Option<Duration> = None;
+// Don't post a Tilt's data if fewer than this many readings were received
+// during the scan, since the value would be unreliable. 0 always posts.
+const MIN_SAMPLES: u32 = 0;
+// A Tilt that has been seen is offline after this many scans in a row without
+// any data from it, e.g. because its battery died. This is logged and shown on
+// the status LED until the Tilt is seen again.
+const OFFLINE_SCANS: u32 = 4;
+// Also post a notice when a Tilt goes offline, which Brewfather shows as a
+// comment on the batch's graph. Other targets ignore it.
+const POST_OFFLINE_NOTICE: bool = false;
+// Post a heartbeat for each Tilt that has been seen when a scan finds no data at
+// all, so Brewfather shows the relay is still alive. It has the relay's uptime
+// and the Tilt's last battery level, but not its stale temperature and gravity.
+const HEARTBEAT_ENABLED: bool = false;
+// Only post when a Tilt's reading has changed since it was last posted, to
+// avoid sending near-identical datapoints during a long stable phase. A reading
+// has changed if its temperature or gravity moved by at least these deltas,
+// which are scaled the same as the values from the Tilt, so 5 is 0.5°F and
+// 0.0005. The readings are still posted after MAX_SUPPRESSED_POSTS windows in a
+// row without a post, so a gap doesn't look like an outage. The last posted
+// values aren't kept across deep sleep, so with DEEP_SLEEP every window posts.
+const POST_ON_CHANGE: bool = false;
+const POST_ON_CHANGE_TEMPERATURE_DELTA: u16 = 5;
+const POST_ON_CHANGE_GRAVITY_DELTA: u16 = 5;
+const MAX_SUPPRESSED_POSTS: u32 = 4;
+
+// The original gravity used to report the apparent attenuation, scaled the same
+// as the gravity from the Tilt, e.g. Some(10500) for 1.0500. If this is None,
+// the first stable gravity above 1.000 from each Tilt is used. That is kept in
+// RTC memory, so it survives resets and deep sleep but is forgotten when the
+// relay loses power, e.g. when it is unplugged for the next batch.
+const ORIGINAL_GRAVITY: Option<u16> = None;
+// Marks the retained original gravities as valid, since RTC memory holds
+// garbage after power on
+const RETAINED_MAGIC: u32 = 0x4F47_0001;
+
+// Stop scanning and posting once the relay has run for this long, e.g. so it
+// doesn't keep posting after the batch is done. It then deep sleeps until it is
+// reset or unplugged. None runs forever.
+const MAX_RUNTIME: Option<Duration> = None;
+// Keep counting the runtime across resets and deep sleep, in RTC memory, so a
+// panic doesn't restart the clock. Otherwise a reset starts it over, which is a
+// way to run for another MAX_RUNTIME. It always starts over after power loss.
+const RUNTIME_SURVIVES_RESET: bool = true;
+// Marks the retained runtime as valid
+const RETAINED_RUNTIME_MAGIC: u32 = 0x5254_0001;
+// Marks the retained readings as valid. The latest reading from each Tilt is
+// kept in RTC memory so the status page can show it as soon as the relay boots
+// after a reset, instead of nothing until the first scan.
+const RETAINED_READINGS_MAGIC: u32 = 0x4C52_0001;
+
+// Deep sleep after each post instead of staying awake until the next scan, to
+// save power when running from a battery. Waking from deep sleep restarts the
+// relay from the beginning, so each cycle includes finding the Tilts and
+// connecting to WiFi again before scanning.
+const DEEP_SLEEP: bool = false;
+// Stop the WiFi after each post and start it again when the next scan starts,
+// to save power without deep sleeping. It reconnects during the scan, so this
+// saves little with SCAN_BURSTS, since the scan spans the whole interval.
+const WIFI_IDLE_BETWEEN_POSTS: bool = false;
+// The longest to wait for the data to be posted before going to sleep or idling
+// the WiFi anyway
+const MAX_PUBLISH_WAIT: Duration = Duration::from_secs(60);
+// Never sleep for less than this, even if the cycle took longer than expected
+const MIN_SLEEP_DURATION: Duration = Duration::from_secs(10);
+
+// Reset the relay if readings have been queued for this many cycles in a row
+// without the publishing task finishing with any of them, e.g. because it is
+// stuck on a socket. It is alive if it buffers the readings while the WiFi is
+// down or fails to post them, which it handles itself by resetting after
+// wifi::MAX_FAILURES failures. None never checks.
+const MAX_UNPUBLISHED_CYCLES: Option<u32> = Some(4);
+
+// The device is reset if the watchdog isn't fed for this long. It must be
+// longer than the scan, since the watchdog can't be fed while scanning.
+pub const WATCHDOG_TIMEOUT_SECS: u64 = 90;
+// How often to feed the watchdog while waiting for the next scan
+const WATCHDOG_FEED_INTERVAL: Duration = Duration::from_secs(30);
+
+const _: () = assert!(SCAN_DURATION.as_secs() < WATCHDOG_TIMEOUT_SECS);
+const _: () = assert!(match SCAN_BURSTS {
+    Some((on, _)) => on.as_secs() < WATCHDOG_TIMEOUT_SECS,
+    None => true,
+});
+const _: () = assert!(WATCHDOG_FEED_INTERVAL.as_secs() < WATCHDOG_TIMEOUT_SECS);
+const _: () = assert!(match SMOOTHING_FACTOR {
+    Some(factor) => factor > 0.0 && factor <= 1.0,
+    None => true,
+});
+const _: () = assert!(match TEMPERATURE_TIME_CONSTANT {
+    Some(time_constant) => time_constant.as_ticks() > 0,
+    None => true,
+});
+
+/// The original gravity of each Tilt, indexed by `TiltColor::index`. 0 means
+/// it hasn't been captured yet.
+#[derive(Clone, Copy)]
+struct RetainedGravity {
+    check: u32,
+    gravity: [u16; TiltColor::COUNT],
+}
+
+impl RetainedGravity {
+    fn checksum(gravity: &[u16; TiltColor::COUNT]) -> u32 {
+        gravity.iter().fold(RETAINED_MAGIC, |check, g| check.rotate_left(5) ^ *g as u32)
+    }
+}
+
+// Only accessed from the relay task
+#[ram(rtc_fast, uninitialized)]
+static mut RETAINED_ORIGINAL_GRAVITY: RetainedGravity = RetainedGravity {
+    check: 0,
+    gravity: [0; TiltColor::COUNT],
+};
+
+/// How long the relay has run in total, as of the last time it was retained.
+#[derive(Clone, Copy)]
+struct RetainedRuntime {
+    check: u32,
+    secs: u64,
+}
+
+impl RetainedRuntime {
+    fn checksum(secs: u64) -> u32 {
+        RETAINED_RUNTIME_MAGIC.rotate_left(5) ^ secs as u32 ^ (secs >> 32) as u32
+    }
+}
+
+// Only accessed from the relay task
+#[ram(rtc_fast, uninitialized)]
+static mut RETAINED_RUNTIME: RetainedRuntime = RetainedRuntime {
+    check: 0,
+    secs: 0,
+};
+
+/// The latest reading from each Tilt, indexed by `TiltColor::index` and
+/// serialized by `TiltData::to_bytes`. All zeros means there isn't one.
+#[derive(Clone, Copy)]
+struct RetainedReadings {
+    check: u32,
+    readings: [[u8; SERIALIZED_LENGTH]; TiltColor::COUNT],
+}
+
+impl RetainedReadings {
+    fn checksum(readings: &[[u8; SERIALIZED_LENGTH]; TiltColor::COUNT]) -> u32 {
+        readings.iter()
+            .flatten()
+            .fold(RETAINED_READINGS_MAGIC, |check, b| check.rotate_left(5) ^ *b as u32)
+    }
+}
+
+// Only accessed from the relay task
+#[ram(rtc_fast, uninitialized)]
+static mut RETAINED_READINGS: RetainedReadings = RetainedReadings {
+    check: 0,
+    readings: [[0; SERIALIZED_LENGTH]; TiltColor::COUNT],
+};
+
 #[embassy_executor::task]
-pub async fn run_relay_task(mut tilt_scanner: TiltScanner) {
+pub async fn run_relay_task(
+    mut tilt_scanner: TiltScanner,
+    mut watchdog: Wdt<TIMG0>,
+    mut rtc: Rtc<'static>,
+    mut delay: Delay,
+) {
     let mut next_publish_time = Instant::now() + SCAN_DURATION;
+    let mut publish_interval = MIN_PUBLISH_INTERVAL;
+    let mut last_gravity: [Option<u16>; TiltColor::COUNT] = [None; TiltColor::COUNT];
+    // The latest data from each Tilt that has been seen, and how many scans in
+    // a row have had no data from it since
+    let mut last_seen: TiltReadings = [None; TiltColor::COUNT];
+    let mut missed_scans = [0u32; TiltColor::COUNT];
+    // The moving average of each Tilt's temperature and gravity, if smoothing
+    let mut averages: [Option<(f32, f32)>; TiltColor::COUNT] = [None; TiltColor::COUNT];
+    // The filtered temperature of each Tilt and when it was last updated, for
+    // TEMPERATURE_TIME_CONSTANT
+    let mut filtered_temperatures: [Option<(f32, Instant)>; TiltColor::COUNT] = [None; TiltColor::COUNT];
+    // The latest posted reading from each Tilt, and how many windows in a row
+    // haven't been posted since, for POST_ON_CHANGE
+    let mut last_posted: TiltReadings = [None; TiltColor::COUNT];
+    let mut n_suppressed = 0u32;
+    // When the publishing task last finished with readings, and how many cycles
+    // in a row have queued readings since, for MAX_UNPUBLISHED_CYCLES
+    let mut last_published: Option<Instant> = None;
+    let mut n_unpublished = 0u32;
+    // The publish interval set by a command, which replaces the adaptive one
+    let mut fixed_interval: Option<Duration> = None;
+    let runtime_before_boot = runtime_before_boot();
+    load_retained_readings();
+
+    if CSV_OUTPUT {
+        esp_println::println!("{}", CSV_HEADER);
+    }
 
     loop {
-        // Sleep until the next publish time, minus the time we spend scanning
-        Timer::at(next_publish_time - SCAN_DURATION).await;
+        // Sleep until the next publish time, minus the time we spend scanning.
+        // Bursts are spread over the whole interval instead.
+        let scan_start_time = match SCAN_BURSTS {
+            Some(_) => Instant::from_ticks(next_publish_time.as_ticks().saturating_sub(publish_interval.as_ticks())),
+            None => next_publish_time - SCAN_DURATION,
+        };
+        wait_until(scan_start_time, &mut watchdog).await;
+
+        if WIFI_IDLE_BETWEEN_POSTS {
+            crate::wifi::wake_up();
+        }
+
+        handle_commands(&mut tilt_scanner, &mut fixed_interval);
+
+        if let Some(max_runtime) = MAX_RUNTIME {
+            let runtime = runtime_before_boot + uptime();
+            retain_runtime(runtime);
+
+            if runtime >= max_runtime {
+                stop(&mut rtc, &mut delay, &mut watchdog, runtime).await;
+            }
+        }
+
+        // Scan for the data over Bluetooth LE. The watchdog is fed before and
+        // after, so it trips if the scan hangs.
+        watchdog.feed();
+        let stats = match SCAN_BURSTS {
+            Some((on, off)) => scan_in_bursts(&mut tilt_scanner, next_publish_time, on, off, &mut watchdog).await,
+            None => {
+                crate::led::set_state(LedState::Scanning);
+                tilt_scanner.scan_until(next_publish_time).await
+            }
+        };
+        let mut readings = if USE_MEDIAN {
+            stats.aggregate_median()
+        } else {
+            stats.aggregate()
+        };
+        let notices = check_offline(&readings, &mut last_seen, &mut missed_scans);
+        crate::led::set_state(LedState::Idle);
+        drop_unreliable(&mut readings);
+        if let Some(factor) = SMOOTHING_FACTOR {
+            smooth(&mut readings, &mut averages, factor);
+        }
+        if let Some(time_constant) = TEMPERATURE_TIME_CONSTANT {
+            filter_temperature(&mut readings, &mut filtered_temperatures, time_constant);
+        }
+        if CSV_OUTPUT {
+            print_csv(&readings);
+        }
+        add_original_gravity(&mut readings, &last_gravity);
+        retain_readings(&readings);
+        watchdog.feed();
+        crate::status::record_scan();
+
+        // Post the data using the WiFi connection. The readings, notices, and
+        // heartbeats are queued together, so the publishing task signals once
+        // for the whole cycle. A Tilt only has a notice when it is missing
+        // from the readings, so they don't overlap.
+        let has_data = readings.iter().any(Option::is_some);
+        let post = has_data && (!POST_ON_CHANGE || should_post(&readings, &mut last_posted, &mut n_suppressed));
+        let mut to_queue: TiltReadings = if post { readings } else { [None; TiltColor::COUNT] };
+        if !post && has_data {
+            // Keep the status page up to date, even though nothing is posted
+            crate::status::record_readings(Instant::now(), &readings);
+        }
+
+        if POST_OFFLINE_NOTICE {
+            merge_notices(&mut to_queue, &notices);
+        }
+
+        if HEARTBEAT_ENABLED && !has_data {
+            let heartbeats = last_seen.map(|last| last.map(|tilt_data| tilt_data.as_notice(DataKind::Heartbeat)));
+            merge_notices(&mut to_queue, &heartbeats);
+        }
+
+        let queued = to_queue.iter().any(Option::is_some);
+        if queued {
+            crate::wifi::queue_readings(Instant::now(), to_queue);
+        }
+
+        if let Some(max_cycles) = MAX_UNPUBLISHED_CYCLES {
+            check_publishing(queued, &mut last_published, &mut n_unpublished, max_cycles);
+        }
+
+        publish_interval = next_interval(publish_interval, &readings, &mut last_gravity, fixed_interval);
+
+        if WIFI_IDLE_BETWEEN_POSTS && !DEEP_SLEEP {
+            wait_for_publish(queued).await;
+            watchdog.feed();
+            crate::wifi::go_idle();
+        }
+
+        if DEEP_SLEEP {
+            deep_sleep(&mut rtc, &mut delay, queued, publish_interval, runtime_before_boot).await;
+        }
+
+        next_publish_time += publish_interval;
+    }
+}
+
+/// Adds each of the `notices` to `readings`, for the Tilts that don't already
+/// have a reading.
+fn merge_notices(readings: &mut TiltReadings, notices: &TiltReadings) {
+    for (reading, notice) in readings.iter_mut().zip(notices) {
+        if reading.is_none() {
+            *reading = *notice;
+        }
+    }
+}
+
+/// Updates how many scans in a row each Tilt has been missing from, given the
+/// `readings` from the latest scan. Returns a notice for each Tilt that has just
+/// gone offline, which is its `last_seen` data as an offline notice.
+fn check_offline(
+    readings: &TiltReadings,
+    last_seen: &mut TiltReadings,
+    missed_scans: &mut [u32; TiltColor::COUNT],
+) -> TiltReadings {
+    let mut notices: TiltReadings = [None; TiltColor::COUNT];
+
+    for (i, reading) in readings.iter().enumerate() {
+        if let Some(tilt_data) = reading {
+            if missed_scans[i] >= OFFLINE_SCANS {
+                info!("{} Tilt is back online", tilt_data.color().name());
+            }
+
+            missed_scans[i] = 0;
+            last_seen[i] = *reading;
+        } else if let Some(tilt_data) = last_seen[i] {
+            missed_scans[i] += 1;
+
+            if missed_scans[i] == OFFLINE_SCANS {
+                error!(
+                    "No data from {} Tilt for {} scans, it may be out of range or its battery may be dead",
+                    tilt_data.color().name(),
+                    OFFLINE_SCANS,
+                );
+                notices[i] = Some(tilt_data.as_notice(DataKind::Offline));
+            }
+        }
+    }
+
+    crate::led::set_tilt_offline(missed_scans.iter().any(|&n| n >= OFFLINE_SCANS));
+
+    notices
+}
+
+/// Removes the data from `readings` that was aggregated from fewer than
+/// MIN_SAMPLES readings.
+fn drop_unreliable(readings: &mut TiltReadings) {
+    for reading in readings.iter_mut() {
+        if let Some(tilt_data) = reading {
+            if tilt_data.n_samples() < MIN_SAMPLES {
+                warn!(
+                    "Only {} readings from {} Tilt, not posting",
+                    tilt_data.n_samples(),
+                    tilt_data.color().name(),
+                );
+                *reading = None;
+            }
+        }
+    }
+}
+
+/// Prints a CSV line over serial for each Tilt in `readings`, with the fields
+/// in CSV_HEADER.
+fn print_csv(readings: &TiltReadings) {
+    let uptime_ms = Instant::now().as_millis();
+
+    for tilt_data in readings.iter().flatten() {
+        esp_println::print!(
+            "{},{},{},",
+            uptime_ms,
+            tilt_data.temperature_str(&mut [0u8; 6]),
+            tilt_data.gravity_str(&mut [0u8; 6]),
+        );
+
+        if let Some(battery) = tilt_data.battery() {
+            esp_println::print!("{}", battery);
+        }
+
+        esp_println::println!(
+            ",{},{},{}",
+            tilt_data.n_samples(),
+            tilt_data.rssi(),
+            tilt_data.color().name(),
+        );
+    }
+}
+
+/// Updates the moving `averages` of each Tilt's temperature and gravity with
+/// the `readings`, weighting them by `factor`, and replaces the readings with
+/// the averages. A Tilt's average starts at its first reading.
+fn smooth(readings: &mut TiltReadings, averages: &mut [Option<(f32, f32)>; TiltColor::COUNT], factor: f32) {
+    for (reading, average) in readings.iter_mut().zip(averages.iter_mut()) {
+        let tilt_data = match *reading {
+            Some(tilt_data) => tilt_data,
+            None => continue,
+        };
+
+        let (temperature, gravity) = (tilt_data.temperature() as f32, tilt_data.gravity() as f32);
+        let (average_temperature, average_gravity) = match *average {
+            Some((t, g)) => (t + factor * (temperature - t), g + factor * (gravity - g)),
+            None => (temperature, gravity),
+        };
+        *average = Some((average_temperature, average_gravity));
+
+        // Both are positive, so adding a half rounds them
+        *reading = Some(tilt_data.with_smoothed((average_temperature + 0.5) as u16, (average_gravity + 0.5) as u16));
+    }
+}
+
+/// Low-pass filters the temperature of each Tilt in `readings` with the given
+/// `time_constant`, updating its `filtered` temperature, and replaces the
+/// readings' temperatures with the filtered ones. The weight of each new reading
+/// depends on the time since the last, so a varying publish interval doesn't
+/// change how quickly the filter follows. A Tilt's filter starts at its first
+/// reading.
+fn filter_temperature(
+    readings: &mut TiltReadings,
+    filtered: &mut [Option<(f32, Instant)>; TiltColor::COUNT],
+    time_constant: Duration,
+) {
+    let now = Instant::now();
+
+    for (reading, filtered) in readings.iter_mut().zip(filtered.iter_mut()) {
+        let tilt_data = match *reading {
+            Some(tilt_data) => tilt_data,
+            None => continue,
+        };
+
+        let temperature = tilt_data.temperature() as f32;
+        let filtered_temperature = match *filtered {
+            Some((t, last_time)) => {
+                // The discrete form of an RC filter
+                let dt = (now - last_time).as_millis() as f32;
+                let weight = dt / (time_constant.as_millis() as f32 + dt);
+                t + weight * (temperature - t)
+            }
+            None => temperature,
+        };
+        *filtered = Some((filtered_temperature, now));
+
+        // The temperature is positive, so adding a half rounds it
+        *reading = Some(tilt_data.with_smoothed((filtered_temperature + 0.5) as u16, tilt_data.gravity()));
+    }
+}
+
+/// Adds the original gravity of each Tilt to its data in `readings`, so the
+/// attenuation can be posted. If the original gravity hasn't been captured yet,
+/// it is the first gravity that is stable compared to the `last_gravity`.
+fn add_original_gravity(readings: &mut TiltReadings, last_gravity: &[Option<u16>; TiltColor::COUNT]) {
+    // Safe since this is only called from the relay task
+    let mut retained = unsafe { RETAINED_ORIGINAL_GRAVITY };
+    if retained.check != RetainedGravity::checksum(&retained.gravity) {
+        retained.gravity = [0; TiltColor::COUNT];
+    }
+
+    let mut captured = false;
+
+    for ((reading, last), original_gravity) in readings.iter_mut()
+        .zip(last_gravity.iter())
+        .zip(retained.gravity.iter_mut())
+    {
+        let tilt_data = match *reading {
+            Some(tilt_data) => tilt_data,
+            None => continue,
+        };
+
+        if let Some(configured) = ORIGINAL_GRAVITY {
+            *reading = Some(tilt_data.with_original_gravity(configured));
+            continue;
+        }
+
+        let stable = last.map_or(false, |last| tilt_data.gravity().abs_diff(last) < STABLE_GRAVITY_THRESHOLD);
+        if *original_gravity == 0 && stable && tilt_data.gravity() > GRAVITY_OF_WATER {
+            *original_gravity = tilt_data.gravity();
+            captured = true;
+            info!(
+                "Original gravity of {} Tilt is {}",
+                tilt_data.color().name(),
+                tilt_data.gravity_str(&mut [0u8; 6]),
+            );
+        }
+
+        if *original_gravity != 0 {
+            *reading = Some(tilt_data.with_original_gravity(*original_gravity));
+        }
+    }
+
+    if captured {
+        retained.check = RetainedGravity::checksum(&retained.gravity);
+        unsafe { RETAINED_ORIGINAL_GRAVITY = retained };
+    }
+}
+
+/// Records the readings retained in RTC memory before the relay reset in the
+/// status, where they are shown as stale until the Tilts are scanned again.
+fn load_retained_readings() {
+    // Safe since this is only called from the relay task
+    let retained = unsafe { RETAINED_READINGS };
+    if retained.check != RetainedReadings::checksum(&retained.readings) {
+        return;
+    }
+
+    let readings = retained.readings.map(|bytes| TiltData::from_bytes(&bytes));
+    let n_readings = readings.iter().flatten().count();
+    if n_readings > 0 {
+        info!("Loaded {} readings from before the last reset", n_readings);
+        crate::status::record_retained_readings(&readings);
+    }
+}
+
+/// Keeps the latest reading of each Tilt in `readings` in RTC memory, so it can
+/// be shown after a reset. Tilts without one keep their previous reading.
+fn retain_readings(readings: &TiltReadings) {
+    if readings.iter().all(Option::is_none) {
+        return;
+    }
+
+    // Safe since this is only called from the relay task
+    let mut retained = unsafe { RETAINED_READINGS };
+    if retained.check != RetainedReadings::checksum(&retained.readings) {
+        retained.readings = [[0; SERIALIZED_LENGTH]; TiltColor::COUNT];
+    }
+
+    for (bytes, reading) in retained.readings.iter_mut().zip(readings.iter()) {
+        if let Some(tilt_data) = reading {
+            *bytes = tilt_data.to_bytes();
+        }
+    }
+
+    retained.check = RetainedReadings::checksum(&retained.readings);
+    unsafe { RETAINED_READINGS = retained };
+}
+
+/// Returns whether the `readings` should be posted for POST_ON_CHANGE, which is
+/// when any Tilt's reading changed by at least the deltas since it was in
+/// `last_posted`, or after MAX_SUPPRESSED_POSTS windows in a row weren't
+/// posted. `last_posted` and `n_suppressed` are updated to match.
+fn should_post(readings: &TiltReadings, last_posted: &mut TiltReadings, n_suppressed: &mut u32) -> bool {
+    let changed = readings.iter().zip(last_posted.iter()).any(|(reading, last)| match (reading, last) {
+        (Some(reading), Some(last)) => {
+            reading.temperature().abs_diff(last.temperature()) >= POST_ON_CHANGE_TEMPERATURE_DELTA
+                || reading.gravity().abs_diff(last.gravity()) >= POST_ON_CHANGE_GRAVITY_DELTA
+        }
+        (Some(_), None) => true,
+        (None, _) => false,
+    });
+
+    if !changed && *n_suppressed < MAX_SUPPRESSED_POSTS {
+        *n_suppressed += 1;
+        info!("Readings haven't changed, not posting ({} in a row)", n_suppressed);
+        return false;
+    }
+
+    *n_suppressed = 0;
+    for (last, reading) in last_posted.iter_mut().zip(readings.iter()) {
+        if reading.is_some() {
+            *last = *reading;
+        }
+    }
+
+    true
+}
+
+/// Checks that the publishing task is still finishing with the readings queued
+/// for it, and panics to reset the relay if it hasn't for `max_cycles` cycles
+/// that `queued` readings in a row. `last_published` and `n_unpublished` track
+/// this across cycles. This is checked after queueing, so the readings from this
+/// cycle are never expected to be published yet.
+fn check_publishing(queued: bool, last_published: &mut Option<Instant>, n_unpublished: &mut u32, max_cycles: u32) {
+    let last = crate::wifi::last_published();
+    let published = last.map(|(time, _)| time);
+    if published != *last_published {
+        *last_published = published;
+        *n_unpublished = 0;
+    }
+
+    if !queued {
+        return;
+    }
 
-        // Scan for the data over Bluetooth LE
-        let tilt_data = tilt_scanner.scan_until(next_publish_time).await;
-        
-        // Post the data using the WiFi connection
-        if let Some(data) = tilt_data {
-            crate::wifi::DATA_SIGNAL.signal(data);
+    *n_unpublished += 1;
+    if *n_unpublished > max_cycles {
+        match last {
+            Some((time, posted)) => error!(
+                "Nothing published for the last {} cycles, the last readings finished {} seconds ago ({})",
+                max_cycles,
+                (Instant::now() - time).as_secs(),
+                if posted { "posted" } else { "not all posted" },
+            ),
+            None => error!("Nothing published for the last {} cycles, or since boot", max_cycles),
         }
+        panic!("The publishing task is stuck, panicking to induce a reset...");
+    }
+}
+
+/// Returns the interval until the next post. This doubles the current
+/// `interval` if the gravity in the `readings` is stable compared to the
+/// `last_gravity` of each Tilt, and returns to the minimum if it isn't. The
+/// interval is unchanged if there is nothing to compare. `last_gravity` is
+/// updated with the new readings. If there is a `fixed` interval, it is always
+/// returned instead.
+fn next_interval(
+    interval: Duration,
+    readings: &TiltReadings,
+    last_gravity: &mut [Option<u16>; TiltColor::COUNT],
+    fixed: Option<Duration>,
+) -> Duration {
+    let mut stable = None;
+
+    for (reading, last) in readings.iter().zip(last_gravity.iter_mut()) {
+        if let Some(reading) = reading {
+            if let Some(last) = last {
+                let change = reading.gravity().abs_diff(*last);
+                stable = Some(stable.unwrap_or(true) && change < STABLE_GRAVITY_THRESHOLD);
+            }
+
+            *last = Some(reading.gravity());
+        }
+    }
+
+    let next = match (fixed, stable) {
+        (Some(fixed), _) => fixed,
+        (None, Some(true)) => (interval * 2).min(MAX_PUBLISH_INTERVAL),
+        (None, Some(false)) => MIN_PUBLISH_INTERVAL,
+        (None, None) => interval,
+    };
+
+    if next != interval {
+        info!("Publish interval is now {} minutes", next.as_secs() / 60);
+    }
+
+    next
+}
+
+/// Acts on the commands received over MQTT since the last cycle. A valid
+/// interval from a command is stored in `fixed_interval`, and takes effect
+/// after the next post.
+fn handle_commands(tilt_scanner: &mut TiltScanner, fixed_interval: &mut Option<Duration>) {
+    while let Ok(command) = crate::mqtt::COMMAND_CHANNEL.try_receive() {
+        match command {
+            Command::Rescan => tilt_scanner.rescan(),
+            Command::Reboot => {
+                info!("Rebooting by command");
+                esp32c3_hal::reset::software_reset();
+                // Wait for the reset to occur
+                loop {}
+            }
+            Command::SetInterval(interval) => {
+                if (MIN_PUBLISH_INTERVAL..=MAX_PUBLISH_INTERVAL).contains(&interval) {
+                    info!("Publish interval fixed at {} seconds by command", interval.as_secs());
+                    *fixed_interval = Some(interval);
+                } else {
+                    warn!(
+                        "Ignoring a publish interval of {} seconds, it must be from {} to {} seconds",
+                        interval.as_secs(),
+                        MIN_PUBLISH_INTERVAL.as_secs(),
+                        MAX_PUBLISH_INTERVAL.as_secs(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Scans for `on` at a time, pausing for `off` in between, until
+/// `scan_end_time`. Returns the stats of all data received during the bursts.
+/// The `watchdog` is fed between the bursts.
+async fn scan_in_bursts(
+    tilt_scanner: &mut TiltScanner,
+    scan_end_time: Instant,
+    on: Duration,
+    off: Duration,
+    watchdog: &mut Wdt<TIMG0>,
+) -> TiltStats {
+    let mut scan = tilt_scanner.start_scan();
+
+    while Instant::now() < scan_end_time {
+        watchdog.feed();
+        crate::led::set_state(LedState::Scanning);
+        tilt_scanner.scan_burst(&mut scan, scan_end_time.min(Instant::now() + on)).await;
+        crate::led::set_state(LedState::Idle);
+
+        wait_until(scan_end_time.min(Instant::now() + off), watchdog).await;
+    }
+
+    tilt_scanner.finish_scan(scan)
+}
+
+/// Waits until `time`, feeding the `watchdog` along the way so it doesn't trip
+/// while the relay is idle.
+async fn wait_until(time: Instant, watchdog: &mut Wdt<TIMG0>) {
+    while Instant::now() < time {
+        watchdog.feed();
+        Timer::at(time.min(Instant::now() + WATCHDOG_FEED_INTERVAL)).await;
+    }
+}
+
+/// Returns how long the relay has been awake since it booted.
+fn uptime() -> Duration {
+    Duration::from_ticks(Instant::now().as_ticks())
+}
+
+/// Returns how long the relay ran before it last booted, from RTC memory. This
+/// is zero unless RUNTIME_SURVIVES_RESET is set, or if the runtime wasn't
+/// retained, e.g. after power on.
+fn runtime_before_boot() -> Duration {
+    if !RUNTIME_SURVIVES_RESET {
+        return Duration::ZERO;
+    }
+
+    // Safe since this is only called from the relay task
+    let retained = unsafe { RETAINED_RUNTIME };
+    if retained.check != RetainedRuntime::checksum(retained.secs) {
+        return Duration::ZERO;
+    }
+
+    Duration::from_secs(retained.secs)
+}
+
+/// Records the total `runtime` in RTC memory, so it can be picked up after a
+/// reset if RUNTIME_SURVIVES_RESET is set.
+fn retain_runtime(runtime: Duration) {
+    let secs = runtime.as_secs();
 
-        next_publish_time += PUBLISH_INTERVAL;
+    // Safe since this is only called from the relay task
+    unsafe {
+        RETAINED_RUNTIME = RetainedRuntime {
+            check: RetainedRuntime::checksum(secs),
+            secs,
+        };
     }
-}
\ No newline at end of file
+}
+
+/// Stops the relay for good once it has run for MAX_RUNTIME. WiFi is shut
+/// down and the device deep sleeps with nothing to wake it, so this never
+/// returns. A reset or power cycle starts it again.
+async fn stop(rtc: &mut Rtc<'static>, delay: &mut Delay, watchdog: &mut Wdt<TIMG0>, runtime: Duration) -> ! {
+    info!(
+        "Ran for {} hours, the maximum runtime. Stopping until the relay is reset.",
+        runtime.as_secs() / (60 * 60),
+    );
+
+    crate::wifi::shutdown().await;
+    watchdog.disable();
+    crate::led::set_state(LedState::Idle);
+
+    rtc.sleep_deep(&[], delay);
+}
+
+/// Waits up to MAX_PUBLISH_WAIT for the data to be posted, if there is any.
+async fn wait_for_publish(has_data: bool) {
+    if has_data && with_timeout(MAX_PUBLISH_WAIT, crate::wifi::PUBLISHED_SIGNAL.wait()).await.is_err() {
+        warn!("Timed out waiting for the data to be posted");
+    }
+}
+
+/// Waits for the data to be posted (if there is any), shuts down WiFi, then
+/// deep sleeps until it is time for the next scan, `publish_interval` after the
+/// device booted. The device restarts when it wakes up, so this never returns.
+/// That also means the publish interval starts from the minimum again. The
+/// time asleep counts towards MAX_RUNTIME, on top of `runtime_before_boot`.
+async fn deep_sleep(
+    rtc: &mut Rtc<'static>,
+    delay: &mut Delay,
+    has_data: bool,
+    publish_interval: Duration,
+    runtime_before_boot: Duration,
+) -> ! {
+    wait_for_publish(has_data).await;
+    crate::wifi::shutdown().await;
+
+    // Instant starts from zero at boot, so now() is how long we've been awake.
+    // Sleep for the rest of the publish interval so that each wake up is one
+    // publish interval after the last.
+    let sleep_duration = if Instant::now().as_ticks() < publish_interval.as_ticks() {
+        publish_interval - Duration::from_ticks(Instant::now().as_ticks())
+    } else {
+        Duration::ZERO
+    };
+    let sleep_duration = sleep_duration.max(MIN_SLEEP_DURATION);
+
+    info!("Deep sleeping for {} seconds", sleep_duration.as_secs());
+
+    if MAX_RUNTIME.is_some() {
+        retain_runtime(runtime_before_boot + uptime() + sleep_duration);
+    }
+
+    let timer = TimerWakeupSource::new(core::time::Duration::from_millis(sleep_duration.as_millis()));
+    rtc.sleep_deep(&[&timer], delay);
+}
@@ -1,10 +1,11 @@
-use embassy_time::Instant;
+use embassy_time::{block_for, Duration, Instant, Timer};
 use embedded_io::blocking::Write;
 use esp32c3_hal::radio::Bluetooth;
-use esp_wifi::ble::controller::BleConnector;
-use log::{info, warn};
+use esp_wifi::ble::controller::{BleConnector, BleConnectorError};
+use heapless::Vec;
+use log::{error, info, warn};
 
-use crate::tilt::{TiltData, TiltPacket, TiltStats};
+use crate::tilt::{AdvertisingReports, TiltAddress, TiltColor, TiltPacket, TiltStats};
 
 const PACKET_HEADER_LENGTH: usize = 4;
 const PACKET_TYPE_COMMAND: u8 = 0x01;
@@ -15,154 +16,668 @@ const OPCODE_SET_EVENT_MASK: u16 = 0x0C01;
 const OPCODE_LE_SET_EVENT_MASK: u16 = 0x2001;
 const OPCODE_SET_SCAN_PARAMS: u16 = 0x200B;
 const OPCODE_SET_SCAN_ENABLE: u16 = 0x200C;
+const OPCODE_CLEAR_WHITELIST: u16 = 0x2010;
 const OPCODE_ADD_TO_WHITELIST: u16 = 0x2011;
 
 const EVENT_COMMAND_COMPLETE: u8 = 0x0E;
-
+/// Sent instead of a Command Complete Event for a command the controller
+/// doesn't know, e.g. a vendor-specific one
+const EVENT_COMMAND_STATUS: u8 = 0x0F;
+/// Every event has the length of its parameters after the event code
+const EVENT_PARAMS_LENGTH_INDEX: usize = 2;
+
+/// The longest HCI event: the packet type, event code and parameter length,
+/// then up to 255 bytes of parameters. A Tilt's advertising report event is
+/// only 45 bytes.
+const MAX_EVENT_LENGTH: usize = 3 + 255;
+/// Events are read into a buffer this big. esp-wifi can return more than one
+/// event from a read (see esp-wifi#174), so there is room for two of the
+/// longest events.
+const EVENT_BUFFER_SIZE: usize = 2 * MAX_EVENT_LENGTH;
+
+/// The most distinct advertisers the self test counts
+const MAX_SELF_TEST_ADVERTISERS: usize = 64;
+
+/// Log every Bluetooth advertiser nearby when the relay starts, with its
+/// strongest RSSI and whether it is a Tilt, e.g. to check that a new location's
+/// Tilt is the closest. This scans for ADVERTISER_INVENTORY_DURATION before
+/// looking for the Tilts, so it delays startup by that long.
+const ADVERTISER_INVENTORY: bool = false;
+const ADVERTISER_INVENTORY_DURATION: Duration = Duration::from_secs(30);
+/// The most distinct advertisers the inventory lists. Any more are counted.
+const MAX_INVENTORY_ADVERTISERS: usize = 32;
+
+/// How many times to try an HCI command before giving up and resetting
+const MAX_CMD_ATTEMPTS: u32 = 3;
+/// How long to wait before retrying a failed HCI command
+const CMD_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How often the controller starts a scan window, in milliseconds.
+const SCAN_INTERVAL_MS: u32 = 30;
+/// How long the controller listens in each interval, in milliseconds. A window
+/// shorter than the interval keeps the radio off for the rest of the interval,
+/// which saves power for battery operation at the cost of missing some
+/// broadcasts. It can't be longer than the interval.
+const SCAN_WINDOW_MS: u32 = 30;
 /// Interval and window are in units of the BLE timing unit of 0.625 milliseconds.
 /// 30 milliseconds / .625 happens to be 0x30 in hexidecimal.
-const SCAN_PARAM_SCAN_INTERVAL: u16 = 0x0030;
-const SCAN_PARAM_SCAN_WINDOW: u16 = SCAN_PARAM_SCAN_INTERVAL;
+const SCAN_TIMING_UNIT_US: u32 = 625;
+const SCAN_PARAM_SCAN_INTERVAL: u16 = (SCAN_INTERVAL_MS * 1000 / SCAN_TIMING_UNIT_US) as u16;
+const SCAN_PARAM_SCAN_WINDOW: u16 = (SCAN_WINDOW_MS * 1000 / SCAN_TIMING_UNIT_US) as u16;
+/// The BLE spec limits both to between 2.5 milliseconds and 10.24 seconds
+const SCAN_PARAM_MIN: u32 = 0x0004;
+const SCAN_PARAM_MAX: u32 = 0x4000;
+
+const _: () = assert!(SCAN_INTERVAL_MS * 1000 / SCAN_TIMING_UNIT_US >= SCAN_PARAM_MIN);
+const _: () = assert!(SCAN_INTERVAL_MS * 1000 / SCAN_TIMING_UNIT_US <= SCAN_PARAM_MAX);
+const _: () = assert!(SCAN_WINDOW_MS * 1000 / SCAN_TIMING_UNIT_US >= SCAN_PARAM_MIN);
+const _: () = assert!(SCAN_WINDOW_MS <= SCAN_INTERVAL_MS, "The scan window can't be longer than the interval");
+
+/// Passive scanning only listens for advertisements
+const SCAN_PARAM_TYPE_PASSIVE: u8 = 0x00;
+/// Active scanning also asks each advertiser for its scan response data
+const SCAN_PARAM_TYPE_ACTIVE: u8 = 0x01;
+/// Whether to scan actively, to get the scan response data that some Tilt
+/// variants and clones send. The radio transmits the scan requests, so this
+/// draws more power than passive scanning.
+const ACTIVE_SCAN: bool = false;
 /// No filtering
 const SCAN_PARAM_FILTER_ALLOW_ALL: u8 = 0x00;
 /// Only report events for addresses that have been added to the list
 const SCAN_PARAM_FILTER_ALLOW_LISTED: u8 = 0x01;
 
-/// Handles Bluetooth LE scanning for the Tilt. It supports a single Tilt.
+/// Which of the advertising channels to scan, e.g. to avoid one that is jammed
+/// by interference. Bit 0 is channel 37, bit 1 is 38 and bit 2 is 39, the same
+/// as the advertising channel map in standard HCI.
+const SCAN_CHANNEL_MASK: u8 = ALL_ADVERTISING_CHANNELS;
+const ALL_ADVERTISING_CHANNELS: u8 = 0b111;
+const _: () = assert!(
+    SCAN_CHANNEL_MASK != 0 && SCAN_CHANNEL_MASK & !ALL_ADVERTISING_CHANNELS == 0,
+    "Scan at least one advertising channel, and only those",
+);
+/// Standard HCI can't select the channels to scan, so SCAN_CHANNEL_MASK is sent
+/// with this vendor-specific command, which takes the mask as its only
+/// parameter. Whether there is such a command, and its opcode, depends on the
+/// controller's firmware, so it must be looked up for the controller in use.
+/// Vendor-specific opcodes are 0xFC00 plus the command number. If this is None,
+/// or the controller rejects the command, all channels are scanned.
+const OPCODE_VENDOR_SET_SCAN_CHANNELS: Option<u16> = None;
+
+/// The most Tilts that can be tracked at once, one for each color.
+const MAX_TILTS: usize = TiltColor::COUNT;
+/// How long to keep looking for other Tilts after the first one is found.
+/// Tilts broadcast every few seconds, so this is plenty to find all of them.
+const DISCOVERY_DURATION: Duration = Duration::from_secs(30);
+/// How long `init` waits for the first Tilt. If none is found by then, e.g.
+/// because it isn't in the wort yet, the relay starts anyway and keeps looking
+/// for Tilts during each scan until one is found.
+const INIT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to sleep when the controller has no events, before checking again.
+/// The controller queues events until they are read, so this only delays them
+/// slightly, and the CPU can idle between checks instead of spinning for the
+/// whole scan. Tilts broadcast about once a second, so the CPU is idle almost
+/// all of the time.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How many scans in a row can go without any Tilt data before looking for the
+/// Tilts again. A Tilt can come back with a new address after a battery swap,
+/// which the allow list would filter out forever.
+const MAX_EMPTY_SCANS: u32 = 2;
+/// The address of the Tilt to scan for, to skip discovering it at startup, e.g.
+/// for a fixed installation. The scanner then never looks for other Tilts. The
+/// 7 bytes are as logged when a Tilt is found: the address type (0x00 public,
+/// 0x01 random), then the 6-byte address in little endian order, which is the
+/// reverse of how it is usually written. For example AA:BB:CC:DD:EE:FF, a
+/// public address, is [0x00, 0xFF, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA]. None
+/// discovers the Tilts by scanning.
+const TILT_ADDRESS: Option<TiltAddress> = None;
+/// Ignore Tilt broadcasts weaker than this many dBm, e.g. Some(-80), so a
+/// neighbor's Tilt of the same color isn't picked up instead of the nearby one.
+/// A scan where every broadcast was too weak is logged, in case this is set too
+/// high. None accepts every broadcast.
+const MIN_RSSI: Option<i8> = None;
+
+/// The ways an HCI command can fail
+#[derive(Debug)]
+enum HciError {
+    /// The command couldn't be written to the controller
+    Write(BleConnectorError),
+    /// The response couldn't be read from the controller
+    Read(BleConnectorError),
+    /// A Command Complete Event arrived for a command other than the one sent
+    UnexpectedOpcode(u16),
+    /// The controller completed the command with a non-zero status
+    CommandFailed(u8),
+}
+
+/// A scan in progress, which can be split into several bursts. Created by
+/// `TiltScanner::start_scan`.
+pub struct Scan {
+    stats: TiltStats,
+    n_packets: u32,
+    // The packets ignored for being weaker than MIN_RSSI
+    n_weak_packets: u32,
+    // Whether the scan is also looking for the Tilts' addresses, and the
+    // addresses from before it started
+    rediscover: bool,
+    old_addresses: ([TiltAddress; MAX_TILTS], usize),
+}
+
+/// Handles Bluetooth LE scanning for Tilts. It supports one Tilt of each color.
 pub struct TiltScanner {
     ble: BleConnector<'static>,
+    addresses: [TiltAddress; MAX_TILTS],
+    n_addresses: usize,
+    n_empty_scans: u32,
+    // Whether the next scan should look for the Tilts again, see `rescan`
+    rescan_requested: bool,
 }
 
 impl TiltScanner {
     pub fn new(bluetooth: Bluetooth) -> Self {
-        Self { ble: BleConnector::new(bluetooth) }
+        Self {
+            ble: BleConnector::new(bluetooth),
+            addresses: [[0u8; 7]; MAX_TILTS],
+            n_addresses: 0,
+            n_empty_scans: 0,
+            rescan_requested: false,
+        }
+    }
+
+    /// Makes the next scan look for the Tilts again, as if it had no Tilt data
+    /// for MAX_EMPTY_SCANS scans. This has no effect if TILT_ADDRESS is set.
+    pub fn rescan(&mut self) {
+        if TILT_ADDRESS.is_some() {
+            warn!("Not looking for Tilts again, the Tilt address is configured");
+            return;
+        }
+
+        self.rescan_requested = true;
     }
 
-    /// Initializes the scanner. This includes an initial scan for a Tilt device
-    /// to get its address. This initial scan will continue until a Tilt is
-    /// detected, so it will not return if there is no tranmitting Tilt nearby.
+    /// Initializes the scanner. This includes an initial scan for Tilt devices
+    /// to get their addresses. This initial scan continues until a Tilt is
+    /// detected or INIT_DISCOVERY_TIMEOUT passes. Once a Tilt is detected, the
+    /// scan continues for DISCOVERY_DURATION to find any other Tilts. If none
+    /// is found, the scanner is left searching, see `is_searching`.
+    /// If TILT_ADDRESS is set, there is no initial scan, only that Tilt is
+    /// scanned for.
     pub fn init(&mut self) {
-        self.write_cmd(&hci_reset());
+        self.send_cmd(&hci_reset());
         info!("Reset bluetooth");
 
-        self.write_cmd(&hci_set_event_mask());
-        self.write_cmd(&hci_le_set_event_mask());
+        self.send_cmd(&hci_set_event_mask());
+        self.send_cmd(&hci_le_set_event_mask());
         info!("Filtering unwanted events");
 
-        self.write_cmd(&hci_le_set_scan_params(false));
+        if ADVERTISER_INVENTORY {
+            self.log_advertisers(ADVERTISER_INVENTORY_DURATION);
+        }
+
+        if let Some(address) = TILT_ADDRESS {
+            self.set_scan_channels();
+
+            info!("Using the configured Tilt address, skipping discovery");
+            self.add_address(&address);
+            self.update_white_list();
+
+            self.send_cmd(&hci_le_set_scan_params(true, ACTIVE_SCAN));
+            info!("Set scan params: filter all but allowed, allow duplicates");
+            return;
+        }
+
+        self.send_cmd(&hci_le_set_scan_params(false, ACTIVE_SCAN));
         info!("Set scan params: allow all, filter duplicates");
+
+        self.set_scan_channels();
     
         info!("Scan for a Tilt device...");
-        self.write_cmd(&hci_le_set_scan_enable(true, true));
+        self.send_cmd(&hci_le_set_scan_enable(true, true));
         info!("Scan enabled");
         
-        let tilt = self.find_tilt();
+        self.find_tilts(Instant::now() + INIT_DISCOVERY_TIMEOUT);
+        if self.is_searching() {
+            warn!("No Tilt found, will keep looking during each scan");
+        }
 
-        self.write_cmd(&hci_le_set_scan_enable(false, true));
+        self.send_cmd(&hci_le_set_scan_enable(false, true));
         info!("Scan disabled");
     
-        self.write_cmd(&hci_le_add_to_white_list(&tilt));
-        info!("Added address to allow list: {:02X?}", &tilt.address());
+        self.update_white_list();
 
-        self.write_cmd(&hci_le_set_scan_params(true));
+        self.send_cmd(&hci_le_set_scan_params(true, ACTIVE_SCAN));
         info!("Set scan params: filter all but allowed, allow duplicates");
     }
 
-    /// Scans for data from the Tilt until `scan_end_time`. Returns the
-    /// aggregate of all Tilt data received during that period, or None if no
-    /// data was received.
-    pub async fn scan_until(&mut self, scan_end_time: Instant) -> Option<TiltData> {
-        self.write_cmd(&hci_le_set_scan_enable(true, false));
+    /// Checks that the Bluetooth controller works, for the self test. This
+    /// resets the controller, confirming each command completes, then scans
+    /// without the allow list for `duration`. Returns the number of distinct
+    /// advertisers seen, up to MAX_SELF_TEST_ADVERTISERS, or None if a command
+    /// failed. Unlike the other commands, failures are logged rather than
+    /// causing a panic. `init` must still be called afterwards.
+    pub fn self_test(&mut self, duration: Duration) -> Option<usize> {
+        let commands: [&[u8]; 4] = [
+            &hci_reset(),
+            &hci_set_event_mask(),
+            &hci_le_set_event_mask(),
+            &hci_le_set_scan_params(false, ACTIVE_SCAN),
+        ];
+
+        for command in commands {
+            if let Err(e) = self.write_cmd(command) {
+                error!("Self test: HCI command {:02X?} failed: {:?}", command, e);
+                return None;
+            }
+        }
+        info!("Self test: Bluetooth reset");
+
+        if let Err(e) = self.write_cmd(&hci_le_set_scan_enable(true, true)) {
+            error!("Self test: enabling the scan failed: {:?}", e);
+            return None;
+        }
+
+        let mut advertisers: Vec<TiltAddress, MAX_SELF_TEST_ADVERTISERS> = Vec::new();
+        let mut n_tilts = 0;
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
+        let end_time = Instant::now() + duration;
+
+        while Instant::now() < end_time {
+            match self.ble.get_next(&mut buffer) {
+                Err(e) => {
+                    warn!("Read error: {:?}", e);
+                }
+                Ok(0) => {
+                    block_for(EVENT_POLL_INTERVAL);
+                }
+                Ok(len) => {
+                    for report in AdvertisingReports::parse_all(&buffer[..len]) {
+                        let address = report.address();
+                        if advertisers.contains(&address) {
+                            continue;
+                        }
+
+                        if report.parse_tilt().is_some() {
+                            n_tilts += 1;
+                        }
+
+                        // Once full, the count is just a lower bound
+                        advertisers.push(address).ok();
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = self.write_cmd(&hci_le_set_scan_enable(false, true)) {
+            error!("Self test: disabling the scan failed: {:?}", e);
+            return None;
+        }
+
+        info!("Self test: saw {} advertisers, {} of them Tilts", advertisers.len(), n_tilts);
+        Some(advertisers.len())
+    }
+
+    /// Scans without the allow list for `duration`, then logs each distinct
+    /// advertiser seen with its strongest RSSI and whether it is a Tilt, the
+    /// strongest first. Duplicates aren't filtered, so the strongest RSSI is
+    /// from all of an advertiser's broadcasts.
+    fn log_advertisers(&mut self, duration: Duration) {
+        self.send_cmd(&hci_le_set_scan_params(false, ACTIVE_SCAN));
+        self.send_cmd(&hci_le_set_scan_enable(true, false));
+        info!("Listing nearby advertisers for {} seconds...", duration.as_secs());
+
+        // Each advertiser's address, strongest RSSI, and color if it is a Tilt
+        let mut advertisers: Vec<(TiltAddress, i8, Option<TiltColor>), MAX_INVENTORY_ADVERTISERS> = Vec::new();
+        let mut n_unlisted = 0u32;
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
+        let end_time = Instant::now() + duration;
+
+        while Instant::now() < end_time {
+            match self.ble.get_next(&mut buffer) {
+                Err(e) => {
+                    warn!("Read error: {:?}", e);
+                }
+                Ok(0) => {
+                    block_for(EVENT_POLL_INTERVAL);
+                }
+                Ok(len) => {
+                    for report in AdvertisingReports::parse_all(&buffer[..len]) {
+                        let address = report.address();
+                        let color = report.parse_tilt().map(|packet| packet.data().color());
+
+                        match advertisers.iter_mut().find(|(known, _, _)| *known == address) {
+                            Some((_, rssi, known_color)) => {
+                                *rssi = (*rssi).max(report.rssi());
+                                *known_color = known_color.or(color);
+                            }
+                            None => {
+                                if advertisers.push((address, report.rssi(), color)).is_err() {
+                                    n_unlisted += 1;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        self.send_cmd(&hci_le_set_scan_enable(false, false));
+
+        advertisers.sort_unstable_by_key(|(_, rssi, _)| -(*rssi as i16));
+        info!("Saw {} advertisers:", advertisers.len());
+        for (address, rssi, color) in advertisers.iter() {
+            match color {
+                Some(color) => info!("  {:02X?} {} dBm, {} Tilt", address, rssi, color.name()),
+                None => info!("  {:02X?} {} dBm", address, rssi),
+            }
+        }
+
+        if n_unlisted > 0 {
+            info!("  and {} more reports from advertisers that didn't fit in the list", n_unlisted);
+        }
+    }
+
+    /// Returns whether no Tilt has been found yet, so every scan looks for them.
+    pub fn is_searching(&self) -> bool {
+        self.n_addresses == 0
+    }
+
+    /// Scans for data from the Tilts until `scan_end_time`. Returns the stats
+    /// of all data received from each Tilt during that period, which can then
+    /// be aggregated.
+    /// This is a scan with a single burst, see `start_scan`.
+    pub async fn scan_until(&mut self, scan_end_time: Instant) -> TiltStats {
+        let mut scan = self.start_scan();
+        self.scan_burst(&mut scan, scan_end_time).await;
+        self.finish_scan(scan)
+    }
+
+    /// Starts a scan for data from the Tilts, which is done in one or more
+    /// bursts with `scan_burst`, and then finished with `finish_scan`.
+    /// If the last MAX_EMPTY_SCANS scans had no Tilt data, this scan also looks
+    /// for the Tilts again, in case their addresses changed. It also looks for
+    /// them while the scanner is searching, since no Tilt has been found yet.
+    /// A configured TILT_ADDRESS is never looked for again. `rescan` forces
+    /// this scan to look for them.
+    pub fn start_scan(&mut self) -> Scan {
+        // Rediscover the Tilts by scanning without the allow list. The old
+        // addresses are kept in case no Tilts are found.
+        let rediscover = TILT_ADDRESS.is_none()
+            && (self.is_searching() || self.n_empty_scans >= MAX_EMPTY_SCANS || self.rescan_requested);
+        let old_addresses = (self.addresses, self.n_addresses);
+        if self.is_searching() {
+            info!("Searching for Tilts");
+        } else if self.rescan_requested {
+            info!("Rescan requested, looking for Tilts again");
+        } else if rediscover {
+            info!("No Tilt data in {} scans, looking for Tilts again", self.n_empty_scans);
+        }
+        if rediscover {
+            self.send_cmd(&hci_le_set_scan_params(false, ACTIVE_SCAN));
+            self.n_addresses = 0;
+        }
+        self.rescan_requested = false;
+
+        Scan {
+            stats: TiltStats::new(),
+            n_packets: 0,
+            n_weak_packets: 0,
+            rediscover,
+            old_addresses,
+        }
+    }
+
+    /// Scans until `burst_end_time`, adding the data received to the `scan`.
+    pub async fn scan_burst(&mut self, scan: &mut Scan, burst_end_time: Instant) {
+        self.send_cmd(&hci_le_set_scan_enable(true, false));
         info!("Scan enabled");
 
-        let mut stats = TiltStats::new();
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
 
-        while Instant::now() < scan_end_time {
-            if let Some(packet) = self.wait_for_tilt_event(scan_end_time).await {
-                stats.add(packet.data());
+        while Instant::now() < burst_end_time {
+            if let Some(len) = self.wait_for_tilt_event(burst_end_time, &mut buffer).await {
+                // An event can hold reports from more than one Tilt
+                for packet in TiltPacket::parse_all(&buffer[..len]) {
+                    if !is_strong_enough(&packet) {
+                        scan.n_weak_packets += 1;
+                        continue;
+                    }
+
+                    if scan.rediscover {
+                        self.add_address(packet.address());
+                    }
+
+                    scan.stats.add(packet.data());
+                    scan.n_packets += 1;
+                }
             }
         }
 
-        self.write_cmd(&hci_le_set_scan_enable(false, false));
+        self.send_cmd(&hci_le_set_scan_enable(false, false));
         info!("Scan disabled");
+    }
+
+    /// Finishes the `scan` and returns the stats of all data received from each
+    /// Tilt during its bursts, which can then be aggregated.
+    pub fn finish_scan(&mut self, scan: Scan) -> TiltStats {
+        if scan.rediscover {
+            if self.n_addresses == 0 {
+                warn!("No Tilts found, keeping the previous addresses");
+                (self.addresses, self.n_addresses) = scan.old_addresses;
+            }
+
+            self.update_white_list();
+            self.send_cmd(&hci_le_set_scan_params(true, ACTIVE_SCAN));
+        }
+
+        if scan.n_packets == 0 && scan.n_weak_packets > 0 {
+            warn!(
+                "All {} Tilt broadcasts were weaker than {} dBm and ignored",
+                scan.n_weak_packets,
+                MIN_RSSI.unwrap_or_default(),
+            );
+        }
+
+        if scan.n_packets == 0 {
+            self.n_empty_scans += 1;
+        } else {
+            self.n_empty_scans = 0;
+        }
     
-        stats.aggregate()
+        scan.stats
+    }
+
+    /// Replaces the addresses in the controller's allow list with the addresses
+    /// of the known Tilts. Scanning must be disabled.
+    fn update_white_list(&mut self) {
+        self.send_cmd(&hci_le_clear_white_list());
+
+        for i in 0..self.n_addresses {
+            let address = self.addresses[i];
+            self.send_cmd(&hci_le_add_to_white_list(&address));
+            info!("Added address to allow list: {:02X?}", &address);
+        }
+    }
+
+    /// Selects the advertising channels to scan with SCAN_CHANNEL_MASK, if
+    /// they aren't all selected. The controller is left scanning all of them
+    /// if it doesn't support the vendor command for this.
+    fn set_scan_channels(&mut self) {
+        let opcode = match OPCODE_VENDOR_SET_SCAN_CHANNELS {
+            Some(opcode) if SCAN_CHANNEL_MASK != ALL_ADVERTISING_CHANNELS => opcode,
+            _ => return,
+        };
+
+        match self.write_cmd(&hci_cmd_packet::<1>(opcode, [SCAN_CHANNEL_MASK])) {
+            Ok(()) => info!("Set scan channels: {:03b}", SCAN_CHANNEL_MASK),
+            Err(e) => warn!("Controller can't select the scan channels, scanning all of them: {:?}", e),
+        }
+    }
+
+    /// Writes the given HCI Command packet to the Bluetooth controller, retrying
+    /// up to MAX_CMD_ATTEMPTS times if it fails. Failures are often a momentary
+    /// hiccup in the controller, but if every attempt fails, this panics to
+    /// induce a reset.
+    fn send_cmd(&mut self, packet: &[u8]) {
+        for attempt in 1..=MAX_CMD_ATTEMPTS {
+            match self.write_cmd(packet) {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!("HCI command {:02X?} failed (attempt {}): {:?}", packet, attempt, e);
+                    block_for(CMD_RETRY_DELAY);
+                }
+            }
+        }
+
+        panic!("HCI command failed {} times, panicking to induce a reset...", MAX_CMD_ATTEMPTS);
     }
 
     /// Writes the given HCI Command packet to the Bluetooth controller. This
     /// waits for the HCI Command Complete Event packet from the controller
     /// to ensure it was fully processed with no errors.
-    fn write_cmd(&mut self, packet: &[u8]) {
+    fn write_cmd(&mut self, packet: &[u8]) -> Result<(), HciError> {
         let opcode_lsb = packet[1];
         let opcode_msb = packet[2];
 
-        self.ble.write_all(packet).unwrap();
-        self.ble.flush().unwrap();
+        self.ble.write_all(packet).map_err(HciError::Write)?;
+        self.ble.flush().map_err(HciError::Write)?;
         
         // Wait for a command complete event with the opcode we just sent
-        let mut buffer = [0u8; 256];
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
         loop {
-            let len = self.ble.get_next(&mut buffer).unwrap();
+            let len = self.ble.get_next(&mut buffer).map_err(HciError::Read)?;
+
+            // A read can hold more than one event, so check each of them
+            let mut events = &buffer[..len];
+            while events.len() > EVENT_PARAMS_LENGTH_INDEX && events[0] == PACKET_TYPE_EVENT {
+                // The length is computed as a usize so it can't overflow. If it
+                // runs past the end of the read, the length is corrupt, so the
+                // rest of the read is discarded and the next one is checked.
+                let event_length = EVENT_PARAMS_LENGTH_INDEX + 1 + events[EVENT_PARAMS_LENGTH_INDEX] as usize;
+                if event_length > events.len() {
+                    warn!("Malformed HCI event, {} bytes long but only {} were read", event_length, events.len());
+                    break;
+                }
 
-            if len != 7 || buffer[0] != PACKET_TYPE_EVENT || buffer[1] != EVENT_COMMAND_COMPLETE {
-                continue;
-            }
+                let (event, rest) = events.split_at(event_length);
+                events = rest;
+
+                // A Command Status Event with an error means the command was
+                // rejected outright. Its status comes before the opcode.
+                if event.len() == 7
+                    && event[1] == EVENT_COMMAND_STATUS
+                    && event[5] == opcode_lsb
+                    && event[6] == opcode_msb
+                    && event[3] != 0x00
+                {
+                    return Err(HciError::CommandFailed(event[3]));
+                }
 
-            // The 2-byte opcode should match the opcode for the command
-            // that was just written. If it doesn't, then some other command
-            // was issued without waiting for this event, which shouldn't
-            // happen since that's what we're doing now.
-            if buffer[4] != opcode_lsb || buffer[5] != opcode_msb {
-                panic!("Unhandled Command Complete Event: {:02X?}", &buffer)
-            }
+                if event.len() != 7 || event[1] != EVENT_COMMAND_COMPLETE {
+                    continue;
+                }
+
+                // The 2-byte opcode should match the opcode for the command
+                // that was just written. If it doesn't, then some other command
+                // was issued without waiting for this event, which shouldn't
+                // happen since that's what we're doing now.
+                if event[4] != opcode_lsb || event[5] != opcode_msb {
+                    return Err(HciError::UnexpectedOpcode(u16::from_le_bytes([event[4], event[5]])));
+                }
+
+                // The last byte is the exit code, with 0 indicating success
+                if event[6] != 0x00 {
+                    return Err(HciError::CommandFailed(event[6]));
+                }
 
-            // The last byte is the exit code, with 0 indicating success
-            if buffer[6] != 0x00 {
-                panic!("HCI command failed. Error code: {}. Command: {:02X?}", buffer[6], packet);
-            } else {
-                break;
+                return Ok(());
             }
         }
     }
 
-    /// Waits for a Tilt data packet to come in and returns that first packet.
-    fn find_tilt(&mut self) -> TiltPacket {
-        let mut buffer = [0u8; 256];
+    /// Waits for a Tilt data packet to come in, then keeps listening for
+    /// DISCOVERY_DURATION. The address of every Tilt seen is recorded. Gives up
+    /// if no Tilt is seen by `timeout_time`.
+    fn find_tilts(&mut self, timeout_time: Instant) {
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
+        let mut discovery_end_time = None;
+        let mut n_weak_packets = 0u32;
 
-        loop {
+        while discovery_end_time.unwrap_or(timeout_time) > Instant::now() {
             match self.ble.get_next(&mut buffer) {
                 Err(e) => {
                     warn!("Read error: {:?}", e);
                 }
                 Ok(0) => {}
                 Ok(len) => {
-                    // See if the packet can be parsed as a Tilt packet
-                    if let Some(packet) = TiltPacket::try_parse(&buffer[..len]) {
-                        return packet;
-                    } 
+                    // See if the packet has any Tilt reports
+                    for packet in TiltPacket::parse_all(&buffer[..len]) {
+                        if !is_strong_enough(&packet) {
+                            n_weak_packets += 1;
+                            continue;
+                        }
+
+                        self.add_address(packet.address());
+
+                        if discovery_end_time.is_none() {
+                            info!("Found a Tilt, looking for others...");
+                            discovery_end_time = Some(Instant::now() + DISCOVERY_DURATION);
+                        }
+                    }
                 }
             }
         }
+
+        if discovery_end_time.is_none() && n_weak_packets > 0 {
+            warn!(
+                "Only found Tilts weaker than {} dBm, ignored {} broadcasts",
+                MIN_RSSI.unwrap_or_default(),
+                n_weak_packets,
+            );
+        }
+    }
+
+    /// Records `address` as a Tilt to scan for, unless it is already recorded
+    /// or the maximum number of Tilts has been reached.
+    fn add_address(&mut self, address: &TiltAddress) {
+        let known = &self.addresses[..self.n_addresses];
+
+        if known.contains(address) {
+            return;
+        }
+
+        if self.n_addresses == MAX_TILTS {
+            warn!("Too many Tilts, ignoring {:02X?}", address);
+            return;
+        }
+
+        info!("Found Tilt: {:02X?}", address);
+        self.addresses[self.n_addresses] = *address;
+        self.n_addresses += 1;
     }
 
     /// Waits for a Tilt data packet to come in, but only until `scan_end_time`,
-    /// Returns None if no Tilt data was received before the end time.
-    async fn wait_for_tilt_event(&mut self, scan_end_time: Instant) -> Option<TiltPacket> {
-        let mut buffer = [0u8; 256];
-        
+    /// Returns the length of the packet read into `buffer`, or None if no Tilt
+    /// data was received before the end time.
+    /// The controller is polled every EVENT_POLL_INTERVAL while it has nothing,
+    /// sleeping in between so other tasks can run and the CPU can idle.
+    async fn wait_for_tilt_event(&mut self, scan_end_time: Instant, buffer: &mut [u8]) -> Option<usize> {
         while Instant::now() < scan_end_time {
-            embassy_futures::yield_now().await;
-
-            match self.ble.get_next(&mut buffer) {
+            match self.ble.get_next(buffer) {
                 Err(e) => {
                     warn!("Read error: {:?}", e);
+                    Timer::at(scan_end_time.min(Instant::now() + EVENT_POLL_INTERVAL)).await;
+                }
+                Ok(0) => {
+                    Timer::at(scan_end_time.min(Instant::now() + EVENT_POLL_INTERVAL)).await;
                 }
-                Ok(0) => {}
                 Ok(len) => {
-                    // See if the packet can be parsed as a Tilt packet
-                    if let Some(packet) = TiltPacket::try_parse(&buffer[..len]) {
-                        return Some(packet);
-                    } 
+                    // See if the packet has any Tilt reports
+                    if TiltPacket::try_parse(&buffer[..len]).is_some() {
+                        return Some(len);
+                    }
+
+                    // There may be more events queued, so check again right
+                    // away, but let other tasks run first
+                    embassy_futures::yield_now().await;
                 }
             }
         }
@@ -171,6 +686,11 @@ impl TiltScanner {
     }
 }
 
+/// Returns whether the `packet` was received at MIN_RSSI or stronger.
+fn is_strong_enough(packet: &TiltPacket) -> bool {
+    MIN_RSSI.map_or(true, |min_rssi| packet.data().rssi() >= min_rssi)
+}
+
 /// Resets the bluetooth controller to its default state.
 fn hci_reset() -> [u8; PACKET_HEADER_LENGTH] {
     hci_cmd_packet::<0>(OPCODE_RESET, []) 
@@ -212,10 +732,16 @@ fn hci_le_set_event_mask() -> [u8; 8 + PACKET_HEADER_LENGTH] {
     )
 }
 
-/// Sets the parameters for the LE scan. This will perform a passive scan for
-/// the configured interval and window. It can optionally filter out unwanted
-/// addresses.
-fn hci_le_set_scan_params(filter: bool) -> [u8; 7 + PACKET_HEADER_LENGTH] {
+/// Sets the parameters for the LE scan. This will perform a passive or `active`
+/// scan for the configured interval and window. It can optionally filter out
+/// unwanted addresses.
+fn hci_le_set_scan_params(filter: bool, active: bool) -> [u8; 7 + PACKET_HEADER_LENGTH] {
+    let type_param = if active {
+        SCAN_PARAM_TYPE_ACTIVE
+    } else {
+        SCAN_PARAM_TYPE_PASSIVE
+    };
+
     let filter_param = if filter {
         // Only report events from addresses that have been added to the list
         // via hci_le_add_to_white_list.
@@ -228,7 +754,8 @@ fn hci_le_set_scan_params(filter: bool) -> [u8; 7 + PACKET_HEADER_LENGTH] {
     hci_cmd_packet::<7>(
         OPCODE_SET_SCAN_PARAMS,
         [
-            0x00, // Scan type: passive
+            type_param,
+            // Both are little endian encoded
             SCAN_PARAM_SCAN_INTERVAL as u8,
             (SCAN_PARAM_SCAN_INTERVAL >> 8) as u8,
             SCAN_PARAM_SCAN_WINDOW as u8,
@@ -239,12 +766,17 @@ fn hci_le_set_scan_params(filter: bool) -> [u8; 7 + PACKET_HEADER_LENGTH] {
     )
 }
 
-/// Allows the BLE address of `tilt` to be reported in LE scans if the scan is
-/// set with the filter enabled.
-fn hci_le_add_to_white_list(tilt: &TiltPacket) -> [u8; 7 + PACKET_HEADER_LENGTH] {
+/// Removes all addresses from the list of addresses allowed in filtered scans.
+fn hci_le_clear_white_list() -> [u8; PACKET_HEADER_LENGTH] {
+    hci_cmd_packet::<0>(OPCODE_CLEAR_WHITELIST, [])
+}
+
+/// Allows the BLE `address` to be reported in LE scans if the scan is set with
+/// the filter enabled.
+fn hci_le_add_to_white_list(address: &TiltAddress) -> [u8; 7 + PACKET_HEADER_LENGTH] {
     hci_cmd_packet::<7>(
         OPCODE_ADD_TO_WHITELIST,
-        *tilt.address(),
+        *address,
     )
 }
 
@@ -1,39 +1,325 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use embassy_executor::Spawner;
 use embassy_executor::_export::StaticCell;
-use embassy_futures::block_on;
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{Stack, StackResources, Config, IpAddress};
+use embassy_net::{Stack, StackResources, Config, IpAddress, Ipv4Address, Ipv4Cidr, StaticConfig};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::{Channel, TrySendError};
 use embassy_sync::signal::Signal;
-use embassy_time::{Timer, Duration, Instant};
+use embassy_time::{with_timeout, Timer, Duration, Instant};
+use embedded_io::asynch::Write as _;
 use embedded_svc::wifi::{ClientConfiguration, Configuration};
+use embedded_tls::asynch::TlsConnection;
+use embedded_tls::{Aes128GcmSha256, NoVerify, TlsConfig, TlsContext, TlsError};
 use esp32c3_hal::radio::Wifi;
 use esp_wifi::wifi::{WifiState, WifiDevice, WifiController, WifiEvent, WifiMode};
+use heapless::{Deque, String, Vec};
 use log::{error, info, warn};
+use rand_chacha::ChaCha8Rng;
+use rand_core::{RngCore, SeedableRng};
 use smoltcp::socket;
 use smoltcp::wire::DnsQueryType;
 
-use crate::tilt::TiltData;
+use crate::http::ResponseClass;
+use crate::led::LedState;
+use crate::payload::Wrapper;
+use crate::status::WifiInfo;
+use crate::tilt::{TiltColor, TiltData, TiltReadings};
 
 // secrets.env is ignored by git and contains values for:
 // SSID, PASSWORD, and BREWFATHER_STREAM_ID
 include!("secrets.env");
-const BREWFATHER_HOSTNAME: &str = "log.brewfather.net";
-const BREWFATHER_PORT: u16 = 80;
+// Each value secrets.env must define, with its type. If one is missing or
+// misspelled, the first error is here and names it, e.g. "cannot find value
+// `SSID` in this scope". If one has the wrong type, it is a mismatched types
+// error here.
+const _: &str = SSID;
+const _: &str = PASSWORD;
+const _: &str = BREWFATHER_STREAM_ID;
+// The WiFi networks to connect to as (SSID, password) pairs, in order of
+// preference. If connecting to one repeatedly fails, the next is tried. To add
+// more, define them in secrets.env and list them here.
+const NETWORKS: &[(&str, &str)] = &[(SSID, PASSWORD)];
+// How many times to try connecting to a network before moving to the next one
+const MAX_CONNECT_ATTEMPTS: u32 = 2;
+// After a disconnect or failed connect, wait this long before reconnecting. The
+// delay doubles with each failure in a row, up to the max, plus up to a quarter
+// more at random so devices don't all retry in step when the AP comes back.
+const RECONNECT_BASE_DELAY_MS: u64 = 1000;
+const RECONNECT_MAX_DELAY_MS: u64 = 60_000;
+
+// The maximum WiFi transmit power, in quarter-dBm, so 40 = 10 dBm. The valid
+// range is 8 (2 dBm) to 84 (21 dBm, which is the default).
+// The antenna on the ESP32-C3 QT Py doesn't like being at full power. My guess
+// is that there is some tuning issue in the hardware that causes reflections or
+// something. Setting it to half power (10 dBm) seems to work reliably. Other
+// boards may need more power to get through a fridge.
+const WIFI_TX_POWER_QDBM: i8 = 40;
+
+const _: () = assert!(WIFI_TX_POWER_QDBM >= 8 && WIFI_TX_POWER_QDBM <= 84);
+
+// Where the data is posted for PublishTarget::Brewfather. This can be any
+// endpoint that accepts JSON, e.g. a self-hosted logger or a webhook like
+// WEBHOOK_ENDPOINT, with payload::JSON_FIELDS set to the names it expects. Use
+// TEST_SERVER_ENDPOINT and run bin/testserver.py on the test server to capture
+// the requests the relay makes instead of sending them to Brewfather.
+const HTTP_ENDPOINT: HttpEndpoint = BREWFATHER_ENDPOINT;
 
-const MAX_POST_ATTEMPTS: usize = 5;
-const POST_BACKOFF_MS: [u64; MAX_POST_ATTEMPTS - 1] = [100, 500, 1000, 1000];
+const BREWFATHER_ENDPOINT: HttpEndpoint = HttpEndpoint {
+    hostname: "log.brewfather.net",
+    address: None,
+    port: if USE_TLS { 443 } else { 80 },
+    path: "/stream?id={stream_id}",
+    headers: "",
+    tls: USE_TLS,
+};
+#[allow(dead_code)]
+const TEST_SERVER_ENDPOINT: HttpEndpoint = HttpEndpoint {
+    hostname: "192.168.0.101",
+    address: Some(IpAddress::v4(192, 168, 0, 101)),
+    port: 8000,
+    path: "/stream?id={stream_id}",
+    headers: "",
+    tls: false,
+};
+// A generic JSON webhook, e.g. a Google Apps Script web app that logs to a
+// sheet. Apps Script responds to a post with a redirect to the script's output,
+// which is followed.
+#[allow(dead_code)]
+const WEBHOOK_ENDPOINT: HttpEndpoint = HttpEndpoint {
+    hostname: "script.google.com",
+    address: None,
+    port: 443,
+    path: "/macros/s/{stream_id}/exec",
+    headers: "",
+    tls: true,
+};
+// The most redirects to follow for one post, so a redirect loop ends
+const MAX_REDIRECTS: usize = 3;
+// The longest URL in a redirect that can be followed
+const MAX_URL_LENGTH: usize = 256;
+
+/// Replaced with the Tilt's stream ID in the path of an HttpEndpoint
+const STREAM_ID_PLACEHOLDER: &str = "{stream_id}";
+// Post some Tilts to their own streams, e.g. to keep two batches fermenting side
+// by side apart, as (color, stream ID) pairs. The other Tilts are posted to
+// BREWFATHER_STREAM_ID.
+const STREAM_IDS: &[(TiltColor, &str)] = &[];
+
+// Post to Brewfather over HTTPS so the stream ID isn't sent in the clear.
+// embedded-tls only supports TLS 1.3 and does not verify the server's
+// certificate, so this protects against eavesdropping but not impersonation.
+const USE_TLS: bool = false;
+// A TLS record can be up to 16 KiB, and the whole record must fit in the read
+// buffer. Requests are much smaller than that, so the write buffer can be
+// small. The buffers take no space when TLS is disabled.
+const TLS_READ_BUFFER_SIZE: usize = if HTTP_ENDPOINT.tls { 16384 + 256 } else { 0 };
+const TLS_WRITE_BUFFER_SIZE: usize = if HTTP_ENDPOINT.tls { 1024 } else { 0 };
+
+pub const MAX_POST_ATTEMPTS: usize = 5;
+// Give up on an attempt if the whole exchange, from connecting to reading the
+// response and following any redirects, takes longer than this. The socket only
+// times out when nothing arrives for a while, so a server that trickles out its
+// response could otherwise hold the attempt open indefinitely.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Keep the connection open after a successful post and reuse it for the next
+// one, instead of connecting for each post. This helps with a local server that
+// is posted to often. It is only for plain HTTP, since the TLS session isn't
+// kept, and the server must send a Content-Length or chunked response. If the
+// server has closed the connection, or dropped it without saying so, the post
+// is sent again on a new connection.
+const KEEP_ALIVE: bool = false;
+// Before each retry of a post, wait the base delay multiplied by the multiplier
+// for each retry before it, up to the max. With these values the waits are 100,
+// 500, 1000, and 1000 ms.
+const POST_BACKOFF_BASE_MS: u64 = 100;
+const POST_BACKOFF_MULTIPLIER: u64 = 5;
+const POST_BACKOFF_MAX_MS: u64 = 1000;
+const _: () = assert!(POST_BACKOFF_MULTIPLIER >= 1, "The backoff can't get shorter");
 // How many times can the post fail all attempts before we force a reset
-const MAX_FAILURES: u32 = 3;
+pub const MAX_FAILURES: u32 = 3;
+// How many scans can be waiting to be posted. When it is full, the oldest scan
+// is dropped to make room, so the latest readings are always posted.
+const DATA_QUEUE_SIZE: usize = 4;
+// How many datapoints to keep while WiFi is down or posting fails, to post once
+// it's back. The oldest are dropped when it is full. Brewfather only takes one
+// post per Tilt every MIN_BREWFATHER_POST_INTERVAL, so a backlog would never
+// drain, and only the latest datapoint from each Tilt is kept for it instead.
+const MAX_BUFFERED: usize = 24;
+// How long to back off when the server is rate limiting or overloaded and
+// doesn't say how long to wait with Retry-After
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(10);
+// The longest to honor a Retry-After for, since the next scan's data is waiting
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+// Log each request instead of sending it, and treat it as posted. Nothing is
+// sent over the network, so the payload can be checked without a server, or
+// without WiFi at all. Timestamps are only included if the time has synced.
+const DRY_RUN: bool = false;
+// Brewfather rejects posts for a Tilt less than 15 minutes after its last one.
+// The relay publishes that far apart, but a post delayed by retries can end up
+// too close to the next, so a post that would be too soon stays buffered to
+// post with a later scan.
+const MIN_BREWFATHER_POST_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// How much sooner than MIN_BREWFATHER_POST_INTERVAL a post is still allowed.
+// Scans are queued exactly that far apart, but the time until each request is
+// sent varies, e.g. with a DNS lookup or a time sync.
+const BREWFATHER_POST_TOLERANCE: Duration = Duration::from_secs(30);
 // Max time wait_until will wait
 const MAX_WAIT_TIME: Duration = Duration::from_secs(60);
 
-// Enable this and run bin/testserver.py on the test server to capture the post
-// requests the relay makes instead of sending them to Brewfather.
-const USE_TEST_SERVER: bool = false;
-const TEST_SERVER_ENDPOINT: (IpAddress, u16) = (IpAddress::v4(192, 168, 0, 101), 8000);
+/// Where the Tilt data is published.
+#[allow(dead_code)]
+enum PublishTarget {
+    /// POST the data to HTTP_ENDPOINT, which is Brewfather by default
+    Brewfather,
+    /// Publish the data to an MQTT broker. See mqtt.rs for its configuration.
+    Mqtt,
+    /// Write the data to InfluxDB over HTTP. See influx.rs for its
+    /// configuration.
+    InfluxDb,
+}
+
+const PUBLISH_TARGET: PublishTarget = PublishTarget::Brewfather;
+
+// Use a static IP configuration instead of DHCP. The DNS server configured here
+// is used by lookup_endpoint. With DHCP, the DNS server provided by the DHCP
+// server is used instead.
+const USE_STATIC_IP: bool = false;
+const STATIC_IP_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 0, 50);
+const STATIC_IP_PREFIX_LENGTH: u8 = 24;
+const STATIC_IP_GATEWAY: Ipv4Address = Ipv4Address::new(192, 168, 0, 1);
+const STATIC_IP_DNS_SERVER: Ipv4Address = Ipv4Address::new(192, 168, 0, 1);
+
+/// Which IP versions to look up and connect to HTTP_ENDPOINT over.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum AddressFamily {
+    /// Only look up and connect to IPv4 (A) addresses
+    V4Only,
+    /// Only look up and connect to IPv6 (AAAA) addresses
+    V6Only,
+    /// Look up an IPv6 address, and fall back to IPv4 if there isn't one or it
+    /// can't be connected to
+    PreferV6,
+}
+
+// Connecting over IPv6 needs the relay to have an IPv6 address of its own.
+// This version of embassy-net only configures the IPv4 address, and smoltcp 0.9
+// has neither SLAAC nor DHCPv6, so until it is upgraded, V6Only can't connect,
+// and PreferV6 ends up connecting over IPv4.
+const ADDRESS_FAMILY: AddressFamily = AddressFamily::V4Only;
+
+// The number of sockets the network stack has room for: DHCP (unless the IP is
+// static), DNS, the socket that posts the data, SNTP's while it syncs, and one
+// each for the status server, OTA updates, and syslog if they are enabled.
+// Opening a socket when they are all in use panics. Each takes a few hundred
+// bytes of RAM for its state, on top of the buffers its task provides.
+const STACK_SOCKETS: usize = !USE_STATIC_IP as usize
+    + 1
+    + 1
+    + 1
+    + crate::http_server::HTTP_SERVER_ENABLED as usize
+    + crate::ota::OTA_ENABLED as usize
+    + crate::syslog::SYSLOG_ENABLED as usize;
 
-pub static DATA_SIGNAL: Signal<CriticalSectionRawMutex, TiltData> = Signal::new();
+// How long to use the IP address from a DNS lookup before looking it up again.
+// It is looked up again sooner if connecting to it fails.
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// An HTTP server that the data can be posted to
+struct HttpEndpoint {
+    /// Looked up with DNS to find the server, and sent in the Host header and
+    /// as the TLS server name
+    hostname: &'static str,
+    /// Connect to this address instead of looking up the hostname, e.g. for a
+    /// server on the local network that isn't in DNS
+    address: Option<IpAddress>,
+    port: u16,
+    /// The path to post to, including any query string. STREAM_ID_PLACEHOLDER
+    /// is replaced with the stream ID of the Tilt being posted.
+    path: &'static str,
+    /// Any other headers to send with each post, each ending with "\r\n", e.g.
+    /// for authorization
+    headers: &'static str,
+    /// Whether to post over HTTPS
+    tls: bool,
+}
+
+impl HttpEndpoint {
+    /// Writes the path to `w`, with the placeholders filled in for the Tilt
+    /// of the given `color`.
+    fn write_path(&self, color: TiltColor, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        let stream_id = STREAM_IDS
+            .iter()
+            .find(|(c, _)| *c == color)
+            .map_or(BREWFATHER_STREAM_ID, |(_, id)| *id);
+        let mut rest = self.path;
+
+        while let Some((before, after)) = rest.split_once(STREAM_ID_PLACEHOLDER) {
+            w.write_str(before)?;
+            w.write_str(stream_id)?;
+            rest = after;
+        }
+
+        w.write_str(rest)
+    }
+}
+
+/// Queues the readings from each scan along with the time the scan finished.
+pub static DATA_CHANNEL: Channel<CriticalSectionRawMutex, (Instant, TiltReadings), DATA_QUEUE_SIZE> = Channel::new();
+/// Signaled once the readings from DATA_CHANNEL have been posted, whether or not
+/// the post succeeded.
+pub static PUBLISHED_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// When the publishing task last finished with readings from DATA_CHANNEL, and
+/// whether they were all posted. The relay task checks this to tell that the
+/// publishing task is still alive.
+static LAST_PUBLISHED: Mutex<CriticalSectionRawMutex, Cell<Option<(Instant, bool)>>> = Mutex::new(Cell::new(None));
+/// Signals the connection task to disconnect and stop the WiFi.
+static SHUTDOWN_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signaled by the connection task once the WiFi has stopped.
+static SHUTDOWN_COMPLETE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// The longest to wait for the WiFi to stop when shutting down
+const MAX_SHUTDOWN_WAIT: Duration = Duration::from_secs(5);
+/// Signals the connection task to stop the WiFi while idle (true), or to start
+/// it again (false).
+static IDLE_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// Set while the WiFi is idle, so nothing is posted until it wakes up
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+enum HttpError {
+    Tcp(embassy_net::tcp::Error),
+    Tls(TlsError),
+    Dns(DnsError),
+    Connect(embassy_net::tcp::ConnectError),
+    /// A redirect's Location couldn't be followed
+    InvalidLocation,
+    /// A redirect needs TLS, but HTTP_ENDPOINT doesn't use it so there are no
+    /// buffers for it
+    TlsDisabled,
+}
+
+impl From<DnsError> for HttpError {
+    fn from(e: DnsError) -> Self {
+        HttpError::Dns(e)
+    }
+}
+
+impl From<embassy_net::tcp::Error> for HttpError {
+    fn from(e: embassy_net::tcp::Error) -> Self {
+        HttpError::Tcp(e)
+    }
+}
+
+impl From<TlsError> for HttpError {
+    fn from(e: TlsError) -> Self {
+        HttpError::Tls(e)
+    }
+}
 
 macro_rules! singleton {
     ($val:expr) => {{
@@ -52,77 +338,302 @@ pub async fn run_wifi_task(
 ) {
     let (wifi_interface, wifi_controller) = esp_wifi::wifi::new_with_mode(wifi, WifiMode::Sta);
 
-    let config = Config::Dhcp(Default::default());
+    let config = if USE_STATIC_IP {
+        let mut dns_servers = Vec::new();
+        dns_servers.push(STATIC_IP_DNS_SERVER).unwrap();
+
+        Config::Static(StaticConfig {
+            address: Ipv4Cidr::new(STATIC_IP_ADDRESS, STATIC_IP_PREFIX_LENGTH),
+            gateway: Some(STATIC_IP_GATEWAY),
+            dns_servers,
+        })
+    } else {
+        Config::Dhcp(Default::default())
+    };
 
     // Init network stack
     let stack = &*singleton!(Stack::new(
         wifi_interface,
         config,
-        singleton!(StackResources::<3>::new()),
+        singleton!(StackResources::<STACK_SOCKETS>::new()),
         seed,
     ));
 
-    spawner.must_spawn(connection(wifi_controller));
+    spawner.must_spawn(connection(wifi_controller, seed));
     spawner.must_spawn(net_task(&stack));
-    spawner.must_spawn(http_task(&stack));
+
+    match PUBLISH_TARGET {
+        PublishTarget::Brewfather | PublishTarget::InfluxDb => spawner.must_spawn(http_task(&stack, seed)),
+        PublishTarget::Mqtt => spawner.must_spawn(crate::mqtt::mqtt_task(&stack)),
+    }
+
+    if crate::http_server::HTTP_SERVER_ENABLED {
+        spawner.must_spawn(crate::http_server::http_server_task(&stack));
+    }
+
+    if crate::ota::OTA_ENABLED {
+        spawner.must_spawn(crate::ota::ota_task(&stack));
+    }
+
+    if crate::syslog::SYSLOG_ENABLED {
+        spawner.must_spawn(crate::syslog::syslog_task(&stack));
+    }
+
+    if crate::self_test::SELF_TEST {
+        spawner.must_spawn(crate::self_test::self_test_task(&stack));
+    }
+}
+
+/// Queues the `readings` from a scan that finished at `scan_time` to be posted.
+/// This never waits, so the relay can keep scanning on schedule. If posting has
+/// fallen behind and the queue is full, the oldest readings are dropped.
+/// PUBLISHED_SIGNAL is reset, so a signal left from earlier readings, e.g. after
+/// waiting for them timed out, isn't taken for these being posted.
+pub fn queue_readings(scan_time: Instant, readings: TiltReadings) {
+    crate::status::record_readings(scan_time, &readings);
+    PUBLISHED_SIGNAL.reset();
+
+    let mut data = (scan_time, readings);
+
+    while let Err(TrySendError::Full(rejected)) = DATA_CHANNEL.try_send(data) {
+        data = rejected;
+
+        if DATA_CHANNEL.try_receive().is_ok() {
+            warn!("Data queue is full, dropping the oldest readings");
+        }
+    }
+}
+
+/// Disconnects and stops the WiFi, e.g. before deep sleeping. The WiFi is not
+/// restarted afterwards.
+pub async fn shutdown() {
+    SHUTDOWN_SIGNAL.signal(());
+
+    if with_timeout(MAX_SHUTDOWN_WAIT, SHUTDOWN_COMPLETE_SIGNAL.wait()).await.is_err() {
+        warn!("Timed out waiting for wifi to stop");
+    }
+}
+
+/// Disconnects and stops the WiFi to save power, e.g. between posts, until
+/// `wake_up` is called. Readings queued meanwhile are kept until then, or
+/// dropped for MQTT.
+pub fn go_idle() {
+    IDLE.store(true, Ordering::Relaxed);
+    IDLE_SIGNAL.signal(true);
+}
+
+/// Starts the WiFi again after `go_idle` and reconnects. Connecting takes a
+/// while, so this should be called well before the next post.
+pub fn wake_up() {
+    if IDLE.swap(false, Ordering::Relaxed) {
+        IDLE_SIGNAL.signal(false);
+    }
+}
+
+/// Records that the publishing task has finished with the readings it received
+/// from DATA_CHANNEL, and whether they were all `posted`, then signals
+/// PUBLISHED_SIGNAL. Readings that are buffered or dropped also count as
+/// finished, since the task is still alive.
+pub fn signal_published(posted: bool) {
+    LAST_PUBLISHED.lock(|last| last.set(Some((Instant::now(), posted))));
+    PUBLISHED_SIGNAL.signal(());
+}
+
+/// Returns when the publishing task last finished with readings, and whether
+/// they were all posted, or None if it hasn't yet. See `signal_published`.
+pub fn last_published() -> Option<(Instant, bool)> {
+    LAST_PUBLISHED.lock(|last| last.get())
+}
+
+/// Returns whether the WiFi has been stopped by `go_idle`.
+pub fn is_idle() -> bool {
+    IDLE.load(Ordering::Relaxed)
 }
 
 #[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
+async fn connection(mut controller: WifiController<'static>, seed: u64) {
+    info!("start connection task");
+
+    loop {
+        match select3(stay_connected(&mut controller, seed), SHUTDOWN_SIGNAL.wait(), wait_for_idle(true)).await {
+            Either3::Third(_) => {}
+            _ => break,
+        }
+
+        stop_wifi(&mut controller).await;
+        info!("Wifi idle");
+
+        if let Either::Second(_) = select(wait_for_idle(false), SHUTDOWN_SIGNAL.wait()).await {
+            SHUTDOWN_COMPLETE_SIGNAL.signal(());
+            return;
+        }
+
+        info!("Waking wifi");
+    }
+
+    stop_wifi(&mut controller).await;
+    SHUTDOWN_COMPLETE_SIGNAL.signal(());
+}
+
+/// Waits until the WiFi is signaled to go `idle`, or to wake up if false.
+async fn wait_for_idle(idle: bool) {
+    while IDLE_SIGNAL.wait().await != idle {}
+}
+
+/// Disconnects from the network and stops the WiFi.
+async fn stop_wifi(controller: &mut WifiController<'static>) {
+    info!("Stopping wifi");
+    if let Err(e) = controller.disconnect().await {
+        warn!("Failed to disconnect from wifi: {:?}", e);
+    }
+    if let Err(e) = controller.stop().await {
+        warn!("Failed to stop wifi: {:?}", e);
+    }
+}
+
+/// Connects to WiFi and reconnects whenever the connection is lost.
+async fn stay_connected(controller: &mut WifiController<'static>, seed: u64) {
     use embedded_svc::wifi::Wifi;
 
-    info!("start connection task");
+    // Only used for the reconnect jitter. The seed is also used by other
+    // tasks, so it is mixed up a bit.
+    let mut rng = ChaCha8Rng::seed_from_u64(!seed);
+    let mut n_failures = 0;
+
+    // The network to connect to. This stays on the last network that was
+    // connected to successfully, so it is tried first when reconnecting.
+    let mut network = 0;
+    let mut configured = false;
+    let mut n_attempts = 0;
+
     loop {
         match esp_wifi::wifi::get_wifi_state() {
             WifiState::StaConnected => {
                 // wait until we're no longer connected
                 controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                sleep_ms(5000).await;
+                sleep_ms(reconnect_delay_ms(n_failures, &mut rng)).await;
             }
             _ => {}
         }
-        if !matches!(controller.is_started(), Ok(true)) {
+        if !configured {
+            let (ssid, password) = NETWORKS[network];
             let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.into(),
-                password: PASSWORD.into(),
+                ssid: ssid.into(),
+                password: password.into(),
                 ..Default::default()
             });
             controller.set_configuration(&client_config).unwrap();
+            configured = true;
+        }
+        if !matches!(controller.is_started(), Ok(true)) {
             info!("Starting wifi");
             controller.start().await.unwrap();
             info!("Wifi started!");
+
+            // The power can only be set once WiFi has started
+            set_tx_power();
         }
-        info!("About to connect...");
-
-        // The antenna on the ESP32-C3 QT Py doesn't like being at full power,
-        // which is the default (20 dBm). My guess is that there is some tuning
-        // issue in the hardware that causes reflections or something. Setting
-        // it to half power (10 dBm) seems to work reliably. Note: the value is
-        // 40, but the units are in quarter-dBm, so 40 = 10 dBm.
-        unsafe { esp_wifi::binary::include::esp_wifi_set_max_tx_power(40) };
-        
+        info!("About to connect to {}...", NETWORKS[network].0);
+
         match controller.connect().await {
-            Ok(_) => info!("Wifi connected!"),
+            Ok(_) => {
+                info!("Wifi connected!");
+                n_attempts = 0;
+                n_failures = 0;
+            }
             Err(e) => {
                 info!("Failed to connect to wifi: {e:?}");
-                sleep_ms(5000).await;
+                n_attempts += 1;
+
+                // Fall back to the next network
+                if n_attempts >= MAX_CONNECT_ATTEMPTS && NETWORKS.len() > 1 {
+                    network = (network + 1) % NETWORKS.len();
+                    configured = false;
+                    n_attempts = 0;
+                    info!("Switching to {}", NETWORKS[network].0);
+                }
+
+                let delay = reconnect_delay_ms(n_failures, &mut rng);
+                n_failures += 1;
+                info!("Reconnecting in {} ms", delay);
+                sleep_ms(delay).await;
             }
         }
     }
 }
 
+/// Returns how long to wait before the `retry`th retry of a post, counting
+/// from 1. See POST_BACKOFF_BASE_MS.
+pub const fn post_backoff_ms(retry: usize) -> u64 {
+    crate::http::backoff_ms(retry, POST_BACKOFF_BASE_MS, POST_BACKOFF_MULTIPLIER, POST_BACKOFF_MAX_MS)
+}
+
+/// Returns how long to wait before reconnecting after `n_failures` failed
+/// attempts in a row, with some random jitter from `rng`.
+fn reconnect_delay_ms(n_failures: u32, rng: &mut ChaCha8Rng) -> u64 {
+    let delay = RECONNECT_BASE_DELAY_MS
+        .saturating_mul(1 << n_failures.min(16))
+        .min(RECONNECT_MAX_DELAY_MS);
+
+    delay + rng.next_u64() % (delay / 4 + 1)
+}
+
+/// Reads the diagnostics for the WiFi connection, then logs them and records
+/// them in the status. This is done with each publish.
+pub fn update_wifi_info() {
+    let wifi_info = read_wifi_info();
+
+    if let Some(info) = wifi_info {
+        info!("WiFi RSSI {} dBm, BSSID {:02X?}, channel {}", info.rssi, info.bssid, info.channel);
+    }
+
+    crate::status::record_wifi(wifi_info);
+}
+
+/// Reads the RSSI, BSSID, and channel of the access point the relay is
+/// connected to. Returns None if it isn't connected.
+fn read_wifi_info() -> Option<WifiInfo> {
+    let mut record: esp_wifi::binary::include::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+
+    let result = unsafe { esp_wifi::binary::include::esp_wifi_sta_get_ap_info(&mut record) };
+    if result != 0 {
+        return None;
+    }
+
+    Some(WifiInfo {
+        rssi: record.rssi,
+        bssid: record.bssid,
+        channel: record.primary,
+    })
+}
+
+/// Sets the maximum transmit power to WIFI_TX_POWER_QDBM and logs the power
+/// that is actually used, which the driver may round.
+fn set_tx_power() {
+    let mut power: i8 = 0;
+
+    unsafe {
+        esp_wifi::binary::include::esp_wifi_set_max_tx_power(WIFI_TX_POWER_QDBM);
+        esp_wifi::binary::include::esp_wifi_get_max_tx_power(&mut power);
+    }
+
+    info!("Wifi TX power set to {} quarter-dBm ({} dBm)", power, power as f32 / 4.0);
+}
+
 #[embassy_executor::task]
 async fn net_task(stack: &'static Stack<WifiDevice<'static>>) {
     stack.run().await
 }
 
 #[embassy_executor::task]
-async fn http_task(stack: &'static Stack<WifiDevice<'static>>) {
-    if wait_until(|| stack.is_link_up()).await.is_err() {
+async fn http_task(stack: &'static Stack<WifiDevice<'static>>, seed: u64) {
+    if !DRY_RUN && wait_until(|| stack.is_link_up()).await.is_err() {
         panic!("Stalled while waiting for link to come up");
     }
 
-    if wait_until(|| stack.config().is_some()).await.is_err() {
+    // A static config is ready immediately, but DHCP has to get an address
+    // and DNS server first
+    if !DRY_RUN && !USE_STATIC_IP && wait_until(|| stack.config().is_some()).await.is_err() {
         panic!("Stalled while waiting for config to be ready");
     }
 
@@ -131,117 +642,428 @@ async fn http_task(stack: &'static Stack<WifiDevice<'static>>) {
     let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
     socket.set_timeout(Some(embassy_net::SmolDuration::from_secs(10)));
 
+    // Only used for TLS
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
     let mut n_failures = 0;
+
+    // Datapoints waiting to be posted, oldest first, along with the time they
+    // were scanned
+    let mut buffered: Deque<(Instant, TiltData), MAX_BUFFERED> = Deque::new();
+    let mut dns_cache: Option<CachedAddress> = None;
+    // When the latest successful post for each Tilt was sent, to limit the
+    // rate of posts
+    let mut last_posts: [Option<Instant>; TiltColor::COUNT] = [None; TiltColor::COUNT];
     
     loop {
-        // Wait for the relay to scan for the Tilt and signal us with data
-        let tilt_data = DATA_SIGNAL.wait().await;
-        
-        // Look up the endpoint with DNS every time in case the IP changes
-        let remote_endpoint = lookup_endpoint(stack).await;
+        // Wait for the relay to scan for the Tilts and send us the data
+        let (scan_time, readings) = DATA_CHANNEL.receive().await;
 
-        let mut attempt = 1;
-        let mut success = false;
-
-        while !success && attempt <= MAX_POST_ATTEMPTS {
-            // Retries should sleep with some backoff
-            if attempt > 1 {
-                sleep_ms(POST_BACKOFF_MS[attempt - 2]).await;
+        for tilt_data in readings.iter().flatten().copied() {
+            // Only Brewfather can show notices
+            if !tilt_data.is_reading() && !matches!(PUBLISH_TARGET, PublishTarget::Brewfather) {
+                continue;
             }
 
-            attempt += 1;
-
-            // Close the socket
-            if socket.state() != socket::tcp::State::Closed {
-                socket.close();
-            
-                // Wait for the socket to actually close
-                if wait_until(|| socket.state() != socket::tcp::State::Closed).await.is_err() {
-                    warn!("Stalled while waiting for socket to close");
+            if matches!(PUBLISH_TARGET, PublishTarget::Brewfather) {
+                if let Some(older) = buffered.iter_mut().find(|(_, older)| older.color() == tilt_data.color()) {
+                    info!("Replacing the buffered {} Tilt data with the latest", tilt_data.color().name());
+                    *older = (scan_time, tilt_data);
                     continue;
                 }
             }
 
-            let r = socket.connect(remote_endpoint).await;
-            
-            if let Err(e) = r {
-                warn!("connect error: {:?}", e);
+            if buffered.is_full() {
+                warn!("Too much data buffered, dropping the oldest");
+                buffered.pop_front();
+            }
+
+            buffered.push_back((scan_time, tilt_data)).ok();
+        }
+
+        // Keep the data until WiFi wakes up
+        if is_idle() && !DRY_RUN {
+            info!("WiFi is idle, buffering {} datapoints", buffered.len());
+            signal_published(false);
+            continue;
+        }
+
+        let wifi_up = stack.is_link_up() && stack.config().is_some();
+
+        // Keep the data until WiFi is back
+        if !wifi_up && !DRY_RUN {
+            warn!("WiFi is down, buffering {} datapoints", buffered.len());
+            signal_published(false);
+            continue;
+        }
+
+        // Make sure the time is synced so the data can be timestamped
+        if wifi_up {
+            crate::sntp::sync_if_stale(stack).await;
+        }
+
+        update_wifi_info();
+
+        // Post the data from each Tilt separately, oldest first so it is
+        // recorded in order. Each is timestamped with when it was scanned.
+        // Each datapoint is taken from the front, and put back at the end
+        // unless it is posted or dropped, so the ones left are still in order
+        // after one pass. A datapoint that is too soon for its Tilt is left
+        // for a later scan rather than waited for, so it doesn't hold up the
+        // others, or the next scan's data.
+        let mut failed = false;
+        for _ in 0..buffered.len() {
+            let (scan_time, tilt_data) = match buffered.pop_front() {
+                Some(datapoint) => datapoint,
+                None => break,
+            };
+            // After a failure, the rest wait for the next scan
+            if failed {
+                buffered.push_back((scan_time, tilt_data)).ok();
+                continue;
+            }
+
+            let since_last_post = last_posts[tilt_data.color().index()].map(|last_post| (Instant::now() - last_post).as_millis());
+            if matches!(PUBLISH_TARGET, PublishTarget::Brewfather)
+                && !crate::http::post_allowed(
+                    since_last_post,
+                    MIN_BREWFATHER_POST_INTERVAL.as_millis(),
+                    BREWFATHER_POST_TOLERANCE.as_millis(),
+                )
+            {
+                info!(
+                    "Deferring the {} Tilt post, the last was only {} seconds ago",
+                    tilt_data.color().name(),
+                    since_last_post.unwrap_or_default() / 1000,
+                );
+                buffered.push_back((scan_time, tilt_data)).ok();
                 continue;
             }
 
-            // Post the data
-            let mut writer = SocketWriter::new(socket);
+            let timestamp = crate::sntp::unix_time(scan_time);
+            let mut attempt = 1;
+            let mut success = false;
+            let mut rejected = false;
+            // When the latest attempt was sent, which is when the server saw
+            // the post if it succeeded
+            let mut sent_time = Instant::now();
 
-            if let Err(e) = do_post(&mut writer, tilt_data).await {
-                warn!("write error: {:?}", e);
+            if DRY_RUN {
+                let mut request_buffer = [0u8; 640];
+                match format_request(tilt_data, timestamp, &mut request_buffer) {
+                    Ok(request) => info!("Dry run, not sending:\n{}", request),
+                    Err(_) => error!("{} Tilt data doesn't fit in the request", tilt_data.color().name()),
+                }
+                success = true;
             }
 
-            // Destroy the writer and get back the socket
-            socket = writer.socket();
+            // Reuse the connection from the last post if the server kept it
+            // open
+            let mut reuse = keep_alive() && socket.state() == socket::tcp::State::Established;
+            // Switched to IPv4 if the IPv6 address can't be connected to
+            let mut family = ADDRESS_FAMILY;
 
-            // Read the response
-            let mut buf = [0u8; 1024];
-            let n = match socket.read(&mut buf).await {
-                Ok(0) => {
-                    info!("read EOF");
-                    None
+            while !success && !rejected && attempt <= MAX_POST_ATTEMPTS {
+                // Retries should sleep with some backoff
+                if attempt > 1 {
+                    sleep_ms(post_backoff_ms(attempt - 1)).await;
                 }
-                Ok(n) => Some(n),
-                Err(e) => {
-                    info!("read error: {:?}", e);
+
+                attempt += 1;
+
+                // Close the socket
+                if !reuse && socket.state() != socket::tcp::State::Closed {
+                    socket.close();
+
+                    // Wait for the socket to actually close. If the server never
+                    // acknowledges the close, the socket would be stuck half
+                    // closed, so reset it to start the next connection cleanly.
+                    if wait_until(|| socket.state() == socket::tcp::State::Closed).await.is_err() {
+                        warn!("Stalled while waiting for socket to close, resetting it");
+                        socket.abort();
+                    }
+                }
+
+                let remote_endpoint = if reuse {
                     None
+                } else {
+                    match PUBLISH_TARGET {
+                        PublishTarget::InfluxDb => Some(crate::influx::INFLUXDB_ENDPOINT),
+                        _ => match lookup_endpoint(stack, &HTTP_ENDPOINT, family, &mut dns_cache).await {
+                            Ok(remote_endpoint) => Some(remote_endpoint),
+                            Err(e) => {
+                                warn!("Could not retrieve hostname for '{}': {:?}", HTTP_ENDPOINT.hostname, e);
+                                continue;
+                            }
+                        },
+                    }
+                };
+
+                let mut request_buffer = [0u8; 640];
+                let request = match format_request(tilt_data, timestamp, &mut request_buffer) {
+                    Ok(request) => request,
+                    // This would fail every time, so the data is dropped
+                    Err(_) => {
+                        error!("{} Tilt data doesn't fit in the request", tilt_data.color().name());
+                        rejected = true;
+                        break;
+                    }
+                };
+
+                let mut buf = [0u8; 1024];
+                let server_name = match PUBLISH_TARGET {
+                    PublishTarget::Brewfather if HTTP_ENDPOINT.tls => Some(HTTP_ENDPOINT.hostname),
+                    _ => None,
+                };
+
+                // Post the data and read the response
+                let mut redirected = false;
+                let exchange = async {
+                    if let Some(remote_endpoint) = remote_endpoint {
+                        socket.connect(remote_endpoint).await.map_err(HttpError::Connect)?;
+                    }
+
+                    sent_time = Instant::now();
+                    let mut result = send_request(&mut socket, server_name, request.as_bytes(), &mut buf, &mut rng).await;
+
+                    // The response may be elsewhere, e.g. Apps Script responds
+                    // with a redirect to the script's output
+                    if let Ok(n) = result {
+                        if crate::http::classify(&buf[..n]) == ResponseClass::Redirect {
+                            redirected = true;
+                            result = follow_redirects(stack, &mut socket, request, &mut buf, n, &mut rng).await;
+                        }
+                    }
+
+                    result
+                };
+
+                let result = match with_timeout(REQUEST_TIMEOUT, exchange).await {
+                    Ok(Err(HttpError::Connect(e))) => {
+                        warn!("connect error: {:?}", e);
+                        if matches!(family, AddressFamily::PreferV6) && matches!(remote_endpoint, Some((IpAddress::Ipv6(_), _))) {
+                            info!("Falling back to IPv4");
+                            family = AddressFamily::V4Only;
+                        }
+                        // The IP may have changed, so look it up again
+                        dns_cache = None;
+                        continue;
+                    }
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!("Request didn't finish within {} seconds", REQUEST_TIMEOUT.as_secs());
+                        socket.close();
+                        reuse = false;
+                        continue;
+                    }
+                };
+
+                // The server may have dropped the kept connection while it was
+                // idle, so send the post again on a new one without counting
+                // this attempt
+                if reuse && !matches!(result, Ok(n) if n > 0) {
+                    info!("Kept connection was closed by the server, reconnecting");
+                    socket.abort();
+                    reuse = false;
+                    attempt -= 1;
+                    continue;
                 }
-            };
+
+                let n = match result {
+                    Ok(0) => {
+                        info!("read EOF");
+                        None
+                    }
+                    Ok(n) => Some(n),
+                    Err(e) => {
+                        info!("request error: {:?}", e);
+                        None
+                    }
+                };
             
-            // Make sure the response is successful
-            if let Some(n) = n {
-                let response = core::str::from_utf8(&buf[..n]).unwrap();
-                info!("{}", response);
+                // Make sure the response is successful
+                if let Some(n) = n {
+                    // The response is only logged, and classified from its
+                    // bytes, so a body that isn't UTF-8, e.g. from a proxy,
+                    // is logged up to the first invalid byte
+                    match core::str::from_utf8(&buf[..n]) {
+                        Ok(response) => info!("{}", response),
+                        Err(e) => {
+                            let valid = core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or_default();
+                            info!("{}<{} bytes that aren't UTF-8>", valid, n - e.valid_up_to());
+                        }
+                    }
 
-                if response.starts_with("HTTP/1.1 200 OK") {
-                    success = true;
+                    // Any 2xx status is a success. InfluxDB responds with 204
+                    // No Content, for example.
+                    match crate::http::classify(&buf[..n]) {
+                        ResponseClass::Success => success = true,
+                        ResponseClass::RetryLater(retry_after) => {
+                            let backoff = retry_after
+                                .map_or(RATE_LIMIT_BACKOFF, |secs| Duration::from_secs(secs as u64))
+                                .min(MAX_RETRY_AFTER);
+                            warn!("Server is busy, backing off for {} seconds", backoff.as_secs());
+                            Timer::after(backoff).await;
+                        }
+                        ResponseClass::Rejected => rejected = true,
+                        ResponseClass::Redirect => warn!("Too many redirects"),
+                        ResponseClass::Retry => warn!("Unsuccessful response"),
+                    }
                 }
-            }
 
-            socket.close();
-        }
+                // A kept connection is only reused after a success, since the
+                // server may not be expecting another request after an error.
+                // After a redirect, it is to another server.
+                if keep_alive() && success && !redirected {
+                    reuse = true;
+                } else {
+                    socket.close();
+                    reuse = false;
+                }
+            }
     
-        // Limit the number of times we can completely fail to post data.
-        // panic if it is too much, which initiates a reset.
-        // Note that this is separate from the retries with backoff on posting
-        // a single datapoint. This looks for failing on *multiple* datapoints.
-        if success {
-            n_failures = 0;
-        } else {
-            error!("Failed to post tilt data");
-            n_failures += 1;
+            // Limit the number of times we can completely fail to post data.
+            // panic if it is too much, which initiates a reset.
+            // Note that this is separate from the retries with backoff on posting
+            // a single datapoint. This looks for failing on *multiple* datapoints.
+            // A rejected post would fail every time, so it is dropped rather
+            // than counted as a failure.
+            // Failed data stays buffered to try again with the next scan.
+            crate::status::record_post(success);
+            crate::led::set_state(if success { LedState::Posted } else { LedState::Failing });
+            if success {
+                n_failures = 0;
+                last_posts[tilt_data.color().index()] = Some(sent_time);
+            } else if rejected {
+                error!("{} tilt data was rejected, dropping it", tilt_data.color().name());
+            } else {
+                error!("Failed to post {} tilt data, keeping it for the next scan", tilt_data.color().name());
+                buffered.push_back((scan_time, tilt_data)).ok();
+                n_failures += 1;
         
-            if n_failures >= MAX_FAILURES {
-                panic!("Too many failures, panicking to induce a reset...");
+                if n_failures >= MAX_FAILURES {
+                    panic!("Too many failures, panicking to induce a reset...");
+                }
+
+                failed = true;
             }
         }
+
+        // Deferred datapoints are still buffered, but the task is done with
+        // this scan, so it counts as published for the liveness check
+        if !buffered.is_empty() {
+            info!("{} datapoints buffered for a later scan", buffered.len());
+        }
+        signal_published(buffered.is_empty());
     }
 }
 
-/// Performs a DNS query for the Brewfather logging endpoint from the hostname
-async fn lookup_endpoint(stack: &'static Stack<WifiDevice<'static>>) -> (IpAddress, u16) {
-    let ip = stack.dns_query(BREWFATHER_HOSTNAME, DnsQueryType::A).await;
+/// Formats the HTTP request that publishes `tilt_data` to PUBLISH_TARGET into
+/// `buffer`, and returns it. Returns an error if it doesn't fit.
+fn format_request<'a>(tilt_data: TiltData, timestamp: Option<u64>, buffer: &'a mut [u8]) -> Result<&'a str, core::fmt::Error> {
+    match PUBLISH_TARGET {
+        PublishTarget::InfluxDb => crate::influx::format_write(tilt_data, timestamp, buffer),
+        _ => format_post(tilt_data, timestamp, buffer),
+    }
+}
+
+#[derive(Debug)]
+enum DnsError {
+    Query(embassy_net::dns::Error),
+    /// The query succeeded, but returned no addresses
+    NoAddress,
+}
 
-    if let Err(e) = ip {
-        panic!("Could not retrieve hostname for '{}': {:?}", BREWFATHER_HOSTNAME, e);
+/// An IP address from a DNS lookup, and when it should be looked up again
+struct CachedAddress {
+    ip: IpAddress,
+    expires: Instant,
+}
+
+/// Returns the IP address and port of the `endpoint`. Unless it has a fixed
+/// address, the `cache`d IP address is used if it hasn't expired, otherwise
+/// this performs a DNS query of the `family` for the hostname and caches the
+/// result for DNS_CACHE_TTL.
+async fn lookup_endpoint(
+    stack: &'static Stack<WifiDevice<'static>>,
+    endpoint: &HttpEndpoint,
+    family: AddressFamily,
+    cache: &mut Option<CachedAddress>,
+) -> Result<(IpAddress, u16), DnsError> {
+    if let Some(address) = endpoint.address {
+        return Ok((address, endpoint.port));
     }
 
-    if USE_TEST_SERVER {
-        TEST_SERVER_ENDPOINT
-    } else {
-        (ip.unwrap()[0], BREWFATHER_PORT)
+    let ip = match cache {
+        Some(cached) if Instant::now() < cached.expires => cached.ip,
+        _ => {
+            let ip = query_address(stack, endpoint.hostname, family).await?;
+
+            *cache = Some(CachedAddress {
+                ip,
+                expires: Instant::now() + DNS_CACHE_TTL,
+            });
+
+            ip
+        }
+    };
+
+    Ok((ip, endpoint.port))
+}
+
+/// Performs a DNS query for the IP address of `hostname` of the `family`. If it
+/// has more than one, the first is returned. With PreferV6, an IPv4 address is
+/// looked up if there is no IPv6 address, or the AAAA query fails.
+async fn query_address(
+    stack: &'static Stack<WifiDevice<'static>>,
+    hostname: &str,
+    family: AddressFamily,
+) -> Result<IpAddress, DnsError> {
+    match family {
+        AddressFamily::V4Only => query_first(stack, hostname, DnsQueryType::A).await,
+        AddressFamily::V6Only => query_first(stack, hostname, DnsQueryType::Aaaa).await,
+        AddressFamily::PreferV6 => match query_first(stack, hostname, DnsQueryType::Aaaa).await {
+            Ok(ip) => Ok(ip),
+            Err(e) => {
+                info!("No IPv6 address for '{}' ({:?}), falling back to IPv4", hostname, e);
+                query_first(stack, hostname, DnsQueryType::A).await
+            }
+        },
+    }
+}
+
+/// Performs a DNS query of `query_type` for `hostname`, and returns the first
+/// address.
+async fn query_first(
+    stack: &'static Stack<WifiDevice<'static>>,
+    hostname: &str,
+    query_type: DnsQueryType,
+) -> Result<IpAddress, DnsError> {
+    let ips = stack.dns_query(hostname, query_type).await.map_err(DnsError::Query)?;
+    ips.first().copied().ok_or(DnsError::NoAddress)
+}
+
+/// Looks up the IP address of HTTP_ENDPOINT for the self test. Unlike
+/// lookup_endpoint, this always performs a DNS query unless the endpoint has a
+/// fixed address. Returns None if the lookup failed.
+pub async fn resolve_endpoint(stack: &'static Stack<WifiDevice<'static>>) -> Option<IpAddress> {
+    if let Some(address) = HTTP_ENDPOINT.address {
+        return Some(address);
+    }
+
+    match query_address(stack, HTTP_ENDPOINT.hostname, ADDRESS_FAMILY).await {
+        Ok(ip) => Some(ip),
+        Err(e) => {
+            error!("Could not retrieve hostname for '{}': {:?}", HTTP_ENDPOINT.hostname, e);
+            None
+        }
     }
 }
 
 /// Waits until the given function returns true, or MAX_WAIT_TIME has been
 /// reached, whichever comes first. Returns Ok if the function returned true and
 /// Err if MAX_WAIT_TIME was reached.
-async fn wait_until(f: impl Fn() -> bool) -> Result<(), ()> {
+pub async fn wait_until(f: impl Fn() -> bool) -> Result<(), ()> {
     let start_time = Instant::now();
 
     while !f() {
@@ -255,115 +1077,186 @@ async fn wait_until(f: impl Fn() -> bool) -> Result<(), ()> {
     Ok(())
 }
 
-/// Helper that will sleep for the given number of milliseconds
-async fn sleep_ms(ms: u64) {
-    Timer::after(Duration::from_millis(ms)).await;
-}
-
-/// Posts the `tilt_data` to the `socket`.
-async fn do_post(socket: &mut SocketWriter<'_>, tilt_data: TiltData) -> Result<(), embassy_net::tcp::Error> {
+/// Follows the redirect in the first `len` bytes of `response`, which was the
+/// response to `request`. Any further redirects are followed too, up to
+/// MAX_REDIRECTS in all. The final response is read into `response`, and its
+/// length is returned.
+/// For 307 and 308, `request` is sent again to the Location, with the same
+/// method, headers, and body. For 301, 302, and 303, the Location is requested
+/// with a GET and no body, as browsers do after a post. Apps Script relies on
+/// that, since it redirects every post to the script's output with a 302.
+async fn follow_redirects(
+    stack: &'static Stack<WifiDevice<'static>>,
+    socket: &mut TcpSocket<'_>,
+    request: &str,
+    response: &mut [u8],
+    mut len: usize,
+    rng: &mut ChaCha8Rng,
+) -> Result<usize, HttpError> {
     use core::fmt::Write;
 
-    let mut buffer = [0u8; 256];
-    let mut wrapper = Wrapper::new(&mut buffer);
-    write!(wrapper,
-        "{{ \
-        \"name\": \"Tilt\", \
-        \"temp\": {}, \
-        \"temp_unit\": \"F\", \
-        \"gravity\": {}, \
-        \"gravity_unit\": \"G\", \
-        \"battery\": {} \
-        }}",
-        tilt_data.temperature_str(&mut [0u8; 6]),
-        tilt_data.gravity_str(&mut [0u8; 6]),
-        tilt_data.battery().unwrap_or_default(),
-    ).unwrap();
-
-    let json = core::str::from_utf8(&wrapper.buffer[..wrapper.offset]).unwrap();
-
-    write!(socket,
-        "POST /stream?id={} HTTP/1.1\r\n\
-         Host: {}\r\n\
-         Content-Type: application/json\r\n\
-         Content-Length: {}\r\n\r\n{}",
-         BREWFATHER_STREAM_ID, BREWFATHER_HOSTNAME, json.len(), json
-    )?;
+    // Where the last request went, for a Location that is just a path
+    let mut host: String<MAX_URL_LENGTH> = String::new();
+    host.push_str(HTTP_ENDPOINT.hostname).map_err(|_| HttpError::InvalidLocation)?;
+    let mut port = HTTP_ENDPOINT.port;
+    let mut tls = HTTP_ENDPOINT.tls;
 
-    socket.flush().await
-}
+    for _ in 0..MAX_REDIRECTS {
+        if crate::http::classify(&response[..len]) != ResponseClass::Redirect {
+            break;
+        }
 
-/// A helper that allows using the `write!` macro when writing to a TcpSocket.
-struct SocketWriter<'a> {
-    socket: TcpSocket<'a>,
-    last_error: embassy_net::tcp::Error,
-}
+        let location = crate::http::location(&response[..len]).ok_or(HttpError::InvalidLocation)?;
+        let mut path: String<MAX_URL_LENGTH> = String::new();
+        match crate::http::parse_url(location) {
+            Some(url) => {
+                host.clear();
+                host.push_str(url.host).map_err(|_| HttpError::InvalidLocation)?;
+                port = url.port;
+                tls = url.tls;
+                path.push_str(url.path).map_err(|_| HttpError::InvalidLocation)?;
+            }
+            None if location.starts_with('/') => {
+                path.push_str(location).map_err(|_| HttpError::InvalidLocation)?;
+            }
+            None => return Err(HttpError::InvalidLocation),
+        }
+        info!("Redirected to {}{}", host, path);
 
-impl<'a> SocketWriter<'a> {
-    fn new(socket: TcpSocket<'a>) -> Self {
-        Self {
-            socket,
-            last_error: embassy_net::tcp::Error::ConnectionReset
+        socket.close();
+        if wait_until(|| socket.state() == socket::tcp::State::Closed).await.is_err() {
+            socket.abort();
         }
-    }
 
-    async fn flush(&mut self) -> Result<(), embassy_net::tcp::Error> {
-        self.socket.flush().await
-    }
+        let ip = query_address(stack, &host, ADDRESS_FAMILY).await?;
+        socket.connect((ip, port)).await.map_err(HttpError::Connect)?;
 
-    fn write_fmt(&mut self, args: core::fmt::Arguments<'_>) -> Result<(), embassy_net::tcp::Error> {
-        match core::fmt::Write::write_fmt(self, args) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(self.last_error),
+        // Room for the host, path, and the original request
+        let mut request_buffer = [0u8; 2 * MAX_URL_LENGTH + 640];
+        let mut wrapper = Wrapper::new(&mut request_buffer);
+        match crate::http::status_code(&response[..len]) {
+            Some(307 | 308) => {
+                // Everything after the request line and Host header is the same
+                let (method, rest) = request.split_once(' ').ok_or(HttpError::InvalidLocation)?;
+                write!(wrapper, "{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, host).unwrap();
+
+                let mut lines = rest.split_inclusive("\r\n").skip(1);
+                for line in &mut lines {
+                    if line == "\r\n" {
+                        wrapper.write_str(line).unwrap();
+                        break;
+                    }
+
+                    if !line.get(..5).map_or(false, |name| name.eq_ignore_ascii_case("Host:")) {
+                        wrapper.write_str(line).unwrap();
+                    }
+                }
+
+                // The body
+                for line in lines {
+                    wrapper.write_str(line).unwrap();
+                }
+            }
+            _ => write!(wrapper,
+                "GET {} HTTP/1.1\r\n\
+                 Host: {}\r\n\
+                 Connection: close\r\n\r\n",
+                 path, host
+            ).unwrap(),
         }
-    }
+        let request_length = wrapper.written();
 
-    fn socket(self) -> TcpSocket<'a> {
-        self.socket
+        let server_name = if tls { Some(host.as_str()) } else { None };
+        len = send_request(socket, server_name, &request_buffer[..request_length], response, rng).await?;
     }
+
+    Ok(len)
 }
 
-impl<'a> core::fmt::Write for SocketWriter<'a> {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        match block_on(self.socket.write(s.as_bytes())) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                // core::fmt::Error can't transmit the original error
-                self.last_error = e;
-                Err(core::fmt::Error)
-            },
+/// Helper that will sleep for the given number of milliseconds
+pub async fn sleep_ms(ms: u64) {
+    Timer::after(Duration::from_millis(ms)).await;
+}
+
+/// Sends the `request` over the connected `socket` and reads the whole response
+/// into `response`. Returns the length of the response, which is 0 if the
+/// server closed the connection without responding.
+/// If there is a TLS `server_name`, the request and response go through a TLS
+/// session with that server instead.
+async fn send_request(
+    socket: &mut TcpSocket<'_>,
+    server_name: Option<&str>,
+    request: &[u8],
+    response: &mut [u8],
+    rng: &mut ChaCha8Rng,
+) -> Result<usize, HttpError> {
+    let server_name = match server_name {
+        Some(server_name) => server_name,
+        None => {
+            socket.write_all(request).await?;
+            socket.flush().await?;
+            return Ok(crate::http::read_response(socket, response).await?);
         }
+    };
+
+    if TLS_READ_BUFFER_SIZE == 0 {
+        return Err(HttpError::TlsDisabled);
     }
+
+    let mut read_record_buffer = [0u8; TLS_READ_BUFFER_SIZE];
+    let mut write_record_buffer = [0u8; TLS_WRITE_BUFFER_SIZE];
+    let config = TlsConfig::new().with_server_name(server_name);
+    let mut tls: TlsConnection<_, Aes128GcmSha256> =
+        TlsConnection::new(socket, &mut read_record_buffer, &mut write_record_buffer);
+
+    tls.open::<_, NoVerify>(TlsContext::new(&config, rng)).await?;
+    tls.write_all(request).await?;
+    tls.flush().await?;
+
+    Ok(crate::http::read_response(&mut tls, response).await?)
 }
 
-struct Wrapper<'a> {
-    buffer: &'a mut [u8],
-    offset: usize,
+/// Returns whether the connection is kept open between posts, see KEEP_ALIVE.
+const fn keep_alive() -> bool {
+    KEEP_ALIVE && !(matches!(PUBLISH_TARGET, PublishTarget::Brewfather) && HTTP_ENDPOINT.tls)
 }
 
-impl<'a> Wrapper<'a> {
-    fn new(buffer: &'a mut [u8]) -> Self {
-        Wrapper {
-            buffer,
-            offset: 0,
-        }
-    }
+/// Returns the value of the Connection header for the posts, which asks the
+/// server to keep the connection open if it will be reused.
+pub const fn connection_header() -> &'static str {
+    if keep_alive() { "keep-alive" } else { "close" }
 }
 
-impl<'a> core::fmt::Write for Wrapper<'a> {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let bytes = s.as_bytes();
-        let remainder = &mut self.buffer[self.offset..];
+/// Formats the request that posts the `tilt_data` to HTTP_ENDPOINT in `buffer`
+/// and returns it. Returns an error if it doesn't fit.
+fn format_post(tilt_data: TiltData, timestamp: Option<u64>, buffer: &mut [u8]) -> Result<&str, core::fmt::Error> {
+    use core::fmt::Write;
 
-        if remainder.len() < bytes.len() {
-            return Err(core::fmt::Error);
-        }
+    let mut json_buffer = [0u8; 384];
+    let json = format_json(tilt_data, timestamp, &mut json_buffer)?;
 
-        let remainder = &mut remainder[..bytes.len()];
-        remainder.copy_from_slice(bytes);
+    let mut wrapper = Wrapper::new(buffer);
+    write!(wrapper, "POST ")?;
+    HTTP_ENDPOINT.write_path(tilt_data.color(), &mut wrapper)?;
+    write!(wrapper,
+        " HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/json\r\n\
+         Connection: {}\r\n\
+         {}\
+         Content-Length: {}\r\n\r\n{}",
+         HTTP_ENDPOINT.hostname, connection_header(), HTTP_ENDPOINT.headers, json.len(), json
+    )?;
 
-        self.offset += bytes.len();
+    let len = wrapper.written();
+    Ok(core::str::from_utf8(&buffer[..len]).unwrap())
+}
 
-        Ok(())
-    }
-}
\ No newline at end of file
+/// Formats the `tilt_data` as a JSON object in `buffer` and returns it, or an
+/// error if it doesn't fit. See `payload::format_json`, which this fills in the
+/// relay's uptime and WiFi signal strength for.
+pub fn format_json(tilt_data: TiltData, timestamp: Option<u64>, buffer: &mut [u8]) -> Result<&str, core::fmt::Error> {
+    let wifi_rssi = crate::status::get().wifi.map(|wifi| wifi.rssi);
+
+    crate::payload::format_json(tilt_data, timestamp, Instant::now().as_secs(), wifi_rssi, buffer)
+}